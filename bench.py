@@ -43,6 +43,8 @@ def main(
     max_sleep: float = 0.0,
     redis_url: str = 'redis://127.0.0.1:6389',
     sleep: float = 0.0,
+    refill_frequency: float = 0.01,
+    connection_pool_size: int = 30,
 ):
     """
     Runs a simple benchmark using the library limiters.
@@ -54,6 +56,13 @@ def main(
             --iterations 12 \
             --target 4
 
+    Or, to measure the token bucket acquire path with close to no sleeping:
+
+        python bench.py tb \
+            --count 100 \
+            --refill-frequency 0.001 \
+            --max-sleep 60
+
     :param type: Which of the limiters to use. Semaphore or TokenBucket.
     :param count: How many context managers to run for a single limiter.
     :param iterations: How many limiters to run.
@@ -62,25 +71,35 @@ def main(
     :param max_sleep: The limiter max sleep.
     :param redis_url: Redis connection string.
     :param sleep: How long to sleep before exiting context manager closure.
+    :param refill_frequency: The token bucket refill frequency. Set this low to measure the acquire overhead.
+    :param connection_pool_size: The limiter connection pool size. Compare runs with different sizes to
+        see the cost of waiting for connections, e.g., `--connection-pool-size 1` against the default.
     :return: Nothing.
     """
     t: partial
     if type.startswith('s'):
         typer.echo('Testing semaphore...')
-        t = partial(Semaphore, capacity=capacity, max_sleep=max_sleep, redis_url=redis_url, connection_pool_size=30)
+        t = partial(
+            Semaphore,
+            capacity=capacity,
+            max_sleep=max_sleep,
+            redis_url=redis_url,
+            connection_pool_size=connection_pool_size,
+        )
         offset = 0.0
     elif type.startswith('t'):
         typer.echo('Testing token bucket...')
         t = partial(
             TokenBucket,
-            refill_frequency=0.01,
+            refill_frequency=refill_frequency,
             refill_amount=1,
             capacity=capacity,
             max_sleep=max_sleep,
             redis_url=redis_url,
-            connection_pool_size=30,
+            connection_pool_size=connection_pool_size,
         )
-        offset = 0.01 * count
+        # The bucket starts out full, so only tokens beyond the capacity are waited for
+        offset = refill_frequency * max(count - capacity, 0)
     else:
         typer.echo(f'type must be \'semaphore\' or \'token_bucket\', not {type}', color=True, err=True)
         return exit(1)
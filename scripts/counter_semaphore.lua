@@ -0,0 +1,55 @@
+--- Script called from the Semaphore implementation, when the semaphore is a counter.
+---
+--- Instead of a list holding one element per permit, counter semaphores store the
+--- number of permits available as an integer, which takes constant space for any capacity.
+--- Since there's no list to block on, waiters call this script repeatedly until it
+--- hands them a permit.
+---
+--- The script creates the counter at full capacity if it doesn't exist yet,
+--- then takes `weight` permits if that many are available.
+---
+--- keys:
+--- * key: The key to use for the counter
+--- * existskey: The key to use for the string we use to check if the counter exists.
+---              Its value is the capacity the counter was created with.
+--- * abortgenerationkey: The key counting aborts issued for the semaphore
+---
+--- args:
+--- * capacity: The capacity of the semaphore
+--- * weight: The number of permits to take
+---
+--- returns:
+--- * 1 if the permits were taken, else 0, and the number of aborts issued so far
+--- * A LIMITERTYPE error if the key belongs to a limiter of another type
+
+redis.replicate_commands()
+
+-- Init config variables
+local key = KEYS[1]
+local existskey = KEYS[2]
+local abortgenerationkey = KEYS[3]
+local capacity = tonumber(ARGV[1])
+local weight = tonumber(ARGV[2])
+
+-- Refuse to share the key with a limiter of another type, which would corrupt both.
+-- List semaphores store a list, and token buckets a string that isn't a number.
+local key_type = redis.call('TYPE', key)['ok']
+if key_type == 'list' then
+    return redis.error_reply('LIMITERTYPE ' .. key .. ' is used by a Semaphore without counter=True')
+elseif key_type == 'string' and tonumber(redis.call('GET', key)) == nil then
+    return redis.error_reply('LIMITERTYPE ' .. key .. ' is used by a TokenBucket')
+end
+
+-- Create the counter if none exists
+if redis.call('SETNX', existskey, capacity) == 1 then
+    redis.call('SET', key, capacity)
+end
+
+local generation = tonumber(redis.call('GET', abortgenerationkey) or 0)
+
+-- Take the permits if there are enough available
+if tonumber(redis.call('GET', key) or 0) >= weight then
+    redis.call('DECRBY', key, weight)
+    return { 1, generation }
+end
+return { 0, generation }
@@ -0,0 +1,52 @@
+--- Script called from the Semaphore implementation, when fairness is enabled.
+---
+--- Waiters push a ticket to a queue on arrival, and only the waiter
+--- holding the ticket at the head of the queue is allowed to pop a permit.
+--- This guarantees strict arrival-order fairness, even across reconnects.
+---
+--- While waiting, each waiter keeps a short-lived key alive for its ticket.
+--- Tickets at the head of the queue whose key has expired belong to waiters
+--- that gave up or died, and are discarded.
+---
+--- keys:
+--- * key: The key of the semaphore list
+--- * queuekey: The key of the ticket queue
+---
+--- args:
+--- * ticket: The ticket of the waiter
+--- * ticket_ttl: How long the ticket stays valid without being refreshed, in milliseconds
+--- * first_attempt: 1 if this is the first attempt, in which case we join the queue
+---
+--- returns:
+--- * 1 and 0 if a permit was acquired, else 0 and the number of tickets ahead of ours
+
+redis.replicate_commands()
+
+-- Init config variables
+local key = KEYS[1]
+local queuekey = KEYS[2]
+local ticket = ARGV[1]
+local ticket_ttl = tonumber(ARGV[2])
+local first_attempt = tonumber(ARGV[3])
+
+-- Join the queue on the first attempt, and keep our ticket alive
+if first_attempt == 1 then
+    redis.call('RPUSH', queuekey, ticket)
+end
+redis.call('SET', queuekey .. ':' .. ticket, 1, 'PX', ticket_ttl)
+redis.call('PEXPIRE', queuekey, ticket_ttl)
+
+-- Discard tickets at the head of the queue that are no longer kept alive
+local head = redis.call('LINDEX', queuekey, 0)
+while head and redis.call('EXISTS', queuekey .. ':' .. head) == 0 do
+    redis.call('LPOP', queuekey)
+    head = redis.call('LINDEX', queuekey, 0)
+end
+
+-- Pop a permit if it's our turn
+if head == ticket and redis.call('LPOP', key) then
+    redis.call('LPOP', queuekey)
+    redis.call('DEL', queuekey .. ':' .. ticket)
+    return { 1, 0 }
+end
+return { 0, redis.call('LPOS', queuekey, ticket) or 0 }
@@ -0,0 +1,34 @@
+--- Script called from the Semaphore implementation, to hand out the next fencing token.
+---
+--- Tokens come from a counter, which is moved forward to the current time in microseconds
+--- whenever it's behind. A counter that expired or was deleted therefore restarts above
+--- any token it handed out before, as long as the redis server's clock doesn't go back.
+---
+--- keys:
+--- * key: The key to use for the counter
+---
+--- args:
+--- * expiry: The expiry to set on the counter, in seconds. 0 means the counter never expires.
+---
+--- returns:
+--- * The fencing token
+
+redis.replicate_commands()
+
+-- Init config variables
+local key = KEYS[1]
+local expiry = tonumber(ARGV[1])
+
+local redis_time = redis.call('TIME') -- Array of [seconds, microseconds]
+local now = tonumber(redis_time[1]) * 1000000 + tonumber(redis_time[2])
+
+local token = redis.call('INCR', key)
+if token < now then
+    token = now
+    redis.call('SET', key, string.format('%d', token))
+end
+
+if expiry > 0 then
+    redis.call('EXPIRE', key, expiry)
+end
+return token
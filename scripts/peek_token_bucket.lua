@@ -0,0 +1,76 @@
+--- Script called from the TokenBucket implementation, to read how many tokens are available
+--- without consuming any.
+---
+--- Refills since the last slot assigned are applied the same way as in the token bucket
+--- script, but only to the values returned, so the state is never written.
+---
+--- keys:
+--- * key: The key name of the token bucket state
+--- * rate_key: (optional) The key name of the adaptive rate state
+---
+--- args:
+--- * capacity: The max capacity of the bucket
+--- * refill_rate: How often tokens are added to the bucket, in milliseconds
+--- * refill_amount: How many tokens are added at each refill
+--- * initial_tokens: How many tokens a new bucket starts with
+---
+--- returns:
+--- * The next slot tokens are available at, as a millisecond timestamp, and the tokens
+---   available then, as a string, since Lua numbers are truncated to integers in replies
+--- * A LIMITERTYPE error if the key belongs to a semaphore
+
+-- Init config variables
+local data_key = KEYS[1]
+local rate_key = KEYS[2]
+local capacity = tonumber(ARGV[1])
+local refill_rate = tonumber(ARGV[2])
+local refill_amount = tonumber(ARGV[3])
+local initial_tokens = tonumber(ARGV[4])
+
+-- Refill more slowly while the rate is scaled down, like the token bucket script
+if rate_key then
+    local rate = redis.call('GET', rate_key)
+    if rate ~= false then
+        for factor in string.gmatch(rate, '(%S+) %S+') do
+            refill_rate = refill_rate / tonumber(factor)
+        end
+    end
+end
+
+local redis_time = redis.call('TIME') -- Array of [seconds, microseconds]
+local now = tonumber(redis_time[1]) * 1000 + (tonumber(redis_time[2]) / 1000)
+
+-- A bucket without state is created with its initial tokens, like in the token bucket script
+local tokens = initial_tokens
+local slot = now
+if initial_tokens <= 0 then
+    tokens = refill_amount
+    slot = now + refill_rate
+end
+
+-- Refuse to read a semaphore's state as a bucket, like the token bucket script
+local key_type = redis.call('TYPE', data_key)['ok']
+if key_type ~= 'none' and key_type ~= 'string' then
+    return redis.error_reply('LIMITERTYPE ' .. data_key .. ' is used by a Semaphore')
+end
+
+local data = redis.call('GET', data_key)
+if data ~= false and string.match(data, '^%S+ %S+$') == nil then
+    return redis.error_reply('LIMITERTYPE ' .. data_key .. ' is used by a Semaphore with counter=True')
+end
+
+if data ~= false then
+    for a, b in string.gmatch(data, '(%S+) (%S+)') do
+        slot = tonumber(a)
+        tokens = tonumber(b)
+    end
+
+    -- Add the tokens refilled since the last slot assigned, up to the capacity
+    if slot < now then
+        local skipped = math.floor((now - slot) / refill_rate)
+        tokens = math.min(tokens + skipped * refill_amount, capacity)
+        slot = slot + skipped * refill_rate
+    end
+end
+
+return { slot, string.format('%.6f', tokens) }
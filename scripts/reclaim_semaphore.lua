@@ -0,0 +1,67 @@
+--- Script called from the Semaphore implementation, to reclaim permits from crashed holders.
+---
+--- Each holder leases its permits in a sorted set, scored by when the lease was
+--- taken or last renewed. Leases older than the cutoff belong to holders that died
+--- without releasing, so their permits are released like a holder would, and the
+--- leases removed. Like the release scripts, we never release beyond the capacity.
+---
+--- Lease members are formatted as `{weight}:{holder id}`, so we know how many
+--- permits each one held.
+---
+--- keys:
+--- * key: The key to use for the list, or the counter in counter mode
+--- * existskey: The key to use for the string we use to check if the semaphore exists
+--- * leaseskey: The key to use for the sorted set of leases
+---
+--- args:
+--- * capacity: The capacity of the semaphore
+--- * cutoff: Leases last renewed before this millisecond timestamp are reclaimed
+--- * counter: 1 if the semaphore is a counter, 0 if it's a list
+--- * expiry: The expiry to set on the semaphore's keys when permits are reclaimed, in seconds
+---
+--- returns:
+--- * The number of permits reclaimed
+
+-- Init config variables
+local key = KEYS[1]
+local existskey = KEYS[2]
+local leaseskey = KEYS[3]
+local capacity = tonumber(ARGV[1])
+local cutoff = ARGV[2]
+local counter = tonumber(ARGV[3]) == 1
+local expiry = tonumber(ARGV[4])
+
+-- Leases taken before the semaphore expired were reset along with it
+if redis.call('EXISTS', existskey) == 0 then
+    redis.call('DEL', leaseskey)
+    return 0
+end
+
+local permits = 0
+for _, lease in ipairs(redis.call('ZRANGEBYSCORE', leaseskey, '-inf', '(' .. cutoff)) do
+    permits = permits + (tonumber(string.match(lease, '^(%d+):')) or 1)
+    redis.call('ZREM', leaseskey, lease)
+end
+if permits == 0 then
+    return 0
+end
+
+-- Release as many permits as there's room for
+local reclaimed
+if counter then
+    reclaimed = math.max(math.min(permits, capacity - tonumber(redis.call('GET', key) or 0)), 0)
+    if reclaimed > 0 then
+        redis.call('INCRBY', key, reclaimed)
+    end
+else
+    capacity = math.max(capacity, tonumber(redis.call('GET', existskey)) or 0)
+    reclaimed = math.max(math.min(permits, capacity - redis.call('LLEN', key)), 0)
+    for _ = 1, reclaimed do
+        redis.call('LPUSH', key, 1)
+    end
+end
+
+-- Refresh expiries, since pushing to an empty list creates it without one
+redis.call('EXPIRE', key, expiry)
+redis.call('EXPIRE', existskey, expiry)
+return reclaimed
@@ -0,0 +1,48 @@
+--- Script called from the Semaphore implementation, to release permits to a counter.
+---
+--- The counter is incremented, but never beyond the semaphore's capacity.
+--- This guards against permits being released more than once.
+---
+--- Expiries are refreshed in the same script, so the increment and the
+--- refresh are atomic.
+---
+--- keys:
+--- * key: The key to use for the counter
+--- * existskey: The key to use for the string we use to check if the counter exists
+---
+--- args:
+--- * capacity: The capacity of the semaphore (i.e., the max value of the counter)
+--- * permits: The number of permits to release
+--- * expiry: The expiry to set on both keys, in seconds. 0 means the keys never expire.
+---
+--- returns:
+--- * The number of permits released
+
+-- Init config variables
+local key = KEYS[1]
+local existskey = KEYS[2]
+local capacity = tonumber(ARGV[1])
+local permits = tonumber(ARGV[2])
+local expiry = tonumber(ARGV[3])
+
+-- There's nothing to release to if the semaphore has expired.
+-- It will be recreated at full capacity the next time it's used.
+if redis.call('EXISTS', existskey) == 0 then
+    return 0
+end
+
+-- Add as many permits as there's room for
+local released = math.max(math.min(permits, capacity - tonumber(redis.call('GET', key) or 0)), 0)
+if released > 0 then
+    redis.call('INCRBY', key, released)
+end
+
+-- Refresh or remove expiries
+if expiry > 0 then
+    redis.call('EXPIRE', key, expiry)
+    redis.call('EXPIRE', existskey, expiry)
+else
+    redis.call('PERSIST', key)
+    redis.call('PERSIST', existskey)
+end
+return released
@@ -0,0 +1,52 @@
+--- Script called from the Semaphore implementation, to release permits.
+---
+--- Permits are pushed back to the semaphore list, but never so many that
+--- the list would exceed the semaphore's capacity. This guards against
+--- permits being released more than once. The capacity is the larger of the
+--- one passed and the one stored, since capacity may have been transferred
+--- to the semaphore while the permits were held.
+---
+--- Expiries are refreshed in the same script, so the push and the
+--- refresh are atomic.
+---
+--- keys:
+--- * key: The key to use for the list
+--- * existskey: The key to use for the string we use to check if the lists exists
+---
+--- args:
+--- * capacity: The capacity of the semaphore (i.e., the max length of the list)
+--- * permits: The number of permits to release
+--- * expiry: The expiry to set on both keys, in seconds. 0 means the keys never expire.
+---
+--- returns:
+--- * The number of permits released
+
+-- Init config variables
+local key = tostring(KEYS[1])
+local existskey = tostring(KEYS[2])
+local capacity = tonumber(ARGV[1])
+local permits = tonumber(ARGV[2])
+local expiry = tonumber(ARGV[3])
+
+-- There's nothing to release to if the semaphore has expired.
+-- It will be recreated at full capacity the next time it's used.
+if redis.call('EXISTS', existskey) == 0 then
+    return 0
+end
+
+-- Push back as many permits as there's room for
+capacity = math.max(capacity, tonumber(redis.call('GET', existskey)) or 0)
+local released = math.min(permits, capacity - redis.call('LLEN', key))
+for _ = 1, released do
+    redis.call('LPUSH', key, 1)
+end
+
+-- Refresh or remove expiries
+if expiry > 0 then
+    redis.call('EXPIRE', key, expiry)
+    redis.call('EXPIRE', existskey, expiry)
+else
+    redis.call('PERSIST', key)
+    redis.call('PERSIST', existskey)
+end
+return math.max(released, 0)
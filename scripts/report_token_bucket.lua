@@ -0,0 +1,70 @@
+--- Script called from the TokenBucket implementation, to adapt an adaptive bucket's rate
+--- to the outcome of a downstream call.
+---
+--- The rate is scaled by a factor between `min_factor` and 1, stored as `{factor} {cooldown_until}`,
+--- and adjusted AIMD-style. An overloaded outcome multiplies the factor by `decrease`, and starts a
+--- cooldown, during which further overloads don't shrink the rate again, since they're likely
+--- caused by the same spike. After the cooldown, each ok outcome adds `increase` to the factor,
+--- until it's back at 1, and the state is deleted.
+---
+--- keys:
+--- * rate_key: The key name of the rate state
+---
+--- args:
+--- * overloaded: 1 if the downstream signalled overload, else 0
+--- * cooldown: How long to hold off on changing the rate after an overload, in milliseconds
+--- * decrease: The factor to multiply the rate by on overload
+--- * increase: How much to add to the factor on an ok outcome, after the cooldown
+--- * min_factor: The lowest factor the rate can be scaled by
+--- * expiry: How long to keep the rate state without reports, in seconds, or 0 to keep it forever
+---
+--- returns:
+--- * The new factor, as a string, since Lua numbers are truncated to integers in replies
+
+redis.replicate_commands()
+
+-- Init config variables
+local rate_key = KEYS[1]
+local overloaded = tonumber(ARGV[1])
+local cooldown = tonumber(ARGV[2])
+local decrease = tonumber(ARGV[3])
+local increase = tonumber(ARGV[4])
+local min_factor = tonumber(ARGV[5])
+local expiry = tonumber(ARGV[6])
+
+local redis_time = redis.call('TIME') -- Array of [seconds, microseconds]
+local now = tonumber(redis_time[1]) * 1000 + (tonumber(redis_time[2]) / 1000)
+
+-- Retrieve (possibly) stored state. Without any, the bucket runs at its full rate.
+local factor = 1
+local cooldown_until = 0
+local data = redis.call('GET', rate_key)
+if data ~= false then
+    for a, b in string.gmatch(data, '(%S+) (%S+)') do
+        factor = tonumber(a)
+        cooldown_until = tonumber(b)
+    end
+end
+
+if overloaded == 1 then
+    if now >= cooldown_until then
+        factor = math.max(factor * decrease, min_factor)
+    end
+    cooldown_until = math.max(cooldown_until, now + cooldown)
+elseif now >= cooldown_until then
+    factor = math.min(factor + increase, 1)
+end
+
+-- Back at the full rate, there's nothing left to store
+if factor >= 1 and now >= cooldown_until then
+    redis.call('DEL', rate_key)
+    return '1'
+end
+
+local state = string.format('%.6f %d', factor, cooldown_until)
+if expiry > 0 then
+    redis.call('SETEX', rate_key, expiry, state)
+else
+    redis.call('SET', rate_key, state)
+end
+return string.format('%.6f', factor)
@@ -0,0 +1,51 @@
+--- Script called from the TokenBucket implementation, to change its capacity.
+---
+--- Slots already handed out are kept, so callers that are already
+--- sleeping wake up as scheduled. The tokens left for the current slot
+--- are capped at the new capacity, so shrinking never lets a burst through
+--- that the new capacity wouldn't allow. When growing, the bucket fills up
+--- to the new capacity through regular refills, rather than all at once.
+---
+--- keys:
+--- * key: The key name of the token bucket state
+---
+--- args:
+--- * capacity: The new max capacity of the bucket
+--- * expiry: How long to keep the bucket state without use, in seconds, or 0 to keep it forever
+---
+--- returns:
+--- * The tokens left for the current slot, or -1 if the bucket has no state
+
+redis.replicate_commands()
+
+-- Init config variables
+local data_key = KEYS[1]
+local capacity = tonumber(ARGV[1])
+local expiry = tonumber(ARGV[2])
+
+-- Nothing to resize if the bucket has no state.
+-- It will be created with the new capacity next time it's used.
+local data = redis.call('GET', data_key)
+if data == false then
+    return -1
+end
+
+local slot, tokens
+for a, b in string.gmatch(data, '(%S+) (%S+)') do
+    slot = tonumber(a)
+    tokens = tonumber(b)
+end
+
+if tokens > capacity then
+    tokens = capacity
+end
+
+-- Save state, with the same expiry as the token bucket script
+local state = string.format('%d %.6f', slot, tokens)
+if expiry > 0 then
+    redis.call('SETEX', data_key, expiry, state)
+else
+    redis.call('SET', data_key, state)
+end
+
+return tokens
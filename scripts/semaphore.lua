@@ -8,13 +8,15 @@
 ---
 --- keys:
 --- * key: The key to use for the list
---- * existskey: The key to use for the string we use to check if the lists exists
+--- * existskey: The key to use for the string we use to check if the lists exists.
+---              Its value is the capacity the list was created with.
 ---
 --- args:
 --- * capacity: The capacity of the semaphore (i.e., the length of the list)
 ---
 --- returns:
 --- * 1 if created, else 0 (but the return value isn't used; only useful for debugging)
+--- * A LIMITERTYPE error if the key holds a string, i.e., belongs to a token bucket or counter semaphore
 
 redis.replicate_commands()
 
@@ -23,20 +25,32 @@ local key = tostring(KEYS[1])
 local existskey = tostring(KEYS[2])
 local capacity = tonumber(ARGV[1])
 
+-- Refuse to share the key with a limiter of another type, which would corrupt both
+if redis.call('TYPE', key)['ok'] == 'string' then
+    return redis.error_reply('LIMITERTYPE ' .. key .. ' is used by a TokenBucket or a Semaphore with counter=True')
+end
+
 -- Check if list exists
 -- Note, we cannot use EXISTS or LLEN below, as we need
 -- to know if a list exists, but has capacity zero.
-local does_not_exist = redis.call('SETNX', string.format(existskey, key), 1)
+local does_not_exist = redis.call('SETNX', string.format(existskey, key), capacity)
 
 -- Create the list if none exists
 if does_not_exist == 1 then
     -- Add '1' as an argument equal to the capacity of the semaphore
     -- If capacity is 5 here, we generate `{RPUSH, 1, 1, 1, 1, 1}`.
-    local args = { 'RPUSH', key }
-    for _ = 1, capacity do
-        table.insert(args, 1)
+    -- Large capacities are pushed in batches, since `unpack` can
+    -- only handle a limited number of values at once.
+    local batch_size = 1000
+    local remaining = capacity
+    while remaining > 0 do
+        local args = { 'RPUSH', key }
+        for _ = 1, math.min(remaining, batch_size) do
+            table.insert(args, 1)
+        end
+        redis.call(unpack(args))
+        remaining = remaining - batch_size
     end
-    redis.call(unpack(args))
     return true
 end
 return false
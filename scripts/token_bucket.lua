@@ -18,18 +18,51 @@
 --- of processes sleeping for an unreasonably long time, but there is a max-sleep
 --- setting in both implementations to offset this.
 ---
+--- Buckets can optionally be shared between weighted tenants. Each tenant may then
+--- be assigned at most `quota` slots in each fixed window of `window` milliseconds,
+--- where windows are counted from the unix epoch and a slot belongs to the window
+--- it falls within. A tenant over its quota is refused without consuming a token,
+--- even if the bucket has tokens left, and is told when to retry instead.
+---
+--- Clients can also be refused outright when the slot they'd be assigned is more than
+--- `max_backlog` milliseconds away, to shed load rather than let the backlog grow.
+---
+--- Acquires with an idempotency key record the slot they were assigned for
+--- `idempotency_window` milliseconds. Repeating the acquire with the same key in
+--- that time returns the recorded slot, instead of consuming more tokens.
+---
+--- Adaptive buckets refill more slowly while a downstream is overloaded. The factor
+--- to scale the rate by is maintained by the report script, under `rate_key`.
+---
 --- keys:
 --- * key: The key name to use for the semaphore
+--- * idempotency_key: (when idempotency_window is set) The key name to record the assigned slot in
+--- * rate_key: (when adaptive is set) The key name of the adaptive rate state
+--- * tenant_key: (optional) The key name for the tenant's usage in the current window
 ---
 --- args:
 --- * capacity: The max capacity of the bucket
 --- * refill_rate: How often tokens are added to the bucket, (NOTE) in *milliseconds*
 ---                The rate is in milliseconds since we cannot use floats for the `now` variable.
 ---                This deviates from the rest of the package code, where the rate is specified in seconds.
---- * refill_amount: How many tokens are added at each interval
+--- * refill_amount: How many tokens are added at each interval. Can be fractional.
+--- * initial_tokens: How many tokens a brand-new bucket starts with. When 0, the
+---                   first tokens are handed out one interval from now (a cold start).
+--- * cost: How many tokens to consume. Can be fractional, and may exceed the capacity.
+--- * now_override: A millisecond timestamp to use instead of the server time, or 0.
+---                 Set by `schedule_at`, and by debug builds to make slots deterministic in tests.
+--- * max_backlog: How far into the future slots may be assigned, in milliseconds, or 0 for no limit.
+--- * idempotency_window: How long to record the assigned slot for, in milliseconds, or 0 for no idempotency key.
+--- * expiry: How long to keep the bucket state without use, in seconds, or 0 to keep it forever.
+--- * adaptive: 1 if the refill rate is scaled by the factor stored under rate_key, else 0
+--- * quota: (with tenant_key) How many tokens the tenant may consume per window
+--- * window: (with tenant_key) The length of a window, in milliseconds
 ---
 --- returns:
---- * The assigned slot, as a millisecond timestamp
+--- * The assigned slot, as a millisecond timestamp, and 1. Or, if the tenant
+---   is over its quota, the time to retry at, as a millisecond timestamp, and 0.
+---   Or, if the slot is beyond the max backlog, the slot that would have been assigned, and 2.
+--- * A LIMITERTYPE error if the key belongs to a semaphore
 
 redis.replicate_commands()
 
@@ -38,33 +71,93 @@ local data_key = KEYS[1]
 local capacity = tonumber(ARGV[1])
 local refill_rate = tonumber(ARGV[2])
 local refill_amount = tonumber(ARGV[3])
+local initial_tokens = tonumber(ARGV[4])
+local cost = tonumber(ARGV[5])
+local now_override = tonumber(ARGV[6])
+local max_backlog = tonumber(ARGV[7])
+local idempotency_window = tonumber(ARGV[8])
+local expiry = tonumber(ARGV[9])
+local adaptive = tonumber(ARGV[10])
+
+-- Optional keys come in a fixed order, after the bucket state
+local next_key = 2
+local idempotency_key
+if idempotency_window > 0 then
+    idempotency_key = KEYS[next_key]
+    next_key = next_key + 1
+end
+local rate_key
+if adaptive > 0 then
+    rate_key = KEYS[next_key]
+    next_key = next_key + 1
+end
+local tenant_key = KEYS[next_key]
+
+-- Refill more slowly while the rate is scaled down, by stretching the refill interval
+if rate_key then
+    local rate = redis.call('GET', rate_key)
+    if rate ~= false then
+        for factor in string.gmatch(rate, '(%S+) %S+') do
+            refill_rate = refill_rate / tonumber(factor)
+        end
+    end
+end
+
+-- Repeated acquires get the slot already assigned to them
+if idempotency_key then
+    local assigned = redis.call('GET', idempotency_key)
+    if assigned ~= false then
+        return { tonumber(assigned), 1 }
+    end
+end
 
 -- Get current time (ms timestamp)
-local redis_time = redis.call('TIME') -- Array of [seconds, microseconds]
-local now = tonumber(redis_time[1]) * 1000 + (tonumber(redis_time[2]) / 1000)
+local now
+if now_override > 0 then
+    now = now_override
+else
+    local redis_time = redis.call('TIME') -- Array of [seconds, microseconds]
+    now = tonumber(redis_time[1]) * 1000 + (tonumber(redis_time[2]) / 1000)
+end
 
 -- Instantiate default bucket values
 -- These are used if no state is retrieved below; i.e., they
 -- are the values we use for creating a new bucket.
-local tokens = refill_amount
-local slot = now + refill_rate
+-- A warm bucket hands out its initial tokens right away,
+-- while a cold one waits for the first refill.
+local tokens = initial_tokens
+local slot = now
+if initial_tokens <= 0 then
+    tokens = refill_amount
+    slot = now + refill_rate
+end
+
+-- Refuse to share the key with a semaphore, which would corrupt both.
+-- List semaphores store a list, and counter semaphores a plain number.
+local key_type = redis.call('TYPE', data_key)['ok']
+if key_type ~= 'none' and key_type ~= 'string' then
+    return redis.error_reply('LIMITERTYPE ' .. data_key .. ' is used by a Semaphore')
+end
 
 -- Retrieve (possibly) stored state
 local data = redis.call('GET', data_key)
 
+if data ~= false and string.match(data, '^%S+ %S+$') == nil then
+    return redis.error_reply('LIMITERTYPE ' .. data_key .. ' is used by a Semaphore with counter=True')
+end
+
 if data ~= false then
     for a, b in string.gmatch(data, '(%S+) (%S+)') do
         slot = tonumber(a)
         tokens = tonumber(b)
     end
 
-    -- Quickly validate our state
-
     -- If the slot is in the past, we need to increment the slot
-    -- value, and add tokens to the bucket equal to the slots skipped
-    if slot < now + 20 then
-        tokens = tokens + (slot - now) / refill_rate
-        slot = slot + refill_rate
+    -- value, and add tokens to the bucket for each refill skipped
+    if slot < now then
+        local skipped = math.floor((now - slot) / refill_rate)
+        tokens = tokens + skipped * refill_amount
+        slot = slot + skipped * refill_rate
 
         -- If we skipped 3 slots, but the capacity is 1,
         -- trim the tokens left.
@@ -73,18 +166,59 @@ if data ~= false then
         end
     end
 
-    -- If the current slot has no more tokens to assign,
-    -- move to the next slot.
-    if tokens <= 0 then
-        slot = slot + refill_rate
-        tokens = refill_amount
+end
+
+-- If the current slot doesn't have enough tokens left for the cost,
+-- move forward as many slots as it takes to refill them. Costs above
+-- the capacity wait for the refills they need as if the bucket could
+-- hold them, rather than losing the tokens refilled beyond the capacity.
+if tokens < cost then
+    -- Allow for rounding errors from fractional refill amounts, which would otherwise cost a whole extra refill
+    local refills = math.max(math.ceil((cost - tokens) / refill_amount - 1e-9), 1)
+    slot = slot + refills * refill_rate
+    tokens = math.min(tokens + refills * refill_amount, math.max(capacity, cost))
+end
+
+-- Refuse clients that would be scheduled too far ahead, without consuming any tokens
+if max_backlog > 0 and slot - now > max_backlog then
+    return { slot, 2 }
+end
+
+-- Refuse tenants that have used up their share of the window the slot falls in.
+-- We return before saving any state, so the token is left for other tenants.
+if tenant_key then
+    local quota = tonumber(ARGV[11])
+    local window_length = tonumber(ARGV[12])
+    local window = math.floor(slot / window_length)
+    local used = 0
+    local usage = redis.call('GET', tenant_key)
+    if usage ~= false then
+        for a, b in string.gmatch(usage, '(%S+) (%S+)') do
+            if tonumber(a) == window then
+                used = tonumber(b)
+            end
+        end
     end
+    if used + cost > quota then
+        return { (window + 1) * window_length, 0 }
+    end
+    redis.call('SET', tenant_key, string.format('%d %.6f', window, used + cost), 'PX', 2 * window_length)
 end
 
--- Consume a token
-tokens = tokens - 1
+-- Consume the tokens. Rounding the stored value keeps fractional
+-- costs from accumulating floating point errors, e.g., ten costs of
+-- 0.1 use up exactly one token.
+tokens = tokens - cost
 
 -- Save state and set expiry
-redis.call('SETEX', data_key, 30, string.format('%d %d', slot, tokens))
+local state = string.format('%d %.6f', slot, tokens)
+if expiry > 0 then
+    redis.call('SETEX', data_key, expiry, state)
+else
+    redis.call('SET', data_key, state)
+end
+if idempotency_key then
+    redis.call('SET', idempotency_key, string.format('%d', slot), 'PX', idempotency_window)
+end
 
-return slot
+return { slot, 1 }
@@ -0,0 +1,62 @@
+--- Script called from the Semaphore implementation, to move capacity between two semaphores.
+---
+--- Free permits are popped from the source list and pushed to the destination list,
+--- and the capacities stored in the exists keys are adjusted to match. Permits that
+--- are currently held can't be moved, so at most the free permits are transferred.
+---
+--- Both semaphores must live in the same hash slot when running on a cluster.
+---
+--- keys:
+--- * sourcekey: The key of the source semaphore's list
+--- * sourceexistskey: The key holding the source semaphore's capacity
+--- * destinationkey: The key of the destination semaphore's list
+--- * destinationexistskey: The key holding the destination semaphore's capacity
+---
+--- args:
+--- * permits: The number of permits to transfer
+--- * partial: 1 to transfer as many free permits as there are when there are
+---            fewer than `permits`, or 0 to transfer none at all in that case
+---
+--- returns:
+--- * The number of permits transferred
+
+redis.replicate_commands()
+
+-- Init config variables
+local sourcekey = KEYS[1]
+local sourceexistskey = KEYS[2]
+local destinationkey = KEYS[3]
+local destinationexistskey = KEYS[4]
+local permits = tonumber(ARGV[1])
+local partial = tonumber(ARGV[2])
+
+-- Either semaphore might have expired since it was created
+if redis.call('EXISTS', sourceexistskey) == 0 or redis.call('EXISTS', destinationexistskey) == 0 then
+    return 0
+end
+
+local transferred = math.min(permits, redis.call('LLEN', sourcekey))
+if transferred < permits and partial == 0 then
+    return 0
+end
+if transferred == 0 then
+    return 0
+end
+
+-- Move the permits, in batches like when creating the semaphore
+redis.call('LTRIM', sourcekey, transferred, -1)
+local batch_size = 1000
+local remaining = transferred
+while remaining > 0 do
+    local args = { 'RPUSH', destinationkey }
+    for _ = 1, math.min(remaining, batch_size) do
+        table.insert(args, 1)
+    end
+    redis.call(unpack(args))
+    remaining = remaining - batch_size
+end
+
+-- Adjust the stored capacities. This keeps any expiries set.
+redis.call('DECRBY', sourceexistskey, transferred)
+redis.call('INCRBY', destinationexistskey, transferred)
+return transferred
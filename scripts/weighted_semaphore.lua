@@ -0,0 +1,61 @@
+--- Script called from the Semaphore implementation, to take several permits at once.
+---
+--- `blpop` pops a single permit, and popping one at a time would let waiters hold
+--- partial acquisitions, which can deadlock when they all wait for the rest. Waiters
+--- for more than one permit call this script repeatedly instead, until it hands them
+--- all the permits they need in one go.
+---
+--- Like the semaphore script, this creates the list at full capacity if it doesn't
+--- exist, since the semaphore may expire while waiters poll.
+---
+--- keys:
+--- * key: The key to use for the list
+--- * existskey: The key to use for the string we use to check if the list exists.
+---              Its value is the capacity the list was created with.
+--- * abortgenerationkey: The key counting aborts issued for the semaphore
+---
+--- args:
+--- * capacity: The capacity of the semaphore
+--- * weight: The number of permits to take
+---
+--- returns:
+--- * 1 if the permits were taken, else 0, and the number of aborts issued so far
+--- * A LIMITERTYPE error if the key holds a string, i.e., belongs to a token bucket or counter semaphore
+
+redis.replicate_commands()
+
+-- Init config variables
+local key = KEYS[1]
+local existskey = KEYS[2]
+local abortgenerationkey = KEYS[3]
+local capacity = tonumber(ARGV[1])
+local weight = tonumber(ARGV[2])
+
+-- Refuse to share the key with a limiter of another type, which would corrupt both
+if redis.call('TYPE', key)['ok'] == 'string' then
+    return redis.error_reply('LIMITERTYPE ' .. key .. ' is used by a TokenBucket or a Semaphore with counter=True')
+end
+
+-- Create the list if none exists, in batches, since `unpack` can only handle a limited number of values
+if redis.call('SETNX', existskey, capacity) == 1 then
+    local remaining = capacity
+    while remaining > 0 do
+        local args = { 'RPUSH', key }
+        for _ = 1, math.min(remaining, 1000) do
+            table.insert(args, 1)
+        end
+        redis.call(unpack(args))
+        remaining = remaining - 1000
+    end
+end
+
+local generation = tonumber(redis.call('GET', abortgenerationkey) or 0)
+
+-- Take the permits if there are enough available, or none at all
+if redis.call('LLEN', key) >= weight then
+    for _ = 1, weight do
+        redis.call('LPOP', key)
+    end
+    return { 1, generation }
+end
+return { 0, generation }
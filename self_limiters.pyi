@@ -1,48 +1,575 @@
 from types import TracebackType
-from typing import Optional
+from typing import Any, Awaitable, Callable, Literal, Optional, Protocol, TypeVar
+
+F = TypeVar('F', bound=Callable[..., Awaitable[Any]])
+
+class Event(Protocol):
+    def is_set(self) -> bool: ...  # E.g., asyncio.Event or threading.Event
+
+class RedisClient:
+    """
+    Redis connection pools, built once and shared by the limiters created from it with `from_client`.
+    """
+
+    def __init__(
+        self,
+        redis_url: Optional[str] = None,  # will be set as "redis://127.0.0.1:6379" if None
+        connection_pool_size: Optional[int] = None,  # Will be set to 15 if None
+        eager_connect: Optional[bool] = None,  # Set to False when None is passed. Connects on init when True.
+        connect_timeout: Optional[float] = None,  # In seconds. Waits as long as the network allows when None.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+        username: Optional[str] = None,  # ACL username. Overrides any in the url.
+        password: Optional[str] = None,  # Overrides any in the url. Never exposed as an attribute.
+        response_timeout: Optional[float] = None,  # In seconds. Commands fail after this when set. Blocking waits are exempt.
+        max_retries: Optional[int] = None,  # Set to 0 when None is passed. Retries for connection errors.
+        retry_backoff: Optional[float] = None,  # Set to 0.1 when None is passed. In seconds, doubled per retry.
+    ) -> None: ...
+
+    connection_pool_size: int
+    verify_tls: bool
+    max_retries: int
+    retry_backoff: float
+
+class TestLimiterScope:
+    """
+    Limiter names unique to a test, whose keys are deleted when the scope is closed, exited, or dropped.
+
+    Only available when built with the `test-utils` feature.
+    """
+
+    def __init__(
+        self,
+        redis_urls: Optional[list[str]] = None,  # The servers to delete keys from. The default url when None.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+        username: Optional[str] = None,  # ACL username. Overrides any in the url.
+        password: Optional[str] = None,  # Overrides any in the url.
+    ) -> None: ...
+    def name(self, name: Optional[str] = None) -> str:
+        """
+        Return `{id}:{name}`, or a random name in the scope when none is passed.
+        """
+    def close(self) -> int:
+        """
+        Delete the keys of all limiters named by the scope, returning how many were deleted.
+        """
+    def __enter__(self) -> TestLimiterScope: ...
+    def __exit__(
+        self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
+    ) -> None: ...
+
+    id: str
 
 class TokenBucket:
     def __init__(
         self,
         name: str,
-        capacity: int,
+        capacity: int,  # At most 1,000,000
         refill_frequency: float,
-        refill_amount: int,
+        refill_amount: float,  # Can be fractional. At most 1,000,000
         redis_url: Optional[str] = None,  # will be set as "redis://127.0.0.1:6379" if None
-        max_sleep: Optional[float] = None,  # will be set to 0.0 if None. In seconds.
+        max_sleep: Optional[float] = None,  # will be set to 0.0 if None. In seconds, at most a year.
         connection_pool_size: Optional[int] = None,  # Will be set to 15 if None
+        eager_connect: Optional[bool] = None,  # Set to False when None is passed. PINGs redis on init when True.
+        quiet: Optional[bool] = None,  # Set to False when None is passed. Suppresses per-acquire logs when True.
+        initial_tokens: Optional[int] = None,  # Set to capacity when None is passed. 0 means a cold start.
+        connect_timeout: Optional[float] = None,  # In seconds. Connecting to redis fails after this when set.
+        response_timeout: Optional[float] = None,  # In seconds. Commands fail after this when set. Blocking waits are exempt.
+        count_rejections: Optional[bool] = None,  # Set to False when None is passed. Counts max sleep rejections in redis when True.
+        max_backlog: Optional[float] = None,  # In seconds. Set to 0.0, for no limit, when None is passed.
+        expiry: Optional[int] = 30,  # In seconds. None means the bucket state never expires.
+        local_lease: Optional[int] = None,  # Tokens to consume per call to redis, serving the rest locally.
+        # Called with (seconds slept, seconds left) while sleeping for a slot. Exceptions are swallowed.
+        heartbeat: Optional[Callable[[float, float], None]] = None,
+        heartbeat_interval: Optional[float] = None,  # Set to 5.0 when None is passed. In seconds.
+        # Called with the seconds spent calling redis after each acquire. Exceptions are swallowed.
+        latency_callback: Optional[Callable[[float], None]] = None,
+        adaptive: Optional[bool] = None,  # Set to False when None is passed. Enables report when True.
+        dry_run: Optional[bool] = None,  # Set to False when None is passed. Logs instead of sleeping when True.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+        prefix: Optional[str] = None,  # Set to '__self-limiters:' when None is passed. Keys are named {prefix}{name}.
+        max_retries: Optional[int] = None,  # Set to 0 when None is passed. Retries for connection errors.
+        retry_backoff: Optional[float] = None,  # Set to 0.1 when None is passed. In seconds, doubled per retry.
+        username: Optional[str] = None,  # ACL username. Overrides any in the url.
+        password: Optional[str] = None,  # Overrides any in the url. Never exposed as an attribute.
     ) -> None: ...
 
     capacity: int
     name: str
     refill_frequency: float
-    refill_amount: int
+    refill_amount: float
+    quiet: bool
+    initial_tokens: int
+    count_rejections: bool
+    cost: float  # Tokens consumed per acquire. Set with with_cost.
+    max_backlog: float
+    expiry: Optional[int]
+    local_lease: Optional[int]
+    heartbeat_interval: float
+    adaptive: bool
+    dry_run: bool
+    verify_tls: bool
+    max_retries: int
+    retry_backoff: float
+    state_key: str  # The redis key holding the bucket state. Same as name.
+    idempotency_key: Optional[str]  # Set on buckets returned by with_idempotency_key
+    tenant: Optional[str]  # Set on buckets returned by with_share
+    share: Optional[float]  # Set on buckets returned by with_share
 
-    async def __aenter__(self) -> None: ...
+    async def __aenter__(self) -> bool: ...  # Whether we had to sleep for a token
     async def __aexit__(
         self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
     ) -> None: ...
+    def __enter__(self) -> bool: ...  # Blocks the thread, with the GIL released, until it's our turn
+    def __exit__(
+        self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
+    ) -> None: ...
+    def limit(self, func: F) -> F:
+        """
+        Decorate an async function, so that every call to it runs inside an `async with` block on this limiter.
+        """
+    async def schedule_at(self, now: float) -> float:
+        """
+        Schedule a slot as if the current time were `now`, a unix timestamp in seconds, without sleeping.
+
+        Returns the assigned slot as a unix timestamp. Tokens are consumed like for any other acquire.
+        """
+    async def wait_for_burst(self, n: int) -> bool:
+        """
+        Sleep until the bucket holds `n` tokens in a single slot, then consume them all at once.
+
+        Returns whether we had to sleep. `n` must be at most the capacity.
+        """
+    async def rejection_rate(self) -> int:
+        """
+        Return the number of rejections counted across all clients in the current 60 second window.
+        """
+    async def tokens_remaining(self) -> tuple[int, float]:
+        """
+        Return the next slot tokens are available at, as a millisecond timestamp, and how many, without consuming any.
+
+        Refills since the last acquire are counted, so the slot is now unless the bucket is booked ahead.
+        """
+    async def snapshot(self) -> dict[str, Any]:
+        """
+        Return the bucket's configuration and state in redis.
+
+        The state is `slot`, the last slot assigned as a millisecond timestamp, and `tokens`,
+        the tokens left for that slot, as a float. Both are None if the bucket has no state.
+        """
+    async def restore(self, snapshot: dict[str, Any]) -> None:
+        """
+        Write the state from a snapshot to this bucket in redis, replacing any existing state.
+        """
+    def with_key(self, key_suffix: str) -> TokenBucket:
+        """
+        Return a token bucket for the key `{name}:{key_suffix}`, sharing this bucket's settings and connection pool.
+        """
+    @staticmethod
+    def from_client(
+        client: RedisClient,
+        name: str,
+        capacity: int,
+        refill_frequency: float,
+        refill_amount: float,
+        max_sleep: Optional[float] = None,
+        quiet: Optional[bool] = None,
+        prefix: Optional[str] = None,
+    ) -> TokenBucket:
+        """
+        Create a token bucket using the connection pools of `client`. Other settings are left at their defaults.
+        """
+    def with_redis_url(self, redis_url: str) -> TokenBucket:
+        """
+        Return a handle on this bucket using the redis server at `redis_url`, sharing this bucket's settings.
+
+        Connection pools are cached per url, and shared by all handles derived from this bucket.
+        """
+    def wait_stats(self) -> dict[str, Optional[int]]:
+        """
+        Return percentiles of the time recently spent sleeping for a token, by this instance.
+
+        The dict has `count`, the number of samples, and `p50`, `p90` and `p99` in milliseconds,
+        which are None until the bucket has been entered. The 1024 most recent samples are kept.
+        """
+    def with_cost(self, cost: float) -> TokenBucket:
+        """
+        Return a handle on this bucket where each acquire consumes `cost` tokens.
+
+        Costs can be fractional, e.g., 0.1 for a cheap request. Costs above the capacity, e.g., for a bulk
+        request, wait for as many refills as they need, and leave the bucket empty.
+        """
+    def with_idempotency_key(self, key: str, window: float = 60) -> TokenBucket:
+        """
+        Return a handle on this bucket where acquires are deduplicated by `key`, e.g., a request id.
+
+        Repeated acquires with the same key within `window` seconds return the first acquire's slot,
+        without consuming more tokens. After the window, the key is forgotten.
+        """
+    def with_share(self, tenant: str, share: float) -> TokenBucket:
+        """
+        Return a handle on this bucket for `tenant`, which may use at most `share` (0 to 1) of its tokens per window.
+
+        A window is the time it takes to refill an empty bucket. Tenants over their share wait
+        for the next window, even if the bucket has tokens left.
+        """
+    async def report(self, outcome: Literal['ok', 'overloaded'], retry_after: Optional[float] = None) -> float:
+        """
+        Report the outcome of a downstream call, to adapt the rate of a bucket created with `adaptive=True`.
+
+        Overloads halve the rate and start a cooldown of `retry_after` seconds, or the time it takes
+        to refill an empty bucket, if longer. After the cooldown, each ok outcome adds a tenth of the
+        configured rate back. Returns the factor the configured rate is now scaled by.
+        """
+    async def resize(self, capacity: int) -> None:
+        """
+        Change the bucket's capacity, here and in redis.
+
+        Slots already assigned are kept, and the tokens left are capped at the new capacity.
+        Other instances for the same bucket keep their capacity until resized too.
+        """
+
+class Acquisition:
+    holder_id: str
+    waited_ms: int  # Milliseconds spent waiting to acquire the semaphore
+    weight: int  # Permits taken, and returned on release
+    fencing_token: Optional[int]  # Greater than any token handed out before it. Set when created with fencing=True.
+    position: Optional[int]  # Tickets ahead of ours on arrival. Set when created with fair=True.
+    released: bool
+
+    async def release(self) -> None:
+        """
+        Release the permit held. Idempotent, so safe to call inside an `async with` block.
+        """
 
 class Semaphore:
     def __init__(
         self,
         name: str,
-        capacity: int,
-        max_sleep: Optional[float] = None,  # Set to 0.0 when None is passed. In seconds.
-        expiry: Optional[int] = None,  # Set to 30 when None is passed. In seconds.
+        capacity: int,  # At most 1,000,000
+        max_sleep: Optional[float] = None,  # Set to 0.0 when None is passed. In seconds, at most a year.
+        expiry: Optional[int] = 30,  # In seconds. None means the semaphore never expires.
         redis_url: Optional[str] = None,  # will be set as "redis://127.0.0.1:6379" if None
         connection_pool_size: Optional[int] = None,  # Will be set to 15 if None
+        eager_connect: Optional[bool] = None,  # Set to False when None is passed. PINGs redis on init when True.
+        # Called with (seconds waited, queue position or None) while waiting. Exceptions are swallowed.
+        wait_callback: Optional[Callable[[float, Optional[int]], None]] = None,
+        wait_callback_interval: Optional[int] = None,  # Set to 5 when None is passed. In seconds.
+        fair: Optional[bool] = None,  # Set to False when None is passed. Queues waiters with tickets when True.
+        dedicated_connection: Optional[bool] = None,  # Set to False when None is passed. Waits outside the pool when True.
+        quiet: Optional[bool] = None,  # Set to False when None is passed. Suppresses per-acquire logs when True.
+        connect_timeout: Optional[float] = None,  # In seconds. Connecting to redis fails after this when set.
+        response_timeout: Optional[float] = None,  # In seconds. Commands fail after this when set. Blocking waits are exempt.
+        count_rejections: Optional[bool] = None,  # Set to False when None is passed. Counts max sleep rejections in redis when True.
+        no_lua: Optional[bool] = None,  # Set to False when None is passed. Uses plain commands instead of Lua scripts when True.
+        backoff: Optional[Literal['constant', 'linear', 'exponential']] = None,  # Set to 'constant' when None is passed.
+        backoff_interval: Optional[float] = None,  # Set to 0.02 when None is passed. In seconds.
+        counter: Optional[bool] = None,  # Set to False when None is passed. Stores permits as an integer when True.
+        suppress_release_errors: Optional[bool] = None,  # Set to False when None is passed. Logs errors on exit when True.
+        track_holders: Optional[bool] = None,  # Set to False when None is passed. Records holders in redis when True.
+        # Called with the seconds spent calling redis after each acquire, excluding the wait. Exceptions are swallowed.
+        latency_callback: Optional[Callable[[float], None]] = None,
+        dry_run: Optional[bool] = None,  # Set to False when None is passed. Logs instead of waiting when True.
+        fencing: Optional[bool] = None,  # Set to False when None is passed. Hands out fencing tokens when True.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+        renew_expiry: Optional[bool] = None,  # Set to False when None is passed. Renews the expiry while held.
+        reclaim: Optional[bool] = None,  # Set to False when None is passed. Reclaims permits of crashed holders.
+        prefix: Optional[str] = None,  # Set to '__self-limiters:' when None is passed. Keys are named {prefix}{name}.
+        max_retries: Optional[int] = None,  # Set to 0 when None is passed. Retries for connection errors.
+        retry_backoff: Optional[float] = None,  # Set to 0.1 when None is passed. In seconds, doubled per retry.
+        username: Optional[str] = None,  # ACL username. Overrides any in the url.
+        password: Optional[str] = None,  # Overrides any in the url. Never exposed as an attribute.
+    ) -> None: ...
+
+    capacity: int
+    name: str
+    max_sleep: float
+    expiry: Optional[int]
+    wait_callback_interval: int
+    fair: bool
+    backoff: str
+    backoff_interval: float
+    dedicated_connection: bool
+    quiet: bool
+    count_rejections: bool
+    no_lua: bool
+    counter: bool
+    suppress_release_errors: bool
+    track_holders: bool
+    dry_run: bool
+    fencing: bool
+    verify_tls: bool
+    renew_expiry: bool
+    reclaim: bool
+    max_retries: int
+    retry_backoff: float
+    entered_count: int  # Acquisitions made by this instance, not counting try_acquire
+    exited_count: int  # Acquisitions released by this instance, not counting release(). Drift suggests leaks.
+
+    async def __aenter__(self) -> Acquisition: ...
+    async def __aexit__(
+        self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
+    ) -> None: ...
+    def __enter__(self) -> Acquisition: ...  # Blocks the thread, with the GIL released, until acquired
+    def __exit__(
+        self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
+    ) -> None: ...
+    def limit(self, func: F) -> F:
+        """
+        Decorate an async function, so that every call to it runs inside an `async with` block on this limiter.
+        """
+    async def acquire(
+        self, id: Optional[str] = None, cancel: Optional[Event] = None, weight: int = 1
+    ) -> Acquisition:
+        """
+        Acquire the semaphore, with `id` identifying the holder.
+
+        The returned acquisition is not released on its own; call `release` on it when done.
+
+        The id is used verbatim as the holder's ticket when `fair=True`, so it must be unique
+        among concurrent waiters. A random id is generated when none is passed.
+
+        Setting `cancel` while waiting gives up the wait, and raises AbortedError.
+
+        A `weight` takes that many permits at once, all or nothing, and releasing returns them all.
+        Not supported with `fair=True` or `no_lua=True`.
+        """
+    async def try_acquire(self) -> bool:
+        """
+        Take a permit if one is free right away, without waiting. Returns whether we got one.
+
+        Bypasses the queue of waiters when `fair=True`. Release a permit taken with `release`.
+        """
+    async def acquire_sharded(self, shards: int) -> Acquisition:
+        """
+        Acquire a permit from any of `shards` semaphores, named `{name}-{index}`, without waiting.
+
+        The capacity is split across shards, with the remainder going to the lowest indexes.
+        Raises MaxSleepExceededError right away if no shard has a free permit.
+        The returned acquisition must be released explicitly.
+        """
+    async def abort_all(self) -> None:
+        """
+        Make everyone currently waiting for the semaphore, in any process, raise an AbortedError.
+
+        Holders keep their permits, and clients that start waiting afterwards are unaffected.
+        """
+    def backoff_delay(self, position: int) -> float:
+        """
+        Return how long a fair waiter sleeps between polls, in seconds, with `position` tickets ahead of it.
+        """
+    async def exists(self) -> bool:
+        """
+        Return whether the semaphore currently exists in redis, without creating it.
+
+        This says nothing about free capacity; a fully acquired semaphore still exists.
+        """
+    async def actual_capacity(self) -> Optional[int]:
+        """
+        Return the capacity the semaphore was created with in redis, or None if it doesn't exist.
+
+        Compare this to `capacity` to detect instances configured with different capacities.
+        """
+    async def available(self) -> int:
+        """
+        Return the number of permits free right now, without acquiring them.
+
+        A semaphore that doesn't exist yet is created full on first use, so this is the capacity then.
+        """
+    async def waiters(self) -> Optional[int]:
+        """
+        Return the number of waiters queued for a permit with `fair=True`, else None.
+        """
+    async def rejection_rate(self) -> int:
+        """
+        Return the number of rejections counted across all clients in the current 60 second window.
+        """
+    async def holders(self) -> list[dict[str, Any]]:
+        """
+        Return the current holders, oldest first, as dicts with `id`, `host`, `pid` and `since`, a unix timestamp.
+
+        Holders are only recorded with `track_holders=True`.
+        """
+    async def snapshot(self) -> dict[str, Any]:
+        """
+        Return the semaphore's configuration and state in redis.
+
+        The state is `available`, the number of permits available, or None if the semaphore doesn't exist.
+        """
+    async def restore(self, snapshot: dict[str, Any]) -> None:
+        """
+        Write the state from a snapshot to this semaphore in redis, replacing any existing state.
+        """
+    def with_key(self, key_suffix: str) -> Semaphore:
+        """
+        Return a semaphore for the key `{name}:{key_suffix}`, sharing this semaphore's settings and connection pools.
+        """
+    @staticmethod
+    def from_client(
+        client: RedisClient,
+        name: str,
+        capacity: int,
+        max_sleep: Optional[float] = None,
+        expiry: Optional[int] = 30,
+        quiet: Optional[bool] = None,
+        prefix: Optional[str] = None,
+    ) -> Semaphore:
+        """
+        Create a semaphore using the connection pools of `client`. Other settings are left at their defaults.
+        """
+    def with_redis_url(self, redis_url: str) -> Semaphore:
+        """
+        Return a handle on this semaphore using the redis server at `redis_url`, sharing this semaphore's settings.
+
+        Connection pools are cached per url, and shared by all handles derived from this semaphore.
+        """
+    def wait_stats(self) -> dict[str, Optional[int]]:
+        """
+        Return percentiles of the time recently spent waiting to acquire the semaphore, by this instance.
+
+        The dict has `count`, the number of samples, and `p50`, `p90` and `p99` in milliseconds,
+        which are None until the semaphore has been acquired. The 1024 most recent samples are kept.
+        """
+    async def release(self, permits: int = 1) -> int:
+        """
+        Release permits back to the semaphore, without exceeding its capacity.
+
+        Returns the number of permits released.
+        """
+
+class CompositeLimiter:
+    """
+    Limits traffic to `refill_amount` requests per `refill_frequency` seconds,
+    and at most `concurrency` requests at the same time.
+
+    The `max_sleep` budget is shared between waiting for a token and waiting for a slot.
+    """
+
+    def __init__(
+        self,
+        name: str,
+        capacity: int,
+        refill_frequency: float,
+        refill_amount: int,
+        concurrency: int,
+        max_sleep: Optional[float] = None,  # Set to 0.0 when None is passed. In seconds, at most a year.
+        redis_url: Optional[str] = None,  # will be set as "redis://127.0.0.1:6379" if None
+        connection_pool_size: Optional[int] = None,  # Will be set to 30 if None
+        quiet: Optional[bool] = None,  # Set to False when None is passed. Suppresses per-acquire logs when True.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+        username: Optional[str] = None,  # ACL username. Overrides any in the url.
+        password: Optional[str] = None,  # Overrides any in the url. Never exposed as an attribute.
     ) -> None: ...
 
+    name: str
     capacity: int
+    refill_frequency: float
+    refill_amount: int
+    concurrency: int
+    max_sleep: float
+    quiet: bool
+    verify_tls: bool
+
+    async def __aenter__(self) -> None: ...
+    async def __aexit__(
+        self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
+    ) -> None: ...
+    def limit(self, func: F) -> F:
+        """
+        Decorate an async function, so that every call to it runs inside an `async with` block on this limiter.
+        """
+
+class QuorumSemaphore:
+    """
+    Limits traffic to `capacity` requests at the same time, like a Semaphore, across independent redis instances.
+
+    Acquires need a permit from a majority of the instances, so a minority of them can be lost.
+    """
+
+    def __init__(
+        self,
+        name: str,
+        capacity: int,  # At most 1,000,000
+        redis_urls: list[str],  # At least 3
+        max_sleep: Optional[float] = None,  # Set to 0.0 when None is passed. In seconds, at most a year.
+        expiry: Optional[int] = 30,  # In seconds. None means the semaphores never expire.
+        connection_pool_size: Optional[int] = None,  # Per instance. Will be set to 15 if None
+        connect_timeout: Optional[float] = None,  # In seconds. Connecting to redis fails after this when set.
+        response_timeout: Optional[float] = None,  # In seconds. Commands fail after this when set.
+        quiet: Optional[bool] = None,  # Set to False when None is passed. Suppresses per-acquire logs when True.
+        verify_tls: Optional[bool] = None,  # Set to True when None is passed. Applies to every instance.
+        username: Optional[str] = None,  # ACL username, for every instance. Overrides any in the urls.
+        password: Optional[str] = None,  # Overrides any in the urls. Never exposed as an attribute.
+    ) -> None: ...
+
     name: str
+    capacity: int
     max_sleep: float
-    expiry: int
+    quiet: bool
+    verify_tls: bool
+    quorum: int  # The number of instances that must grant a permit
 
     async def __aenter__(self) -> None: ...
     async def __aexit__(
         self, exc_type: type[BaseException] | None, exc_val: BaseException | None, exc_tb: TracebackType | None
     ) -> None: ...
+    def limit(self, func: F) -> F:
+        """
+        Decorate an async function, so that every call to it runs inside an `async with` block on this limiter.
+        """
+
+async def purge(
+    redis_url: Optional[str],
+    older_than: int,
+    dry_run: Optional[bool] = None,
+    verify_tls: Optional[bool] = None,  # Set to True when None is passed. Only applies to rediss:// urls.
+    username: Optional[str] = None,  # ACL username. Overrides any in the url.
+    password: Optional[str] = None,  # Overrides any in the url.
+    prefix: Optional[str] = None,  # Will be set to "__self-limiters:" if None. Only keys with this prefix are purged.
+) -> list[str]:
+    """
+    Delete limiter keys without an expiry that have been idle for `older_than` seconds.
+
+    Returns the stale keys. Nothing is deleted when `dry_run` is True.
+    """
+
+async def transfer_capacity(from_semaphore: Semaphore, to_semaphore: Semaphore, n: int, partial: bool = False) -> int:
+    """
+    Atomically move `n` free permits from one semaphore to the other, adjusting both capacities.
+
+    When the source has fewer than `n` free permits, nothing is moved, unless `partial` is True,
+    in which case all free permits are. Returns the number of permits moved.
+    """
+
+async def shutdown(timeout: float) -> int:
+    """
+    Wait up to `timeout` seconds for all semaphore permits acquired in this process to be released.
+
+    Permits taken with `try_acquire` aren't tracked by an acquisition, so they're not waited for.
+    Returns the number of permits that weren't released in time.
+    """
+
+def enable_metrics(enabled: bool = True) -> None:
+    """
+    Start, or with `enabled=False` stop, counting acquires and releases for `collect_metrics`.
+    """
+
+def collect_metrics() -> dict[str, dict[str, int]]:
+    """
+    Return the counters of every limiter used since metrics were enabled, by limiter name.
+
+    Each has `acquisitions`, `wait_ms`, `max_sleep_exceeded` and `releases`, which only ever increase.
+    Limiters are never dropped from the result, so it has an entry per key for limiters derived with `with_key`.
+    """
+
+def register(name: str, limiter: TokenBucket | Semaphore | CompositeLimiter | QuorumSemaphore) -> None:
+    """
+    Register a limiter under `name` for the whole process, replacing any limiter already registered under it.
+    """
+
+def get(name: str) -> TokenBucket | Semaphore | CompositeLimiter | QuorumSemaphore:
+    """
+    Return the limiter registered under `name`. Raises KeyError if there is none.
+    """
 
 __all__: list[str]
 
@@ -51,6 +578,29 @@ class RedisError(Exception):
     Raised when the downstream redis library raises any exception.
     """
 
+    limiter_name: str  # Name of the limiter that raised, when raised while acquiring or releasing
+
+class ScriptError(RedisError):
+    """
+    Raised when one of the package's Lua scripts fails to run.
+    """
+
+    script: str  # Name of the failing script
+
+class RedisConnectionError(RedisError):
+    """
+    Raised when connecting to redis fails or times out, or the connection drops or times out mid-command.
+    """
+
+    pass
+
+class RedisWriteError(RedisError):
+    """
+    Raised when Redis refuses a write, e.g., when it's out of memory or a read-only replica.
+
+    The limiter's state couldn't be persisted, so the acquire fails rather than proceed unlimited.
+    """
+
     pass
 
 class MaxSleepExceededError(Exception):
@@ -58,4 +608,33 @@ class MaxSleepExceededError(Exception):
     Raised when we've slept for longer than the `max_sleep` specified limit.
     """
 
-    pass
+    limiter_name: str  # Name of the limiter that raised
+
+class BacklogExceededError(Exception):
+    """
+    Raised when a token bucket would schedule a client further ahead than its `max_backlog`.
+    """
+
+    limiter_name: str  # Name of the limiter that raised
+
+class WouldDeadlockError(Exception):
+    """
+    Raised when a task acquires a semaphore it already holds the full capacity of, without a `max_sleep`.
+    """
+
+    limiter_name: str  # Name of the limiter that raised
+
+class LimiterTypeConflictError(Exception):
+    """
+    Raised when a limiter's key is already used by a limiter of another type, e.g., a Semaphore and a TokenBucket
+    with the same name.
+    """
+
+    limiter_name: str  # Name of the limiter that raised
+
+class AbortedError(Exception):
+    """
+    Raised in clients waiting for a semaphore when `Semaphore.abort_all` is called.
+    """
+
+    limiter_name: str  # Name of the aborted semaphore
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bb8_redis::bb8::Pool;
+use bb8_redis::RedisConnectionManager;
+use log::debug;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::utils::{
+    create_connection_manager, create_connection_pool, positive_seconds, Credentials, PoolCache, RetryPolicy,
+};
+
+/// The default size of each of the client's connection pools.
+const DEFAULT_POOL_SIZE: u32 = 15;
+
+/// A redis connection, built once and shared by any number of limiters created with `from_client`.
+///
+/// Like a semaphore, the client keeps separate pools for acquiring and releasing, so that
+/// releases never wait behind acquires blocked on an empty semaphore.
+///
+/// Limiters created from the client also use its response timeout and retry settings.
+#[pyclass(
+    frozen,
+    text_signature = "(redis_url=None, connection_pool_size=None, eager_connect=None, connect_timeout=None, \
+    verify_tls=None, username=None, password=None, response_timeout=None, max_retries=None, retry_backoff=None)"
+)]
+pub(crate) struct RedisClient {
+    #[pyo3(get)]
+    connection_pool_size: u32,
+    #[pyo3(get)]
+    pub(crate) verify_tls: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) response_timeout: Option<Duration>,
+    pub(crate) retries: RetryPolicy,
+    pub(crate) open_pool: Pool<RedisConnectionManager>,
+    pub(crate) return_pool: Pool<RedisConnectionManager>,
+    /// Pools for other redis servers, for handles returned by `with_redis_url`
+    pub(crate) open_pools: Arc<PoolCache>,
+    pub(crate) return_pools: Arc<PoolCache>,
+}
+
+#[pymethods]
+impl RedisClient {
+    /// Create a new class instance.
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        redis_url: Option<&str>,
+        connection_pool_size: Option<u32>,
+        eager_connect: Option<bool>,
+        connect_timeout: Option<f32>,
+        verify_tls: Option<bool>,
+        username: Option<String>,
+        password: Option<String>,
+        response_timeout: Option<f32>,
+        max_retries: Option<u32>,
+        retry_backoff: Option<f32>,
+    ) -> PyResult<Self> {
+        debug!("Creating new RedisClient instance");
+
+        let connect_timeout = connect_timeout
+            .map(|t| positive_seconds("Connect timeout", t))
+            .transpose()?;
+        let response_timeout = response_timeout
+            .map(|t| positive_seconds("Response timeout", t))
+            .transpose()?;
+        let retries = RetryPolicy::new(max_retries, retry_backoff)?;
+        let connection_pool_size = connection_pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+        if connection_pool_size == 0 {
+            return Err(PyValueError::new_err("Connection pool size must be greater than 0"));
+        }
+
+        let verify_tls = verify_tls.unwrap_or(true);
+        let credentials = Credentials { username, password };
+        let open_manager = create_connection_manager(redis_url, verify_tls, &credentials)?;
+        let return_manager = create_connection_manager(redis_url, verify_tls, &credentials)?;
+        let eager_connect = eager_connect.unwrap_or(false);
+
+        Ok(Self {
+            connection_pool_size,
+            verify_tls,
+            connect_timeout,
+            response_timeout,
+            retries,
+            open_pool: create_connection_pool(open_manager, connection_pool_size, eager_connect, connect_timeout)?,
+            return_pool: create_connection_pool(return_manager, connection_pool_size, false, connect_timeout)?,
+            open_pools: Arc::new(PoolCache::new(
+                connection_pool_size,
+                connect_timeout,
+                verify_tls,
+                credentials.clone(),
+            )),
+            return_pools: Arc::new(PoolCache::new(
+                connection_pool_size,
+                connect_timeout,
+                verify_tls,
+                credentials,
+            )),
+        })
+    }
+
+    /// How many times to retry connection failures of limiters created from the client.
+    #[getter]
+    fn max_retries(&self) -> u32 {
+        self.retries.max_retries
+    }
+
+    /// How long to wait before the first retry, in seconds. Later retries wait exponentially longer.
+    #[getter]
+    fn retry_backoff(&self) -> f32 {
+        self.retries.backoff.as_secs_f32()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "RedisClient with pools of max {} connections",
+            self.connection_pool_size
+        )
+    }
+}
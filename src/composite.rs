@@ -0,0 +1,182 @@
+use log::debug;
+use nanoid::nanoid;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use pyo3_asyncio::tokio::future_into_py;
+
+use crate::errors::SLError;
+use crate::semaphore::{self, create_and_acquire_semaphore, release_semaphore, Semaphore};
+use crate::shutdown::track_released;
+use crate::token_bucket::{self, schedule_and_sleep, TokenBucket};
+use crate::utils::{
+    at_most_max_capacity, create_connection_manager, create_connection_pool, limit, max_sleep_seconds, now_millis,
+    positive_seconds, Credentials, SLResult, REDIS_KEY_PREFIX,
+};
+
+/// Wait for a token, then for a semaphore slot.
+///
+/// The `max_sleep` budget is shared, so time spent waiting
+/// for a token counts against the time allowed waiting for a slot.
+async fn acquire(
+    token_bucket_ts: token_bucket::ThreadState,
+    mut semaphore_ts: semaphore::ThreadState,
+    max_sleep: f32,
+    quiet: bool,
+) -> SLResult<()> {
+    let start = now_millis()?;
+    schedule_and_sleep(token_bucket_ts).await?;
+
+    if max_sleep > 0.0 {
+        let remaining = max_sleep - (now_millis()? - start) as f32 / 1000.0;
+        if remaining <= 0.0 {
+            return Err(SLError::MaxSleepExceeded(
+                "Max sleep exceeded waiting for a token".to_string(),
+            ));
+        }
+        if !quiet {
+            debug!("Retrieved token. {} seconds left of the max sleep budget", remaining);
+        }
+        semaphore_ts.max_sleep = remaining;
+    }
+
+    create_and_acquire_semaphore(semaphore_ts, &nanoid!(10), None).await?;
+    Ok(())
+}
+
+/// Async context manager combining a token bucket and a semaphore,
+/// for limiting traffic to `n` requests per `m` unit of time, *and* at
+/// most `c` requests concurrently. For example, 10 requests per second
+/// with at most 2 in flight at the same time.
+#[pyclass(
+    frozen,
+    text_signature = "(name, capacity, refill_frequency, refill_amount, concurrency, max_sleep=None, \
+    redis_url=None, connection_pool_size=None, quiet=None, verify_tls=None, username=None, password=None)"
+)]
+#[pyo3(name = "CompositeLimiter")]
+#[pyo3(module = "self_limiters")]
+pub(crate) struct CompositeLimiter {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    capacity: u32,
+    #[pyo3(get)]
+    refill_frequency: f32,
+    #[pyo3(get)]
+    refill_amount: u32,
+    #[pyo3(get)]
+    concurrency: u32,
+    #[pyo3(get)]
+    max_sleep: f32,
+    #[pyo3(get)]
+    quiet: bool,
+    #[pyo3(get)]
+    verify_tls: bool,
+    token_bucket: TokenBucket,
+    semaphore: Semaphore,
+}
+
+#[pymethods]
+impl CompositeLimiter {
+    /// Create a new class instance.
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        name: String,
+        capacity: u32,
+        refill_frequency: f32,
+        refill_amount: u32,
+        concurrency: u32,
+        max_sleep: Option<f32>,
+        redis_url: Option<&str>,
+        connection_pool_size: Option<u32>,
+        quiet: Option<bool>,
+        verify_tls: Option<bool>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> PyResult<Self> {
+        debug!("Creating new CompositeLimiter instance");
+
+        positive_seconds("Refill frequency", refill_frequency)?;
+        at_most_max_capacity("Capacity", capacity.into())?;
+        if refill_amount == 0 {
+            return Err(PyValueError::new_err("Refill amount must be greater than 0"));
+        }
+        at_most_max_capacity("Refill amount", refill_amount.into())?;
+        at_most_max_capacity("Concurrency", concurrency.into())?;
+        let max_sleep = max_sleep_seconds(max_sleep)?;
+
+        // Create redis connection manager
+        let verify_tls = verify_tls.unwrap_or(true);
+        let manager = create_connection_manager(redis_url, verify_tls, &Credentials { username, password })?;
+
+        // Create a connection pool, shared by the token bucket and semaphore
+        let pool = create_connection_pool(manager, connection_pool_size.unwrap_or(30), false, None)?;
+
+        let name = format!("{}{}", REDIS_KEY_PREFIX, name);
+        let quiet = quiet.unwrap_or(false);
+        Ok(Self {
+            token_bucket: TokenBucket::with_pool(
+                format!("{}-bucket", name),
+                capacity,
+                refill_frequency,
+                refill_amount as f32,
+                max_sleep,
+                quiet,
+                pool.clone(),
+            ),
+            semaphore: Semaphore::with_pool(py, name.clone(), concurrency, max_sleep, Some(30), quiet, pool)?,
+            name,
+            capacity,
+            refill_frequency,
+            refill_amount,
+            concurrency,
+            max_sleep,
+            quiet,
+            verify_tls,
+        })
+    }
+
+    /// Wait for a token, then for a semaphore slot.
+    #[pyo3(text_signature = "($self)")]
+    fn __aenter__<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let token_bucket_ts = token_bucket::ThreadState::from(&self.token_bucket);
+        let semaphore_ts = semaphore::ThreadState::from(&self.semaphore);
+        let max_sleep = self.max_sleep;
+        let quiet = self.quiet;
+        let name = self.name.clone();
+        future_into_py(py, async move {
+            acquire(token_bucket_ts, semaphore_ts, max_sleep, quiet)
+                .await
+                .map_err(|e| e.for_limiter(&name))
+        })
+    }
+
+    /// Release the semaphore slot.
+    #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
+    fn __aexit__<'p>(&self, py: Python<'p>, _a: &'p PyTuple) -> PyResult<&'p PyAny> {
+        let ts = semaphore::ThreadState::from(&self.semaphore);
+        let name = self.name.clone();
+        future_into_py(py, async move {
+            let result = release_semaphore(&ts, 1).await;
+            track_released();
+            result.map_err(|e| e.for_limiter(&name))?;
+            ts.record_release(1);
+            Ok(())
+        })
+    }
+
+    /// Decorate an async function, so that every call to it runs inside an `async with` block on the limiter.
+    ///
+    /// The wrapped function's return value and exceptions are passed through as is.
+    #[pyo3(text_signature = "($self, func)")]
+    fn limit(slf: &PyCell<Self>, func: PyObject) -> PyResult<PyObject> {
+        limit(slf.py(), slf.as_ref().into(), func)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Composite limiter instance for queue {}", &self.name)
+    }
+}
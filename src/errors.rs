@@ -8,14 +8,39 @@ use bb8_redis::bb8::RunError;
 use pyo3::create_exception;
 use pyo3::exceptions::{PyException, PyRuntimeError};
 use pyo3::prelude::*;
-use redis::RedisError as RedisLibError;
+use redis::{ErrorKind, RedisError as RedisLibError};
 
 // Raised when redis::RedisError is raised by the redis crate.
 create_exception!(self_limiters, RedisError, PyException);
 
+// Raised when one of our Lua scripts fails to run. Subclasses RedisError,
+// and carries the name of the failing script in its `script` attribute.
+create_exception!(self_limiters, ScriptError, RedisError);
+
+// Raised when we can't connect to redis, or the connection drops or times out. Subclasses RedisError.
+// Named so it doesn't shadow the builtin ConnectionError on star imports.
+create_exception!(self_limiters, RedisConnectionError, RedisError);
+
+// Raised when redis refuses a write, e.g., because it's out of memory. Subclasses RedisError.
+// Limiter state can't be persisted when this happens, so we raise rather than let callers through.
+create_exception!(self_limiters, RedisWriteError, RedisError);
+
 // Raised when we've slept for too long. Useful for catching forever-growing queues.
 create_exception!(self_limiters, MaxSleepExceededError, PyException);
 
+// Raised when a token bucket's backlog is too long to schedule more clients, with `max_backlog` set.
+create_exception!(self_limiters, BacklogExceededError, PyException);
+
+// Raised in waiters woken up by `Semaphore.abort_all`, e.g., on shutdown.
+create_exception!(self_limiters, AbortedError, PyException);
+
+// Raised when a limiter's key is already used by a limiter of another type, e.g., a
+// Semaphore and a TokenBucket created with the same name. Sharing a key would corrupt both.
+create_exception!(self_limiters, LimiterTypeConflictError, PyException);
+
+// Raised when a task tries to acquire a semaphore it already holds all the capacity of, without a `max_sleep`.
+create_exception!(self_limiters, WouldDeadlockError, PyException);
+
 /// Enum containing all handled errors.
 /// This enables us to use the `?` operator on function calls to utilities
 /// that raise any of the mapped errors below, to automatically raise the
@@ -23,7 +48,14 @@ create_exception!(self_limiters, MaxSleepExceededError, PyException);
 #[derive(Debug)]
 pub(crate) enum SLError {
     MaxSleepExceeded(String),
+    BacklogExceeded(String),
+    Aborted(String),
+    WouldDeadlock(String),
+    TypeConflict(String),
     Redis(String),
+    Connection(String),
+    Script(&'static str, String),
+    Write(String),
     RuntimeError(String),
 }
 
@@ -32,16 +64,89 @@ impl From<SLError> for PyErr {
     fn from(e: SLError) -> Self {
         match e {
             SLError::MaxSleepExceeded(e) => MaxSleepExceededError::new_err(e),
+            SLError::BacklogExceeded(e) => BacklogExceededError::new_err(e),
+            SLError::Aborted(e) => AbortedError::new_err(e),
+            SLError::WouldDeadlock(e) => WouldDeadlockError::new_err(e),
+            SLError::TypeConflict(e) => LimiterTypeConflictError::new_err(e),
             SLError::Redis(e) => RedisError::new_err(e),
+            SLError::Connection(e) => RedisConnectionError::new_err(e),
+            SLError::Script(script, e) => with_attribute(
+                ScriptError::new_err(format!("Failed to run the {} script: {}", script, e)),
+                "script",
+                script,
+            ),
+            SLError::Write(e) => RedisWriteError::new_err(format!("Redis refused a write: {}", e)),
             SLError::RuntimeError(e) => PyRuntimeError::new_err(e),
         }
     }
 }
 
+impl SLError {
+    /// Convert to a Python exception, with the name of the limiter that raised it in a `limiter_name` attribute.
+    pub(crate) fn for_limiter(self, name: &str) -> PyErr {
+        with_attribute(self.into(), "limiter_name", name)
+    }
+}
+
+/// Set an attribute on the exception, or return the error raised trying to.
+fn with_attribute(err: PyErr, name: &str, value: &str) -> PyErr {
+    Python::with_gil(|py| match err.value(py).setattr(name, value) {
+        Ok(()) => err,
+        Err(e) => e,
+    })
+}
+
 // redis::RedisError could be raised any time we perform a call to redis
 impl From<RedisLibError> for SLError {
     fn from(e: RedisLibError) -> Self {
-        Self::Redis(e.to_string())
+        if is_write_failure(&e) {
+            Self::Write(e.to_string())
+        } else if is_connection_failure(&e) {
+            Self::Connection(e.to_string())
+        } else {
+            Self::Redis(e.to_string())
+        }
+    }
+}
+
+/// Whether the error is about the connection rather than the command, e.g., the server
+/// refusing connections, the connection dropping, or a command timing out.
+fn is_connection_failure(e: &RedisLibError) -> bool {
+    e.kind() == ErrorKind::IoError || e.is_connection_refusal() || e.is_connection_dropped() || e.is_timeout()
+}
+
+/// Whether redis refused to write, because it's out of memory, a read-only
+/// replica, or failing to persist to disk. This also applies to writes from scripts.
+fn is_write_failure(e: &RedisLibError) -> bool {
+    e.kind() == ErrorKind::ReadOnly
+        || matches!(e.code(), Some("OOM" | "MISCONF" | "READONLY"))
+        || e.detail()
+            .map_or(false, |detail| detail.contains("OOM command not allowed"))
+}
+
+/// Whether redis replied with an error to the script call itself, e.g., a command failing
+/// inside the script, or the script's reply not having the type we expect.
+fn is_script_failure(e: &RedisLibError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::NoScriptError | ErrorKind::ResponseError | ErrorKind::ExtensionError | ErrorKind::TypeError
+    )
+}
+
+/// Map errors raised while running one of our Lua scripts to a script error,
+/// naming the script. Type conflicts reported by the scripts get their own error,
+/// while other errors, including refused writes and connection failures, are mapped as usual.
+pub(crate) fn map_script_error(e: RedisLibError, script: &'static str) -> SLError {
+    if is_write_failure(&e) {
+        return e.into();
+    }
+    if e.code() == Some("LIMITERTYPE") {
+        return SLError::TypeConflict(e.detail().unwrap_or_default().to_string());
+    }
+    if is_script_failure(&e) {
+        SLError::Script(script, e.to_string())
+    } else {
+        e.into()
     }
 }
 
@@ -73,9 +178,12 @@ impl From<SystemTimeError> for SLError {
     }
 }
 
-// RunError<RedisError> could happen when creating a connection pool
+// RunError<RedisError> could happen when getting a connection from a connection pool
 impl From<RunError<redis::RedisError>> for SLError {
     fn from(e: RunError<redis::RedisError>) -> Self {
-        Self::RuntimeError(e.to_string())
+        match e {
+            RunError::User(e) => e.into(),
+            RunError::TimedOut => Self::Connection("Timed out connecting to redis".to_string()),
+        }
     }
 }
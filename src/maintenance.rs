@@ -0,0 +1,105 @@
+use bb8_redis::bb8::ManageConnection;
+use bb8_redis::RedisConnectionManager;
+use log::{debug, info};
+use pyo3::prelude::*;
+use pyo3_asyncio::tokio::future_into_py;
+use redis::{AsyncCommands, RedisError};
+
+use crate::errors::SLError;
+use crate::utils::{create_connection_manager, prefixed_name, Credentials, SLResult};
+
+/// Map errors for commands that are disabled, renamed, or not permitted by an ACL
+/// to an actionable error. Other errors are mapped as usual.
+fn map_command_error(e: RedisError, command: &str) -> SLError {
+    if e.code() == Some("NOPERM") || e.to_string().contains("unknown command") {
+        SLError::Redis(format!(
+            "Purging requires the {} command, which is disabled or not permitted for this user. \
+            See the README for the full list of commands required. Original error: {}",
+            command, e
+        ))
+    } else {
+        e.into()
+    }
+}
+
+async fn purge_stale_keys(
+    manager: RedisConnectionManager,
+    prefix: String,
+    older_than: u64,
+    dry_run: bool,
+) -> SLResult<Vec<String>> {
+    // Connect to redis
+    let mut connection = manager.connect().await?;
+
+    // Collect all our keys first, since the scan iterator borrows the connection
+    let mut keys: Vec<String> = vec![];
+    {
+        let mut iter = connection
+            .scan_match::<_, String>(format!("{}*", prefix))
+            .await
+            .map_err(|e| map_command_error(e, "SCAN"))?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+    debug!("Found {} keys to inspect", keys.len());
+
+    let mut stale_keys = vec![];
+    for key in keys {
+        // Keys with a TTL are left for redis to expire. Keys that have
+        // already expired (-2) are gone, even if they showed up in the scan.
+        let ttl: i64 = connection.ttl(&key).await.map_err(|e| map_command_error(e, "TTL"))?;
+        if ttl != -1 {
+            continue;
+        }
+
+        // Keys without a TTL are stale once they've been idle long enough
+        let idle: Option<u64> = redis::cmd("OBJECT")
+            .arg("IDLETIME")
+            .arg(&key)
+            .query_async(&mut connection)
+            .await
+            .map_err(|e| map_command_error(e, "OBJECT"))?;
+        if idle.map_or(false, |idle| idle >= older_than) {
+            stale_keys.push(key);
+        }
+    }
+
+    if !dry_run && !stale_keys.is_empty() {
+        connection
+            .del::<_, ()>(&stale_keys)
+            .await
+            .map_err(|e| map_command_error(e, "DEL"))?;
+        info!("Purged {} stale keys", stale_keys.len());
+    }
+    Ok(stale_keys)
+}
+
+/// Delete keys that have been idle for at least `older_than` seconds, and have no expiry.
+///
+/// Only keys starting with `prefix`, which defaults to `REDIS_KEY_PREFIX`, are inspected, so limiters
+/// created with another prefix are purged by passing the same one here.
+///
+/// Returns the names of the stale keys. When `dry_run` is set, nothing is deleted.
+#[pyfunction]
+#[pyo3(
+    text_signature = "(redis_url, older_than, dry_run=None, verify_tls=None, username=None, password=None, prefix=None)"
+)]
+pub(crate) fn purge<'p>(
+    py: Python<'p>,
+    redis_url: Option<&str>,
+    older_than: u64,
+    dry_run: Option<bool>,
+    verify_tls: Option<bool>,
+    username: Option<String>,
+    password: Option<String>,
+    prefix: Option<&str>,
+) -> PyResult<&'p PyAny> {
+    let prefix = prefixed_name(prefix, "")?;
+    let credentials = Credentials { username, password };
+    let manager = create_connection_manager(redis_url, verify_tls.unwrap_or(true), &credentials)?;
+    let dry_run = dry_run.unwrap_or(false);
+    future_into_py(py, async move {
+        Ok(purge_stale_keys(manager, prefix, older_than, dry_run).await?)
+    })
+}
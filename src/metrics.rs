@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::errors::SLError;
+use crate::utils::SLResult;
+
+/// Whether acquires and releases are counted. Off until `enable_metrics` is called.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Process-local counters, by limiter name.
+///
+/// Entries are never removed, so there's one for every limiter name used since metrics were enabled.
+/// Limiters derived per tenant, e.g., with `with_key`, each get their own.
+static METRICS: OnceLock<Mutex<HashMap<String, Arc<Counters>>>> = OnceLock::new();
+
+/// Running totals for a single limiter. These only ever increase, like Prometheus counters.
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub(crate) acquisitions: AtomicU64,
+    pub(crate) wait_ms: AtomicU64,
+    pub(crate) max_sleep_exceeded: AtomicU64,
+    pub(crate) releases: AtomicU64,
+}
+
+/// Return the counters for the limiter with the given name, creating them on first use.
+pub(crate) fn counters(name: &str) -> Arc<Counters> {
+    // A poisoned lock only means another thread panicked mid-update; the map itself is still usable
+    let mut metrics = METRICS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    match metrics.get(name) {
+        Some(counters) => counters.clone(),
+        None => metrics.entry(name.to_string()).or_default().clone(),
+    }
+}
+
+/// Run `acquire`, counting it and the time it took against the limiter with the given name.
+///
+/// Failed acquires count towards the wait time, and towards `max_sleep_exceeded` when that's
+/// why they failed, but not as acquisitions. Nothing is counted while metrics are disabled.
+pub(crate) async fn measured<T>(name: &str, acquire: impl Future<Output = SLResult<T>>) -> SLResult<T> {
+    measured_if(name, acquire, |_| true).await
+}
+
+/// Run `acquire` like `measured`, but only count it as an acquisition if `acquired` holds for its result.
+///
+/// This is for acquires that return whether they got anything, like `try_acquire`.
+pub(crate) async fn measured_if<T>(
+    name: &str,
+    acquire: impl Future<Output = SLResult<T>>,
+    acquired: impl FnOnce(&T) -> bool,
+) -> SLResult<T> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return acquire.await;
+    }
+    let start = Instant::now();
+    let result = acquire.await;
+    let counters = counters(name);
+    counters
+        .wait_ms
+        .fetch_add(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    match &result {
+        Ok(value) if acquired(value) => counters.acquisitions.fetch_add(1, Ordering::Relaxed),
+        Ok(_) => 0,
+        Err(SLError::MaxSleepExceeded(_)) => counters.max_sleep_exceeded.fetch_add(1, Ordering::Relaxed),
+        Err(_) => 0,
+    };
+    result
+}
+
+/// Count `releases` releases of the limiter with the given name.
+///
+/// Each should match an acquire counted by `measured`, so that the two can be compared.
+pub(crate) fn record_release(name: &str, releases: u64) {
+    if ENABLED.load(Ordering::Relaxed) {
+        counters(name).releases.fetch_add(releases, Ordering::Relaxed);
+    }
+}
+
+/// Start, or with `enabled=False` stop, counting acquires and releases for `collect_metrics`.
+///
+/// Counters are kept when disabled, and carry on from where they were if enabled again.
+#[pyfunction]
+#[pyo3(text_signature = "(enabled=True)")]
+pub(crate) fn enable_metrics(enabled: Option<bool>) {
+    ENABLED.store(enabled.unwrap_or(true), Ordering::Relaxed);
+}
+
+/// Return the counters of every limiter used since metrics were enabled, by limiter name.
+///
+/// Each limiter's counters are a dict of `acquisitions`, `wait_ms`, `max_sleep_exceeded`
+/// and `releases`. These are process-local, and read without calling redis. Limiters are never
+/// dropped from the result, so there's an entry per key for limiters derived with `with_key`.
+#[pyfunction]
+#[pyo3(text_signature = "()")]
+pub(crate) fn collect_metrics(py: Python<'_>) -> PyResult<&PyDict> {
+    let snapshot: Vec<(String, Arc<Counters>)> = {
+        let metrics = METRICS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        metrics
+            .iter()
+            .map(|(name, counters)| (name.clone(), counters.clone()))
+            .collect()
+    };
+    let dict = PyDict::new(py);
+    for (name, counters) in snapshot {
+        let stats = PyDict::new(py);
+        stats.set_item("acquisitions", counters.acquisitions.load(Ordering::Relaxed))?;
+        stats.set_item("wait_ms", counters.wait_ms.load(Ordering::Relaxed))?;
+        stats.set_item(
+            "max_sleep_exceeded",
+            counters.max_sleep_exceeded.load(Ordering::Relaxed),
+        )?;
+        stats.set_item("releases", counters.releases.load(Ordering::Relaxed))?;
+        dict.set_item(name, stats)?;
+    }
+    Ok(dict)
+}
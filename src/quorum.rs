@@ -0,0 +1,314 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, warn};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use pyo3_asyncio::tokio::future_into_py;
+
+use crate::errors::SLError;
+use crate::metrics::{measured, record_release};
+use crate::semaphore::{self, release_semaphore, try_acquire_semaphore, Semaphore};
+use crate::shutdown::{track_acquired, track_released};
+use crate::utils::{
+    at_most_max_capacity, check_expiry, create_connection_manager, create_connection_pool, limit, max_sleep_seconds,
+    now_millis, positive_seconds, Credentials, DropGuard, SLResult, REDIS_KEY_PREFIX,
+};
+
+/// How long to wait before retrying when a quorum wasn't reached, in milliseconds.
+/// A random delay of up to the same length is added, so competing clients don't retry in lockstep.
+const QUORUM_RETRY_INTERVAL_MS: u64 = 50;
+
+/// Take a permit from a majority of the instances, retrying until `max_sleep` runs out.
+///
+/// Instances are called one at a time. When fewer than a majority grant a permit, the permits
+/// granted are returned before retrying. Returns the indexes of the instances that granted one.
+async fn acquire_quorum(instances: Vec<semaphore::ThreadState>, max_sleep: f32, quiet: bool) -> SLResult<Vec<usize>> {
+    let start = now_millis()?;
+    let quorum = instances.len() / 2 + 1;
+    loop {
+        let mut granted = Vec::with_capacity(instances.len());
+        let mut failed = Vec::new();
+        for (i, ts) in instances.iter().enumerate() {
+            match try_acquire_semaphore(ts).await {
+                Ok(true) => granted.push(i),
+                Ok(false) => {}
+                Err(e) => failed.push(format!("{:?}", e)),
+            }
+        }
+        if granted.len() >= quorum {
+            if !quiet {
+                debug!(
+                    "Acquired permits from {} of {} instances",
+                    granted.len(),
+                    instances.len()
+                );
+            }
+            return Ok(granted);
+        }
+
+        // Give back what we got, so a failed attempt doesn't hold permits from others
+        release_instances(&instances, &granted).await;
+
+        if failed.len() > instances.len() - quorum {
+            return Err(SLError::Redis(format!(
+                "Only {} of {} redis instances responded, which is fewer than the quorum of {}: {}",
+                instances.len() - failed.len(),
+                instances.len(),
+                quorum,
+                failed.join(", ")
+            )));
+        }
+        let waited = now_millis()? - start;
+        if max_sleep > 0.0 && waited as f64 >= max_sleep as f64 * 1000.0 {
+            return Err(SLError::MaxSleepExceeded(format!(
+                "Max sleep of {} seconds exceeded without a quorum of {} of {} instances",
+                max_sleep,
+                quorum,
+                instances.len()
+            )));
+        }
+        let jitter = RandomState::new().build_hasher().finish() % QUORUM_RETRY_INTERVAL_MS;
+        tokio::time::sleep(Duration::from_millis(QUORUM_RETRY_INTERVAL_MS + jitter)).await;
+    }
+}
+
+/// Return a permit to each of the `granted` instances.
+///
+/// Failures are logged rather than raised, since the other instances should still get their permits
+/// back. Permits that couldn't be returned are freed when the semaphore expires on that instance.
+async fn release_instances(instances: &[semaphore::ThreadState], granted: &[usize]) {
+    for &i in granted {
+        let ts = instances[i].clone();
+        let name = ts.name.clone();
+        if let Err(e) = release_semaphore(&ts, 1).await {
+            warn!(
+                "Failed to return a permit to instance {} of Semaphore {}: {:?}",
+                i, name, e
+            );
+        }
+    }
+}
+
+/// Async context manager limiting concurrency like a `Semaphore`, using
+/// independent redis instances and requiring a majority of them to grant a permit.
+/// This keeps the limiter working when a minority of the instances is lost.
+#[pyclass(
+    frozen,
+    text_signature = "(name, capacity, redis_urls, max_sleep=None, expiry=30, connection_pool_size=None, \
+    connect_timeout=None, response_timeout=None, quiet=None, verify_tls=None, username=None, password=None)"
+)]
+#[pyo3(name = "QuorumSemaphore")]
+#[pyo3(module = "self_limiters")]
+pub(crate) struct QuorumSemaphore {
+    #[pyo3(get)]
+    name: String,
+    #[pyo3(get)]
+    capacity: u32,
+    #[pyo3(get)]
+    max_sleep: f32,
+    #[pyo3(get)]
+    quiet: bool,
+    #[pyo3(get)]
+    verify_tls: bool,
+    semaphores: Vec<Semaphore>,
+    /// The `QuorumHold` of each entered acquire, innermost last
+    held: PyObject,
+}
+
+/// The permits taken by an entered acquire, tracked in its context for `__aexit__`.
+#[pyclass(frozen)]
+#[pyo3(module = "self_limiters")]
+struct QuorumHold {
+    /// Indexes of the instances that granted a permit, filled in once the quorum is reached
+    granted: Arc<Mutex<Vec<usize>>>,
+    /// Set when the acquire raised or was cancelled, since `__aexit__` is then never called for it
+    failed: Arc<AtomicBool>,
+}
+
+impl QuorumSemaphore {
+    fn instances(&self) -> Vec<semaphore::ThreadState> {
+        self.semaphores.iter().map(semaphore::ThreadState::from).collect()
+    }
+
+    /// The holds of acquires entered in the current context, innermost last.
+    ///
+    /// Holds whose acquire failed are left out, so they're dropped the next time the context is updated.
+    fn entered<'p>(&self, py: Python<'p>) -> PyResult<&'p PyTuple> {
+        let entered = self
+            .held
+            .call_method1(py, "get", (PyTuple::empty(py),))?
+            .into_ref(py)
+            .downcast::<PyTuple>()?;
+        let pending: Vec<&PyAny> = entered
+            .iter()
+            .filter(|entered| {
+                entered
+                    .extract::<PyRef<QuorumHold>>()
+                    .map_or(true, |hold| !hold.failed.load(Ordering::Relaxed))
+            })
+            .collect();
+        Ok(PyTuple::new(py, pending))
+    }
+}
+
+#[pymethods]
+impl QuorumSemaphore {
+    /// Create a new class instance.
+    ///
+    /// The expiry defaults to 30 seconds. Passing `None` explicitly means the semaphores never expire.
+    #[new]
+    #[args(expiry = "30")]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        name: String,
+        capacity: u32,
+        redis_urls: Vec<String>,
+        max_sleep: Option<f32>,
+        expiry: Option<usize>,
+        connection_pool_size: Option<u32>,
+        connect_timeout: Option<f32>,
+        response_timeout: Option<f32>,
+        quiet: Option<bool>,
+        verify_tls: Option<bool>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> PyResult<Self> {
+        debug!("Creating new QuorumSemaphore instance");
+
+        if redis_urls.len() < 3 {
+            return Err(PyValueError::new_err("Quorum semaphores need at least 3 redis urls"));
+        }
+        at_most_max_capacity("Capacity", capacity.into())?;
+        check_expiry(expiry)?;
+        let max_sleep = max_sleep_seconds(max_sleep)?;
+        let connect_timeout = connect_timeout
+            .map(|t| positive_seconds("Connect timeout", t))
+            .transpose()?;
+        let response_timeout = response_timeout
+            .map(|t| positive_seconds("Response timeout", t))
+            .transpose()?;
+
+        let name = format!("{}{}", REDIS_KEY_PREFIX, name);
+        let quiet = quiet.unwrap_or(false);
+        let verify_tls = verify_tls.unwrap_or(true);
+        let credentials = Credentials { username, password };
+        let semaphores = redis_urls
+            .iter()
+            .map(|redis_url| {
+                let pool = create_connection_pool(
+                    create_connection_manager(Some(redis_url), verify_tls, &credentials)?,
+                    connection_pool_size.unwrap_or(15),
+                    false,
+                    connect_timeout,
+                )?;
+                Ok(
+                    Semaphore::with_pool(py, name.clone(), capacity, max_sleep, expiry, quiet, pool)?
+                        .with_response_timeout(response_timeout),
+                )
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+
+        // Permits are tracked per context (i.e., per asyncio task), like for `Semaphore`
+        let held = py
+            .import("contextvars")?
+            .getattr("ContextVar")?
+            .call1((format!("{}-quorum", name),))?
+            .into();
+
+        Ok(Self {
+            name,
+            capacity,
+            max_sleep,
+            quiet,
+            verify_tls,
+            semaphores,
+            held,
+        })
+    }
+
+    /// Take a permit from a majority of the redis instances, retrying until that succeeds.
+    #[pyo3(text_signature = "($self)")]
+    fn __aenter__<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        // Track the instances we get permits from in the current context, for `__aexit__`.
+        // They're filled in once the quorum is reached.
+        let hold = QuorumHold {
+            granted: Arc::new(Mutex::new(Vec::new())),
+            failed: Arc::new(AtomicBool::new(false)),
+        };
+        let (granted, failed) = (hold.granted.clone(), hold.failed.clone());
+        let entered = self.entered(py)?;
+        let mut held: Vec<PyObject> = entered.iter().map(Into::into).collect();
+        held.push(Py::new(py, hold)?.into_py(py));
+        self.held.call_method1(py, "set", (PyTuple::new(py, held),))?;
+
+        let instances = self.instances();
+        let (max_sleep, quiet, name) = (self.max_sleep, self.quiet, self.name.clone());
+        let failed = DropGuard::new(move || failed.store(true, Ordering::Relaxed));
+        future_into_py(py, async move {
+            let indexes = measured(&name, acquire_quorum(instances, max_sleep, quiet))
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            track_acquired();
+            *granted.lock().unwrap_or_else(|e| e.into_inner()) = indexes;
+            failed.defuse();
+            Ok(())
+        })
+    }
+
+    /// Return the permits to the instances that granted them.
+    ///
+    /// Instances that can't be reached are logged, and get their permit back when the semaphore expires.
+    #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
+    fn __aexit__<'p>(&self, py: Python<'p>, _a: &'p PyTuple) -> PyResult<&'p PyAny> {
+        let entered = self.entered(py)?;
+        let granted: Vec<usize> = match entered.len() {
+            // We can only get here if `__aenter__` was called from another context
+            0 => {
+                return Err(PyRuntimeError::new_err(
+                    "QuorumSemaphore was not entered in this context",
+                ))
+            }
+            n => {
+                let hold: PyRef<QuorumHold> = entered.get_item(n - 1)?.extract()?;
+                self.held.call_method1(py, "set", (entered.get_slice(0, n - 1),))?;
+                let granted = hold.granted.lock().unwrap_or_else(|e| e.into_inner());
+                granted.clone()
+            }
+        };
+        let instances = self.instances();
+        let name = self.name.clone();
+        future_into_py(py, async move {
+            if !granted.is_empty() {
+                release_instances(&instances, &granted).await;
+                track_released();
+                record_release(&name, 1);
+            }
+            Ok(())
+        })
+    }
+
+    /// The number of instances that must grant a permit for an acquire to succeed.
+    #[getter]
+    fn quorum(&self) -> usize {
+        self.semaphores.len() / 2 + 1
+    }
+
+    /// Decorate an async function, so that every call to it runs inside an `async with` block on the semaphore.
+    ///
+    /// The wrapped function's return value and exceptions are passed through as is.
+    #[pyo3(text_signature = "($self, func)")]
+    fn limit(slf: &PyCell<Self>, func: PyObject) -> PyResult<PyObject> {
+        limit(slf.py(), slf.as_ref().into(), func)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Quorum semaphore instance for queue {}", &self.name)
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use pyo3::exceptions::{PyKeyError, PyTypeError};
+use pyo3::prelude::*;
+
+use crate::composite::CompositeLimiter;
+use crate::quorum::QuorumSemaphore;
+use crate::semaphore::Semaphore;
+use crate::token_bucket::TokenBucket;
+
+/// Process-wide limiters, registered by name.
+static REGISTRY: OnceLock<Mutex<HashMap<String, PyObject>>> = OnceLock::new();
+
+fn registry() -> MutexGuard<'static, HashMap<String, PyObject>> {
+    // A poisoned lock only means another thread panicked mid-update; the map itself is still usable
+    REGISTRY
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Register a limiter under `name`, replacing any limiter already registered under it.
+#[pyfunction]
+#[pyo3(text_signature = "(name, limiter)")]
+pub(crate) fn register(name: String, limiter: &PyAny) -> PyResult<()> {
+    if !(limiter.is_instance_of::<Semaphore>()?
+        || limiter.is_instance_of::<TokenBucket>()?
+        || limiter.is_instance_of::<CompositeLimiter>()?
+        || limiter.is_instance_of::<QuorumSemaphore>()?)
+    {
+        return Err(PyTypeError::new_err(format!(
+            "Only limiters can be registered, got {}",
+            limiter.get_type().name()?
+        )));
+    }
+    // The lock is released at the end of the statement, so the replaced
+    // limiter is dropped outside of it, in case that runs arbitrary code
+    let replaced = registry().insert(name, limiter.into());
+    drop(replaced);
+    Ok(())
+}
+
+/// Return the limiter registered under `name`.
+#[pyfunction]
+#[pyo3(name = "get")]
+#[pyo3(text_signature = "(name)")]
+pub(crate) fn get_registered(py: Python<'_>, name: &str) -> PyResult<PyObject> {
+    registry()
+        .get(name)
+        .map(|limiter| limiter.clone_ref(py))
+        .ok_or_else(|| PyKeyError::new_err(format!("No limiter registered as '{}'", name)))
+}
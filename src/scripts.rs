@@ -0,0 +1,59 @@
+//! Lua scripts, bundled into the binary at compile time.
+//!
+//! The scripts live in the `scripts` directory as regular Lua files, so they can be read and debugged directly.
+
+use std::sync::OnceLock;
+
+use redis::Script;
+
+pub const SEMAPHORE_SCRIPT: &str = include_str!("../scripts/semaphore.lua");
+pub const RELEASE_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/release_semaphore.lua");
+pub const FAIR_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/fair_semaphore.lua");
+pub const WEIGHTED_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/weighted_semaphore.lua");
+pub const COUNTER_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/counter_semaphore.lua");
+pub const RELEASE_COUNTER_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/release_counter_semaphore.lua");
+pub const RECLAIM_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/reclaim_semaphore.lua");
+pub const TRANSFER_SEMAPHORE_SCRIPT: &str = include_str!("../scripts/transfer_semaphore.lua");
+pub const FENCING_TOKEN_SCRIPT: &str = include_str!("../scripts/fencing_token.lua");
+pub const TOKEN_BUCKET_SCRIPT: &str = include_str!("../scripts/token_bucket.lua");
+pub const RESIZE_TOKEN_BUCKET_SCRIPT: &str = include_str!("../scripts/resize_token_bucket.lua");
+pub const REPORT_TOKEN_BUCKET_SCRIPT: &str = include_str!("../scripts/report_token_bucket.lua");
+pub const PEEK_TOKEN_BUCKET_SCRIPT: &str = include_str!("../scripts/peek_token_bucket.lua");
+
+/// A script with its SHA1 hash computed once, on first use,
+/// rather than every time an acquire or release builds a `Script`.
+///
+/// Scripts are invoked with `EVALSHA`, so only the hash is sent per call. When redis doesn't
+/// have the script cached, e.g., after a restart or `SCRIPT FLUSH`, `invoke_async` loads it
+/// with `SCRIPT LOAD` and retries, without surfacing the `NOSCRIPT` error.
+pub(crate) struct CachedScript {
+    source: &'static str,
+    script: OnceLock<Script>,
+}
+
+impl CachedScript {
+    const fn new(source: &'static str) -> Self {
+        Self {
+            source,
+            script: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn get(&self) -> &Script {
+        self.script.get_or_init(|| Script::new(self.source))
+    }
+}
+
+pub(crate) static SEMAPHORE: CachedScript = CachedScript::new(SEMAPHORE_SCRIPT);
+pub(crate) static RELEASE_SEMAPHORE: CachedScript = CachedScript::new(RELEASE_SEMAPHORE_SCRIPT);
+pub(crate) static FAIR_SEMAPHORE: CachedScript = CachedScript::new(FAIR_SEMAPHORE_SCRIPT);
+pub(crate) static WEIGHTED_SEMAPHORE: CachedScript = CachedScript::new(WEIGHTED_SEMAPHORE_SCRIPT);
+pub(crate) static COUNTER_SEMAPHORE: CachedScript = CachedScript::new(COUNTER_SEMAPHORE_SCRIPT);
+pub(crate) static RELEASE_COUNTER_SEMAPHORE: CachedScript = CachedScript::new(RELEASE_COUNTER_SEMAPHORE_SCRIPT);
+pub(crate) static RECLAIM_SEMAPHORE: CachedScript = CachedScript::new(RECLAIM_SEMAPHORE_SCRIPT);
+pub(crate) static TRANSFER_SEMAPHORE: CachedScript = CachedScript::new(TRANSFER_SEMAPHORE_SCRIPT);
+pub(crate) static FENCING_TOKEN: CachedScript = CachedScript::new(FENCING_TOKEN_SCRIPT);
+pub(crate) static TOKEN_BUCKET: CachedScript = CachedScript::new(TOKEN_BUCKET_SCRIPT);
+pub(crate) static RESIZE_TOKEN_BUCKET: CachedScript = CachedScript::new(RESIZE_TOKEN_BUCKET_SCRIPT);
+pub(crate) static REPORT_TOKEN_BUCKET: CachedScript = CachedScript::new(REPORT_TOKEN_BUCKET_SCRIPT);
+pub(crate) static PEEK_TOKEN_BUCKET: CachedScript = CachedScript::new(PEEK_TOKEN_BUCKET_SCRIPT);
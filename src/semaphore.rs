@@ -1,33 +1,184 @@
+use std::collections::hash_map::RandomState;
+use std::future::{poll_fn, Future};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
 use bb8_redis::bb8::Pool;
 use bb8_redis::RedisConnectionManager;
-use log::{debug, info};
+use log::{debug, info, warn};
+use nanoid::nanoid;
+use pyo3::exceptions::{PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::PyTuple;
+use pyo3::types::{PyDict, PyTuple};
 use pyo3_asyncio::tokio::future_into_py;
-use redis::{AsyncCommands, Script};
+use redis::aio::Connection;
+use redis::AsyncCommands;
+use tokio::task::JoinHandle;
+
+use crate::client::RedisClient;
+use crate::errors::{map_script_error, SLError};
+use crate::metrics::{measured, measured_if, record_release};
+use crate::scripts::{
+    COUNTER_SEMAPHORE, FAIR_SEMAPHORE, FENCING_TOKEN, RECLAIM_SEMAPHORE, RELEASE_COUNTER_SEMAPHORE, RELEASE_SEMAPHORE,
+    SEMAPHORE, TRANSFER_SEMAPHORE, WEIGHTED_SEMAPHORE,
+};
+use crate::shutdown::{track_acquired, track_released};
+use crate::stats::WaitSamples;
+use crate::utils::{
+    at_most_max_capacity, block_on, check_expiry, create_connection_manager, create_connection_pool,
+    invoke_latency_callback, limit, max_sleep_seconds, now_millis, positive_seconds, prefixed_name, read_rejections,
+    record_rejection, snapshot_item, traced, with_timeout, Credentials, DropGuard, PoolCache, RetryPolicy, SLResult,
+};
 
-use crate::errors::SLError;
-use crate::generated::SEMAPHORE_SCRIPT;
-use crate::utils::{create_connection_manager, create_connection_pool, now_millis, SLResult, REDIS_KEY_PREFIX};
+/// Process-local bookkeeping of how many times a semaphore has been entered and exited.
+#[derive(Default)]
+struct Counters {
+    entered: AtomicU64,
+    exited: AtomicU64,
+}
 
-struct ThreadState {
+#[derive(Clone)]
+pub(crate) struct ThreadState {
     open_connection_pool: Pool<RedisConnectionManager>,
     return_connection_pool: Pool<RedisConnectionManager>,
-    name: String,
-    expiry: usize,
+    pub(crate) name: String,
+    expiry: Option<usize>,
     capacity: u32,
-    max_sleep: f32,
+    /// Permits taken, and released, per acquire
+    weight: u32,
+    pub(crate) max_sleep: f32,
+    /// The name acquires and releases are counted under in metrics, which for shards is the semaphore's own
+    metrics_name: String,
+    wait_callback: Option<PyObject>,
+    wait_callback_interval: usize,
+    latency_callback: Option<PyObject>,
+    fair: bool,
+    dedicated_connection: bool,
+    quiet: bool,
+    count_rejections: bool,
+    no_lua: bool,
+    counter: bool,
+    /// Hostname to record holders with, when holders are tracked
+    holder_host: Option<String>,
+    dry_run: bool,
+    fencing: bool,
+    renew_expiry: bool,
+    reclaim: bool,
+    connect_timeout: Option<Duration>,
+    response_timeout: Option<Duration>,
+    counters: Arc<Counters>,
+    wait_samples: Arc<WaitSamples>,
+    backoff: Backoff,
+    backoff_interval_ms: u64,
+    retries: RetryPolicy,
+    /// Set once the cancel event of a cancellable acquire fires, for waits to stop between commands
+    cancelled: Arc<AtomicBool>,
+    /// Id of the connection a cancellable acquire waits on, for the cancel to unblock, or 0 if it can't be cancelled
+    waiting_client: Arc<AtomicU64>,
+}
+
+/// How often waiters check whether it's their turn by default, when fairness or counter mode is enabled.
+const FAIR_POLL_INTERVAL_MS: u64 = 20;
+
+/// The longest fair waiters sleep between polls, which keeps their ticket alive.
+const MAX_FAIR_POLL_INTERVAL_MS: u64 = TICKET_TTL_MS / 2;
+
+/// How long a waiter's ticket stays valid without being refreshed, when fairness is enabled.
+const TICKET_TTL_MS: u64 = 2000;
+
+/// How often cancellable waiters check whether their cancel event has been set.
+const CANCEL_POLL_INTERVAL_MS: u64 = 20;
+
+/// How many times in a row a waiter reconnects after its connection drops mid-wait, before giving up.
+const MAX_RECONNECTS: u32 = 3;
+
+/// How long an abort stays pending for waiters that haven't woken up to it yet.
+const ABORT_TTL_SECONDS: usize = 60;
+
+/// How many connections each of a semaphore's connection pools holds by default.
+const DEFAULT_POOL_SIZE: u32 = 15;
+
+/// How long fair waiters sleep between polls, depending on how many tickets are ahead of theirs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backoff {
+    /// Poll every interval, regardless of position
+    Constant,
+    /// Poll every interval times the number of tickets ahead, plus one
+    Linear,
+    /// Poll every interval times two to the power of the number of tickets ahead
+    Exponential,
+}
+
+impl Backoff {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "constant" => Ok(Self::Constant),
+            "linear" => Ok(Self::Linear),
+            "exponential" => Ok(Self::Exponential),
+            _ => Err(PyValueError::new_err(
+                "Backoff must be one of 'constant', 'linear' or 'exponential'",
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Constant => "constant",
+            Self::Linear => "linear",
+            Self::Exponential => "exponential",
+        }
+    }
+
+    /// Milliseconds to sleep before the next poll, with `position` tickets ahead of ours.
+    ///
+    /// The sleep is capped, so that waiters far back in the queue keep their ticket alive.
+    fn delay(self, interval_ms: u64, position: u32) -> u64 {
+        let delay = match self {
+            Self::Constant => interval_ms,
+            Self::Linear => interval_ms.saturating_mul(position as u64 + 1),
+            Self::Exponential => interval_ms.saturating_mul(1 << position.min(16)),
+        };
+        delay.min(MAX_FAIR_POLL_INTERVAL_MS)
+    }
 }
 
 impl ThreadState {
-    fn from(slf: &Semaphore) -> Self {
+    pub(crate) fn from(slf: &Semaphore) -> Self {
         Self {
             open_connection_pool: slf.open_connection_pool.clone(),
             return_connection_pool: slf.return_connection_pool.clone(),
             name: slf.name.clone(),
             expiry: slf.expiry,
-            capacity: slf.capacity,
+            capacity: slf.capacity(),
+            weight: 1,
             max_sleep: slf.max_sleep,
+            metrics_name: slf.name.clone(),
+            wait_callback: slf.wait_callback.clone(),
+            wait_callback_interval: slf.wait_callback_interval,
+            latency_callback: slf.latency_callback.clone(),
+            fair: slf.fair,
+            dedicated_connection: slf.dedicated_connection,
+            quiet: slf.quiet,
+            count_rejections: slf.count_rejections,
+            no_lua: slf.no_lua,
+            counter: slf.counter,
+            holder_host: slf.holder_host.clone(),
+            dry_run: slf.dry_run,
+            fencing: slf.fencing,
+            renew_expiry: slf.renew_expiry,
+            reclaim: slf.reclaim,
+            connect_timeout: slf.connect_timeout,
+            response_timeout: slf.response_timeout,
+            counters: slf.counters.clone(),
+            wait_samples: slf.wait_samples.clone(),
+            backoff: slf.backoff,
+            backoff_interval_ms: slf.backoff_interval_ms,
+            retries: slf.retries,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            waiting_client: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -35,120 +186,2238 @@ impl ThreadState {
     fn exists_key(&self) -> String {
         format!("{}-exists", self.name)
     }
+
+    /// List of pending aborts, which waiters block on alongside the semaphore itself
+    fn abort_key(&self) -> String {
+        format!("{}-abort", self.name)
+    }
+
+    /// Counter of aborts so far, so waiters can tell aborts issued while they wait from earlier ones
+    fn abort_generation_key(&self) -> String {
+        format!("{}-abort-generation", self.name)
+    }
+
+    /// State for shard `index` of a semaphore split into `shards`, holding its share of the capacity.
+    ///
+    /// The capacity is spread as evenly as possible, with lower shards taking the remainder.
+    fn shard(&self, index: u32, shards: u32) -> Self {
+        Self {
+            name: format!("{}-{}", self.name, index),
+            capacity: self.capacity / shards + u32::from(index < self.capacity % shards),
+            // Holders and leases are recorded on the semaphore itself, which sharded acquires don't touch
+            holder_host: None,
+            reclaim: false,
+            ..self.clone()
+        }
+    }
+
+    /// State for dry run acquires, which take permits from `{name}-dry-run` rather than the semaphore
+    /// itself, so that whether an acquire would have waited reflects the load of the dry runs.
+    fn dry_run_shadow(&self) -> Self {
+        Self {
+            name: format!("{}-dry-run", self.name),
+            // Dry runs only take and return permits, and leave holders, leases and tokens alone
+            holder_host: None,
+            fencing: false,
+            renew_expiry: false,
+            reclaim: false,
+            ..self.clone()
+        }
+    }
+
+    /// Count `releases` releases in the metrics, matching acquires measured under the same name.
+    pub(crate) fn record_release(&self, releases: u64) {
+        record_release(&self.metrics_name, releases);
+    }
+
+    /// Counter handing out fencing tokens. It expires with the semaphore, and restarts from the current time.
+    fn fence_key(&self) -> String {
+        format!("{}-fence", self.name)
+    }
+
+    /// Hash of who holds permits, by holder id, when holders are tracked
+    fn holders_key(&self) -> String {
+        format!("{}-holders", self.name)
+    }
+
+    /// Sorted set of leases on permits, scored by when they were last renewed, when reclaiming is enabled
+    fn leases_key(&self) -> String {
+        format!("{}-leases", self.name)
+    }
+
+    /// Member of the leases set for the permits held by `id`, which records how many were taken
+    fn lease(&self, id: &str) -> String {
+        format!("{}:{}", self.weight, id)
+    }
+
+    /// Key of the ticket queue used when fairness is enabled
+    fn queue_key(&self) -> String {
+        format!("{}-queue", self.name)
+    }
+
+    /// Raise if the acquire was cancelled, which waits check before each command they send.
+    fn check_cancelled(&self) -> SLResult<()> {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return Err(SLError::Aborted(format!(
+                "Cancelled while waiting for Semaphore {}",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+
+    fn max_sleep_exceeded(&self, waited: u64) -> bool {
+        self.max_sleep > 0.0 && waited > (self.max_sleep * 1000.0) as u64
+    }
+
+    /// The number of seconds to block for in the next `blpop` call, where 0 means forever.
+    ///
+    /// We block for at most the remaining `max_sleep` budget, and
+    /// wake up every interval to invoke the wait callback, if one is set.
+    /// We also wake up at least once per expiry, since the semaphore
+    /// might have expired and need to be recreated while we wait.
+    fn blpop_timeout(&self, waited: u64) -> usize {
+        let mut timeout = 0;
+        if self.max_sleep > 0.0 {
+            let remaining = ((self.max_sleep * 1000.0) as u64).saturating_sub(waited);
+            timeout = (remaining as f64 / 1000.0).ceil().max(1.0) as usize;
+        }
+        if self.wait_callback.is_some() && (timeout == 0 || timeout > self.wait_callback_interval) {
+            timeout = self.wait_callback_interval;
+        }
+        if let Some(expiry) = self.expiry {
+            if timeout == 0 || timeout > expiry {
+                timeout = expiry;
+            }
+        }
+        timeout
+    }
+
+    /// Invoke the wait callback with the time waited so far, in seconds.
+    ///
+    /// Exceptions raised by the callback are logged and swallowed,
+    /// so they never abort the acquire.
+    fn invoke_wait_callback(&self, waited: u64) {
+        if let Some(callback) = &self.wait_callback {
+            Python::with_gil(|py| {
+                if let Err(e) = callback.call1(py, (waited as f64 / 1000.0, py.None())) {
+                    debug!("Wait callback raised an exception: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Acquire the semaphore. Returns the number of milliseconds waited, and the
+/// number of tickets ahead of ours on arrival, when fairness is enabled.
+///
+/// When a `cancel` event is passed, we stop waiting and raise once it's set.
+pub(crate) async fn create_and_acquire_semaphore(
+    ts: ThreadState,
+    id: &str,
+    cancel: Option<PyObject>,
+) -> SLResult<(u64, Option<u32>)> {
+    let name = ts.name.clone();
+    measured(&name, traced("semaphore", &name, acquire_semaphore(ts, id, cancel))).await
 }
 
-async fn create_and_acquire_semaphore(ts: ThreadState) -> SLResult<()> {
+/// Acquire the semaphore, as described for `create_and_acquire_semaphore`, without a span.
+async fn acquire_semaphore(ts: ThreadState, id: &str, cancel: Option<PyObject>) -> SLResult<(u64, Option<u32>)> {
+    let start = now_millis()?;
+    // Only time calls to redis when someone's listening
+    let timer = ts.latency_callback.as_ref().map(|_| Instant::now());
+
     // Connect to redis
-    let mut connection = ts.open_connection_pool.get().await?;
+    let mut connection = ts.retries.get(&ts.open_connection_pool).await?;
+    let connected = now_millis()?;
 
     // Define queue if it doesn't already exist
-    if Script::new(SEMAPHORE_SCRIPT)
+    create_semaphore(&ts, &mut *connection).await?;
+    if ts.reclaim {
+        reclaim_permits(&ts, &mut *connection).await?;
+    }
+    let created = now_millis()?;
+    // Waiting for our turn is left out, since it's mostly waiting for other holders
+    let latency = timer.map(|timer| timer.elapsed());
+
+    // Wait for our turn
+    let mut queued = created;
+    let result = if ts.dedicated_connection || cancel.is_some() {
+        // Return the pooled connection before we start blocking, so it stays free for other work
+        drop(connection);
+        let mut connection = with_timeout(ts.connect_timeout, ts.open_connection_pool.dedicated_connection()).await?;
+        queued = now_millis()?;
+        match cancel {
+            Some(cancel) => wait_unless_cancelled(&ts, &mut connection, id, cancel).await,
+            None => wait_for_turn(&ts, &mut connection, id).await,
+        }
+    } else {
+        wait_for_turn(&ts, &mut *connection, id).await
+    };
+    if let Err(SLError::MaxSleepExceeded(e)) = result {
+        if ts.count_rejections {
+            record_rejection(&ts.open_connection_pool, &ts.name).await;
+        }
+        // Break down where the time went, to tell a long queue from a slow connection
+        return Err(SLError::MaxSleepExceeded(format!(
+            "{}, after {:.3}s connecting to redis, {:.3}s creating the semaphore, and {:.3}s waiting in the queue",
+            e,
+            ((connected - start) + (queued - created)) as f64 / 1000.0,
+            (created - connected) as f64 / 1000.0,
+            (now_millis()? - queued) as f64 / 1000.0,
+        )));
+    }
+    let position = result?;
+    count_entered(&ts);
+    track_acquired();
+    if let (Some(callback), Some(latency)) = (&ts.latency_callback, latency) {
+        invoke_latency_callback(callback, latency);
+    }
+
+    if !ts.quiet {
+        debug!("Acquired semaphore as {}", id);
+    }
+    let waited = now_millis()? - start;
+    ts.wait_samples.record(waited);
+    Ok((waited, position))
+}
+
+/// Acquire a permit from the first of `shards` semaphores with one free, without waiting.
+///
+/// Shards are tried starting from a random one, to spread load across keys.
+/// Returns the state of the shard the permit was taken from, for releasing it.
+pub(crate) async fn acquire_sharded(ts: ThreadState, shards: u32) -> SLResult<(ThreadState, u64)> {
+    let start = now_millis()?;
+    let mut connection = ts.open_connection_pool.get().await?;
+    let offset = (RandomState::new().build_hasher().finish() % shards as u64) as u32;
+    for i in 0..shards {
+        let shard = ts.shard((offset + i) % shards, shards);
+        create_semaphore(&shard, &mut *connection).await?;
+        let permit: Option<String> = with_timeout(ts.response_timeout, connection.lpop(&shard.name, None)).await?;
+        if permit.is_some() {
+            count_entered(&ts);
+            track_acquired();
+            if !ts.quiet {
+                debug!("Acquired permit from shard {}", shard.name);
+            }
+            let waited = now_millis()? - start;
+            ts.wait_samples.record(waited);
+            return Ok((shard, waited));
+        }
+    }
+    if ts.count_rejections {
+        record_rejection(&ts.open_connection_pool, &ts.name).await;
+    }
+    Err(SLError::MaxSleepExceeded(format!(
+        "No permits available in any of the {} shards of Semaphore",
+        shards
+    )))
+}
+
+/// Take permits from the dry run semaphore if they're free, without waiting, and log what
+/// an acquire would have done otherwise. Returns whether we got them.
+///
+/// `ts` is the state returned by `dry_run_shadow`.
+async fn acquire_dry_run(ts: &ThreadState) -> SLResult<bool> {
+    if try_acquire_semaphore(ts).await? {
+        count_entered(ts);
+        track_acquired();
+        return Ok(true);
+    }
+    if ts.max_sleep > 0.0 {
+        info!(
+            "Dry run: Semaphore {} has no free permits, so an acquire would have waited, \
+            and been rejected unless a permit was released within the max sleep of {} seconds",
+            ts.metrics_name, ts.max_sleep
+        );
+    } else {
+        info!(
+            "Dry run: Semaphore {} has no free permits, so an acquire would have waited for one",
+            ts.metrics_name
+        );
+    }
+    Ok(false)
+}
+
+/// Take the acquire's permits if they're free, without waiting, creating the semaphore if needed.
+/// Returns whether we got them.
+pub(crate) async fn try_acquire_semaphore(ts: &ThreadState) -> SLResult<bool> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    if ts.counter || ts.weight > 1 {
+        let (acquired, _) = take_permits(ts, &mut *connection).await?;
+        return Ok(acquired);
+    }
+    create_semaphore(ts, &mut *connection).await?;
+    let permit: Option<String> = with_timeout(ts.response_timeout, connection.lpop(&ts.name, None)).await?;
+    Ok(permit.is_some())
+}
+
+/// Count a successful acquire, warning if we're holding more permits than there
+/// is capacity, since that suggests some acquisitions are never released.
+fn count_entered(ts: &ThreadState) {
+    let entered = ts.counters.entered.fetch_add(1, Ordering::Relaxed) + 1;
+    let held = entered.saturating_sub(ts.counters.exited.load(Ordering::Relaxed));
+    if held > ts.capacity as u64 {
+        warn!(
+            "Semaphore {} has been entered {} times, but only exited {} times. \
+            This exceeds the capacity of {}, and suggests acquisitions are not being released",
+            ts.name,
+            entered,
+            entered - held,
+            ts.capacity
+        );
+    }
+}
+
+/// Create the semaphore queue, unless it exists already.
+///
+/// Counters are created by the script that takes permits from them, so there's nothing to do for those.
+async fn create_semaphore(ts: &ThreadState, connection: &mut Connection) -> SLResult<()> {
+    if ts.counter {
+        return Ok(());
+    }
+    let created = if ts.no_lua {
+        create_semaphore_without_scripts(ts, connection).await?
+    } else {
+        let mut invocation = SEMAPHORE.get().prepare_invoke();
+        invocation.key(&ts.name).key(&ts.exists_key()).arg(ts.capacity);
+        with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+            .await
+            .map_err(|e| map_script_error(e, "semaphore"))?
+    };
+    if created {
+        if !ts.quiet {
+            info!("Created new semaphore queue with a capacity of {}", &ts.capacity);
+        }
+    } else if !ts.quiet {
+        debug!("Skipped creating new semaphore queue, since one exists already")
+    }
+    Ok(())
+}
+
+/// Release the permits of holders whose lease is older than the expiry, since they must have died without releasing.
+async fn reclaim_permits(ts: &ThreadState, connection: &mut Connection) -> SLResult<()> {
+    let expiry = ts.expiry.unwrap_or_default();
+    let mut invocation = RECLAIM_SEMAPHORE.get().prepare_invoke();
+    invocation
         .key(&ts.name)
         .key(&ts.exists_key())
+        .key(&ts.leases_key())
         .arg(ts.capacity)
-        .invoke_async(&mut *connection)
-        .await?
-    {
-        info!("Created new semaphore queue with a capacity of {}", &ts.capacity);
+        .arg(now_millis()?.saturating_sub(expiry as u64 * 1000))
+        .arg(u8::from(ts.counter))
+        .arg(expiry);
+    let reclaimed: u32 = with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+        .await
+        .map_err(|e| map_script_error(e, "reclaim_semaphore"))?;
+    if reclaimed > 0 && !ts.quiet {
+        info!(
+            "Reclaimed {} permits of Semaphore {} from holders that didn't release",
+            reclaimed, ts.name
+        );
+    }
+    Ok(())
+}
+
+/// Create the semaphore queue with plain commands, for servers where scripting is disabled.
+/// Returns whether the semaphore was created.
+///
+/// Unlike the create script, claiming the exists key and filling the queue are two round trips.
+/// The exists key is given the semaphore's expiry, so a client dying in between leaves
+/// an empty semaphore only until it expires.
+async fn create_semaphore_without_scripts(ts: &ThreadState, connection: &mut Connection) -> SLResult<bool> {
+    let mut claim = redis::cmd("SET");
+    claim.arg(ts.exists_key()).arg(ts.capacity).arg("NX");
+    if let Some(expiry) = ts.expiry {
+        claim.arg("EX").arg(expiry);
+    }
+    let claimed: Option<String> = with_timeout(ts.response_timeout, claim.query_async(connection)).await?;
+    if claimed.is_none() {
+        return Ok(false);
+    }
+
+    // Drop any permits released into the queue after the semaphore expired, before refilling it
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(&ts.name).ignore();
+    push_permits(&mut pipe, &ts.name, ts.capacity);
+    if let Some(expiry) = ts.expiry {
+        pipe.expire(&ts.name, expiry).ignore();
+    }
+    with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(connection)).await?;
+    Ok(true)
+}
+
+/// Add commands pushing `permits` permits to the queue, in batches to keep individual commands reasonably small.
+fn push_permits(pipe: &mut redis::Pipeline, key: &str, permits: u32) {
+    let mut remaining = permits;
+    while remaining > 0 {
+        let batch = remaining.min(1000);
+        pipe.rpush(key, vec![1; batch as usize]).ignore();
+        remaining -= batch;
+    }
+}
+
+/// Wait for our turn. Returns the number of tickets ahead of ours on arrival, when fairness is enabled.
+async fn wait_for_turn(ts: &ThreadState, connection: &mut Connection, id: &str) -> SLResult<Option<u32>> {
+    if ts.fair {
+        Ok(Some(wait_for_ticket(ts, connection, id).await?))
+    } else if ts.counter || ts.weight > 1 {
+        poll_for_permits(ts, connection).await.map(|_| None)
     } else {
-        debug!("Skipped creating new semaphore queue, since one exists already")
+        wait_for_permit(ts, connection).await.map(|_| None)
+    }
+}
+
+/// Wait for our turn, unless the `cancel` event is set first, in which case we give up our place and raise.
+///
+/// The wait is never dropped mid-command, since a `blpop` or script call in flight may already
+/// have taken a permit. Instead, cancelling flags the wait to stop before its next command, and
+/// unblocks the connection if it's in a `blpop`. Permits taken by the last command are put back.
+async fn wait_unless_cancelled(
+    ts: &ThreadState,
+    connection: &mut Connection,
+    id: &str,
+    cancel: PyObject,
+) -> SLResult<Option<u32>> {
+    ts.waiting_client
+        .store(client_id(ts, connection).await?, Ordering::Relaxed);
+    let mut event_error = None;
+    let result = {
+        let mut wait = Box::pin(wait_for_turn(ts, connection, id));
+        let mut watch = Box::pin(async {
+            if let Err(e) = wait_for_event(cancel).await {
+                event_error = Some(e);
+            }
+            ts.cancelled.store(true, Ordering::Relaxed);
+            // The wait might be between commands when we unblock it, and block right after, so
+            // we keep unblocking it until it returns, at which point this is no longer polled
+            loop {
+                unblock_client(ts, ts.waiting_client.load(Ordering::Relaxed)).await;
+                tokio::time::sleep(Duration::from_millis(CANCEL_POLL_INTERVAL_MS)).await;
+            }
+        });
+        poll_fn(|cx| {
+            if let Poll::Ready(result) = wait.as_mut().poll(cx) {
+                return Poll::Ready(result);
+            }
+            let _ = watch.as_mut().poll(cx);
+            Poll::Pending
+        })
+        .await
+    };
+    if !ts.cancelled.load(Ordering::Relaxed) {
+        return result;
+    }
+    if result.is_ok() {
+        // The permits arrived after we were cancelled, so we pass them on to the next waiter
+        let mut connection = ts.open_connection_pool.get().await?;
+        return_permits(ts, &mut *connection, ts.weight).await?;
+    }
+    if let Some(e) = event_error {
+        return Err(e);
+    }
+    // Raise the cancel, unless the wait failed for another reason first
+    result?;
+    ts.check_cancelled().map(|_| None)
+}
+
+/// The id redis knows `connection` by, which other connections can refer to it with.
+async fn client_id(ts: &ThreadState, connection: &mut Connection) -> SLResult<u64> {
+    Ok(with_timeout(
+        ts.response_timeout,
+        redis::cmd("CLIENT").arg("ID").query_async(connection),
+    )
+    .await?)
+}
+
+/// Unblock the client with `client_id` if it's in a blocking command, which then returns as if it timed out.
+///
+/// Failing to unblock it only delays the cancel until its `blpop` times out, so errors are logged.
+async fn unblock_client(ts: &ThreadState, client_id: u64) {
+    let result: SLResult<()> = async {
+        let mut connection = ts.open_connection_pool.get().await?;
+        with_timeout(
+            ts.response_timeout,
+            redis::cmd("CLIENT")
+                .arg("UNBLOCK")
+                .arg(client_id)
+                .query_async::<_, ()>(&mut *connection),
+        )
+        .await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!(
+            "Failed to unblock a cancelled waiter for Semaphore {}: {:?}",
+            ts.name, e
+        );
+    }
+}
+
+/// Wait until `event` is set, by polling its `is_set` method.
+async fn wait_for_event(event: PyObject) -> SLResult<()> {
+    loop {
+        let set = Python::with_gil(|py| event.call_method0(py, "is_set")?.is_true(py))
+            .map_err(|e| SLError::RuntimeError(format!("Failed to check the cancel event: {}", e)))?;
+        if set {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(CANCEL_POLL_INTERVAL_MS)).await;
+    }
+}
+
+/// Wait for a permit using `blpop`. This waits non-blockingly until we're free to proceed.
+async fn wait_for_permit(ts: &ThreadState, connection: &mut Connection) -> SLResult<()> {
+    wait_for_any_permit(std::slice::from_ref(ts), connection).await?;
+    Ok(())
+}
+
+/// Wait for a permit from whichever of `semaphores` frees up first, using a single `blpop` on all their queues.
+///
+/// Returns the index of the semaphore the permit was taken from, which is the one to release it to.
+/// Waiting is governed by the settings of the first semaphore, such as its `max_sleep`.
+///
+/// If the connection drops while we're blocking, we open a new one and keep waiting within
+/// the remaining `max_sleep`, rather than fail the acquire.
+///
+/// We also block on each semaphore's abort list, and raise if an abort is issued while we wait.
+async fn wait_for_any_permit(semaphores: &[ThreadState], connection: &mut Connection) -> SLResult<usize> {
+    let ts = &semaphores[0];
+    let abort_keys: Vec<String> = semaphores.iter().map(ThreadState::abort_key).collect();
+    let mut keys: Vec<&str> = semaphores.iter().map(|semaphore| semaphore.name.as_str()).collect();
+    keys.extend(abort_keys.iter().map(String::as_str));
+    let generations = read_abort_generations(semaphores, connection).await?;
+    let start = now_millis()?;
+    let mut reconnected: Option<Connection> = None;
+    let mut reconnects = 0;
+    loop {
+        ts.check_cancelled()?;
+        let connection = match reconnected.as_mut() {
+            Some(reconnected) => reconnected,
+            None => &mut *connection,
+        };
+        let timeout = ts.blpop_timeout(now_millis()? - start);
+        let permit: Option<(String, String)> = match connection.blpop(&keys, timeout).await {
+            Ok(permit) => {
+                reconnects = 0;
+                permit
+            }
+            Err(e) if (e.is_connection_dropped() || e.is_io_error()) && reconnects < MAX_RECONNECTS => {
+                if ts.max_sleep_exceeded(now_millis()? - start) {
+                    return Err(e.into());
+                }
+                warn!(
+                    "Lost connection while waiting for Semaphore {}, reconnecting: {}",
+                    ts.name, e
+                );
+                reconnects += 1;
+                let mut connection =
+                    with_timeout(ts.connect_timeout, ts.open_connection_pool.dedicated_connection()).await?;
+                // A cancel has to unblock the new connection from now on
+                if ts.waiting_client.load(Ordering::Relaxed) != 0 {
+                    ts.waiting_client
+                        .store(client_id(ts, &mut connection).await?, Ordering::Relaxed);
+                }
+                reconnected = Some(connection);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // Check for a permit before the max sleep, so we never drop a permit we've already taken
+        if let Some((key, value)) = permit {
+            if let Some(index) = abort_keys.iter().position(|abort_key| *abort_key == key) {
+                // Aborts issued before we started waiting are stale, and are dropped
+                if value.parse::<u64>().unwrap_or_default() > generations[index] {
+                    // Pass the abort on to the next waiter before raising
+                    with_timeout(ts.response_timeout, connection.lpush::<_, _, ()>(&key, &value)).await?;
+                    return Err(SLError::Aborted(format!(
+                        "Aborted while waiting for Semaphore {}",
+                        semaphores[index].name
+                    )));
+                }
+                continue;
+            }
+            return semaphores
+                .iter()
+                .position(|semaphore| semaphore.name == key)
+                .ok_or_else(|| SLError::Redis(format!("Received a permit from an unexpected key {}", key)));
+        }
+
+        // Raise an exception if we waited too long
+        let waited = now_millis()? - start;
+        if ts.max_sleep_exceeded(waited) {
+            return Err(SLError::MaxSleepExceeded(
+                "Max sleep exceeded waiting for Semaphore".to_string(),
+            ));
+        };
+
+        // The semaphores might have expired between us creating them and calling
+        // `blpop`, in which case we'd otherwise wait for lists that will never
+        // be recreated. Re-running the create script is a no-op otherwise.
+        for semaphore in semaphores {
+            create_semaphore(semaphore, connection).await?;
+        }
+        ts.invoke_wait_callback(waited);
     }
+}
+
+/// Read the number of aborts issued so far for each of `semaphores`.
+async fn read_abort_generations(semaphores: &[ThreadState], connection: &mut Connection) -> SLResult<Vec<u64>> {
+    let mut pipe = redis::pipe();
+    for semaphore in semaphores {
+        pipe.get(semaphore.abort_generation_key());
+    }
+    let generations: Vec<Option<u64>> =
+        with_timeout(semaphores[0].response_timeout, pipe.query_async(connection)).await?;
+    Ok(generations.into_iter().map(Option::unwrap_or_default).collect())
+}
 
-    // Wait for our turn - this waits non-blockingly until we're free to proceed
+/// Wait for a permit by queueing up with a ticket, to guarantee arrival-order fairness.
+///
+/// Since only the head of the ticket queue may pop a permit, we can't
+/// use `blpop` here, and instead poll until it's our turn, backing off
+/// according to how many tickets are ahead of ours.
+async fn wait_for_ticket(ts: &ThreadState, connection: &mut Connection, ticket: &str) -> SLResult<u32> {
+    let generation = read_abort_generations(std::slice::from_ref(ts), connection).await?[0];
     let start = now_millis()?;
-    connection.blpop(&ts.name, ts.max_sleep as usize).await?;
+    let mut next_callback = ts.wait_callback_interval as u64 * 1000;
+    let mut first_attempt = true;
+    let mut arrival_position = 0;
+    loop {
+        if let Err(e) = ts.check_cancelled() {
+            remove_ticket(ts, connection, ticket).await?;
+            return Err(e);
+        }
+        let mut invocation = FAIR_SEMAPHORE.get().prepare_invoke();
+        invocation
+            .key(&ts.name)
+            .key(&ts.queue_key())
+            .arg(ticket)
+            .arg(TICKET_TTL_MS)
+            .arg(first_attempt as u8);
+        let (acquired, position): (bool, u32) = with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+            .await
+            .map_err(|e| map_script_error(e, "fair_semaphore"))?;
+        if first_attempt {
+            // Taking a permit right away means there were no tickets ahead of ours
+            if !acquired {
+                arrival_position = position;
+            }
+            #[cfg(feature = "tracing")]
+            tracing::Span::current().record("position", arrival_position);
+        }
+        if acquired {
+            return Ok(arrival_position);
+        }
+        first_attempt = false;
 
-    // Raise an exception if we waited too long
-    if ts.max_sleep > 0.0 && (now_millis()? - start) > (ts.max_sleep * 1000.0) as u64 {
-        return Err(SLError::MaxSleepExceeded(
-            "Max sleep exceeded waiting for Semaphore".to_string(),
-        ));
+        // Give up our place in the queue and raise if we waited too long, or were aborted
+        let waited = now_millis()? - start;
+        let aborted = read_abort_generations(std::slice::from_ref(ts), connection).await?[0] > generation;
+        if aborted || ts.max_sleep_exceeded(waited) {
+            remove_ticket(ts, connection, ticket).await?;
+            if aborted {
+                return Err(SLError::Aborted(format!(
+                    "Aborted while waiting for Semaphore {}",
+                    ts.name
+                )));
+            }
+            return Err(SLError::MaxSleepExceeded(
+                "Max sleep exceeded waiting for Semaphore".to_string(),
+            ));
+        }
+
+        if waited >= next_callback {
+            ts.invoke_wait_callback(waited);
+            next_callback += ts.wait_callback_interval as u64 * 1000;
+        }
+        tokio::time::sleep(Duration::from_millis(
+            ts.backoff.delay(ts.backoff_interval_ms, position),
+        ))
+        .await;
+    }
+}
+
+/// Record that `id` holds a permit, with our host and pid, when holders are tracked.
+///
+/// The record is only for debugging, so failing to write it is logged rather than failing the acquire.
+async fn record_holder(ts: &ThreadState, id: &str) {
+    let host = match &ts.holder_host {
+        Some(host) => host,
+        None => return,
+    };
+    let result: SLResult<()> = async {
+        let mut connection = ts.open_connection_pool.get().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .hset(
+                ts.holders_key(),
+                id,
+                format!("{} {} {}", std::process::id(), now_millis()?, host),
+            )
+            .ignore();
+        if let Some(expiry) = ts.expiry {
+            pipe.expire(ts.holders_key(), expiry).ignore();
+        }
+        with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(&mut *connection)).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to record holder {} of Semaphore {}: {:?}", id, ts.name, e);
+    }
+}
+
+/// Lease the permits held by `id`, when reclaiming is enabled, so they can be reclaimed if we die without releasing.
+///
+/// Like holder records, failing to write the lease is logged rather than failing the acquire.
+async fn record_lease(ts: &ThreadState, id: &str) {
+    let expiry = match ts.expiry.filter(|_| ts.reclaim) {
+        Some(expiry) => expiry,
+        None => return,
     };
+    let result: SLResult<()> = async {
+        let mut connection = ts.open_connection_pool.get().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .zadd(ts.leases_key(), ts.lease(id), now_millis()?)
+            .ignore()
+            .expire(ts.leases_key(), expiry)
+            .ignore();
+        with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(&mut *connection)).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to record the lease of {} on Semaphore {}: {:?}", id, ts.name, e);
+    }
+}
+
+/// Remove the lease on the permits held by `id`, when reclaiming is enabled.
+async fn remove_lease(ts: &ThreadState, id: &str) {
+    if !ts.reclaim {
+        return;
+    }
+    let result: SLResult<()> = async {
+        let mut connection = ts.return_connection_pool.get().await?;
+        with_timeout(
+            ts.response_timeout,
+            connection.zrem::<_, _, ()>(ts.leases_key(), ts.lease(id)),
+        )
+        .await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to remove the lease of {} on Semaphore {}: {:?}", id, ts.name, e);
+    }
+}
+
+/// Hand out the next fencing token for the semaphore, which is greater than any handed out before.
+///
+/// The token is taken after the permit, so it's greater than the token of any holder whose permit
+/// was released, or expired, before this one was taken. Holders acquiring concurrently can get their
+/// tokens in either order.
+async fn next_fencing_token(ts: &ThreadState) -> SLResult<u64> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let mut invocation = FENCING_TOKEN.get().prepare_invoke();
+    invocation.key(ts.fence_key()).arg(ts.expiry.unwrap_or(0)); // 0 means the key never expires
+    with_timeout(ts.response_timeout, invocation.invoke_async(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "fencing_token"))
+}
 
-    debug!("Acquired semaphore");
+/// Start renewing the semaphore's expiry in the background, while a permit is held, when enabled.
+///
+/// Without renewal, a semaphore held for longer than its expiry vanishes, and is recreated at
+/// full capacity by the next acquire. The returned task must be aborted once the permit is released.
+fn spawn_expiry_renewal(ts: &ThreadState, id: &str) -> Option<JoinHandle<()>> {
+    let expiry = ts.expiry.filter(|_| ts.renew_expiry)?;
+    let (ts, id) = (ts.clone(), id.to_string());
+    Some(pyo3_asyncio::tokio::get_runtime().spawn(async move {
+        // Renew at half the expiry, so a slow renewal doesn't let the keys lapse
+        let interval = Duration::from_millis(expiry as u64 * 500);
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = renew_expiry(&ts, &id, expiry).await {
+                warn!("Failed to renew the expiry of Semaphore {}: {:?}", ts.name, e);
+            }
+        }
+    }))
+}
+
+/// Reset the expiry of the semaphore's keys, and of its holders when they are tracked.
+///
+/// The lease of `id` is renewed too, when reclaiming is enabled, so its permits aren't reclaimed while held.
+/// Leases are only updated, never added, in case the permits were released while we renewed.
+async fn renew_expiry(ts: &ThreadState, id: &str, expiry: usize) -> SLResult<()> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let mut pipe = redis::pipe();
+    pipe.expire(&ts.name, expiry)
+        .ignore()
+        .expire(ts.exists_key(), expiry)
+        .ignore();
+    if ts.holder_host.is_some() {
+        pipe.expire(ts.holders_key(), expiry).ignore();
+    }
+    if ts.reclaim {
+        pipe.cmd("ZADD")
+            .arg(ts.leases_key())
+            .arg("XX")
+            .arg(now_millis()?)
+            .arg(ts.lease(id))
+            .ignore()
+            .expire(ts.leases_key(), expiry)
+            .ignore();
+    }
+    with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(&mut *connection)).await?;
+    debug!("Renewed the expiry of Semaphore {}", ts.name);
     Ok(())
 }
 
-async fn release_semaphore(ts: ThreadState) -> SLResult<()> {
+/// Remove the record of `id` holding a permit, when holders are tracked.
+async fn remove_holder(ts: &ThreadState, id: &str) {
+    if ts.holder_host.is_none() {
+        return;
+    }
+    let result: SLResult<()> = async {
+        let mut connection = ts.return_connection_pool.get().await?;
+        with_timeout(ts.response_timeout, connection.hdel::<_, _, ()>(ts.holders_key(), id)).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        warn!("Failed to remove holder {} of Semaphore {}: {:?}", id, ts.name, e);
+    }
+}
+
+/// Read who holds permits, as each holder's id, host, pid and the millisecond timestamp it acquired at.
+async fn read_holders(ts: ThreadState) -> SLResult<Vec<(String, String, u32, u64)>> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let records: Vec<(String, String)> =
+        with_timeout(ts.response_timeout, connection.hgetall(ts.holders_key())).await?;
+    let mut holders = records
+        .into_iter()
+        .map(|(id, record)| {
+            let mut parts = record.splitn(3, ' ');
+            let pid = parts.next().and_then(|pid| pid.parse().ok());
+            let since = parts.next().and_then(|since| since.parse().ok());
+            match (pid, since, parts.next()) {
+                (Some(pid), Some(since), Some(host)) => Ok((id, host.to_string(), pid, since)),
+                _ => Err(SLError::Redis(format!("Failed to parse holder record '{}'", record))),
+            }
+        })
+        .collect::<SLResult<Vec<_>>>()?;
+    holders.sort_by_key(|(_, _, _, since)| *since);
+    Ok(holders)
+}
+
+/// Give up our place in the ticket queue, when fairness is enabled.
+async fn remove_ticket(ts: &ThreadState, connection: &mut Connection, ticket: &str) -> SLResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.lrem(&ts.queue_key(), 1, ticket)
+        .del(format!("{}:{}", ts.queue_key(), ticket));
+    with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(connection)).await?;
+    Ok(())
+}
+
+/// Wait for permits by polling a script that takes them all at once, when the semaphore is stored as
+/// a counter, or when we need more than one permit.
+///
+/// There's no list to block on, so waiters poll at a constant interval, and whoever
+/// polls first after a release gets the permits. The scripts also return the number of
+/// aborts issued, which saves a round trip per poll.
+async fn poll_for_permits(ts: &ThreadState, connection: &mut Connection) -> SLResult<()> {
+    let start = now_millis()?;
+    let mut next_callback = ts.wait_callback_interval as u64 * 1000;
+    let mut first_generation = None;
+    loop {
+        ts.check_cancelled()?;
+        let (acquired, generation) = take_permits(ts, connection).await?;
+        if acquired {
+            return Ok(());
+        }
+
+        // Aborts issued before our first poll are stale
+        if generation > *first_generation.get_or_insert(generation) {
+            return Err(SLError::Aborted(format!(
+                "Aborted while waiting for Semaphore {}",
+                ts.name
+            )));
+        }
+
+        let waited = now_millis()? - start;
+        if ts.max_sleep_exceeded(waited) {
+            return Err(SLError::MaxSleepExceeded(
+                "Max sleep exceeded waiting for Semaphore".to_string(),
+            ));
+        }
+
+        if waited >= next_callback {
+            ts.invoke_wait_callback(waited);
+            next_callback += ts.wait_callback_interval as u64 * 1000;
+        }
+        tokio::time::sleep(Duration::from_millis(ts.backoff.delay(ts.backoff_interval_ms, 0))).await;
+    }
+}
+
+/// Take the acquire's permits with a single script call, if that many are free.
+/// Returns whether we got them, and the number of aborts issued so far.
+async fn take_permits(ts: &ThreadState, connection: &mut Connection) -> SLResult<(bool, u64)> {
+    // Both scripts take the same keys and args, and differ in how the permits are stored
+    let (script, script_name) = if ts.counter {
+        (&COUNTER_SEMAPHORE, "counter_semaphore")
+    } else {
+        (&WEIGHTED_SEMAPHORE, "weighted_semaphore")
+    };
+    let mut invocation = script.get().prepare_invoke();
+    invocation
+        .key(&ts.name)
+        .key(&ts.exists_key())
+        .key(&ts.abort_generation_key())
+        .arg(ts.capacity)
+        .arg(ts.weight);
+    with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+        .await
+        .map_err(|e| map_script_error(e, script_name))
+}
+
+/// Push permits back to the semaphore. Returns the number of permits released,
+/// which is lower than `permits` if releasing all would exceed the capacity.
+///
+/// Callers count the release in the metrics, since only they know what it matches.
+pub(crate) async fn release_semaphore(ts: &ThreadState, permits: u32) -> SLResult<u32> {
     // Connect to redis
     let mut connection = ts.return_connection_pool.get().await?;
 
     // Push capacity back to the semaphore
-    // We don't care about this being atomic
-    redis::pipe()
-        .lpush(&ts.name, 1)
-        .expire(&ts.name, ts.expiry)
-        .expire(&ts.exists_key(), ts.expiry)
-        .query_async(&mut *connection)
-        .await?;
+    let released = return_permits(ts, &mut *connection, permits).await?;
+
+    if !ts.quiet {
+        debug!("Released {} of {} permits", released, permits);
+    }
+    Ok(released)
+}
+
+/// Push permits back to the semaphore over `connection`.
+/// Returns the number of permits pushed, as for `release_semaphore`.
+async fn return_permits(ts: &ThreadState, connection: &mut Connection, permits: u32) -> SLResult<u32> {
+    if ts.no_lua {
+        release_semaphore_without_scripts(ts, connection, permits).await
+    } else if ts.counter {
+        let mut invocation = RELEASE_COUNTER_SEMAPHORE.get().prepare_invoke();
+        invocation
+            .key(&ts.name)
+            .key(&ts.exists_key())
+            .arg(ts.capacity)
+            .arg(permits)
+            .arg(ts.expiry.unwrap_or(0)); // 0 means the keys never expire
+        with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+            .await
+            .map_err(|e| map_script_error(e, "release_counter_semaphore"))
+    } else {
+        let mut invocation = RELEASE_SEMAPHORE.get().prepare_invoke();
+        invocation
+            .key(&ts.name)
+            .key(&ts.exists_key())
+            .arg(ts.capacity)
+            .arg(permits)
+            .arg(ts.expiry.unwrap_or(0)); // 0 means the keys never expire
+        with_timeout(ts.response_timeout, invocation.invoke_async(connection))
+            .await
+            .map_err(|e| map_script_error(e, "release_semaphore"))
+    }
+}
+
+/// Push permits back with a transaction of plain commands, for servers where scripting is disabled.
+///
+/// Trimming the queue in the same transaction keeps it from growing beyond the capacity.
+/// Unlike the release script, we can't skip releasing into a semaphore that has expired.
+async fn release_semaphore_without_scripts(
+    ts: &ThreadState,
+    connection: &mut Connection,
+    permits: u32,
+) -> SLResult<u32> {
+    let permits = permits.min(ts.capacity);
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .llen(&ts.name)
+        .lpush(&ts.name, vec![1; permits as usize])
+        .ignore()
+        .ltrim(&ts.name, 0, ts.capacity as isize - 1)
+        .ignore();
+    match ts.expiry {
+        Some(expiry) => pipe
+            .expire(&ts.name, expiry)
+            .ignore()
+            .expire(ts.exists_key(), expiry)
+            .ignore(),
+        None => pipe.persist(&ts.name).ignore().persist(ts.exists_key()).ignore(),
+    };
+    let (available,): (u32,) = with_timeout(ts.response_timeout, pipe.query_async(connection)).await?;
+    Ok(permits.min(ts.capacity.saturating_sub(available)))
+}
+
+/// Wake up everyone currently waiting for the semaphore, and make them raise an `AbortedError`.
+///
+/// Each abort gets a number from the generation counter. The first waiter to pop it from
+/// the abort list pushes it back for the next one, and waiters that started waiting after
+/// the abort was issued drop it, which clears it from the list.
+async fn abort_waiters(ts: ThreadState) -> SLResult<()> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let generation: u64 = with_timeout(ts.response_timeout, connection.incr(ts.abort_generation_key(), 1)).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .lpush(ts.abort_key(), generation)
+        .ignore()
+        .expire(ts.abort_key(), ABORT_TTL_SECONDS)
+        .ignore();
+    with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(&mut *connection)).await?;
+    Ok(())
+}
+
+/// Read the capacity the semaphore was created with, which is stored in the exists key.
+///
+/// Returns `None` if the semaphore doesn't currently exist in redis.
+async fn read_actual_capacity(ts: ThreadState) -> SLResult<Option<u32>> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    Ok(with_timeout(ts.response_timeout, connection.get(ts.exists_key())).await?)
+}
+
+/// Check whether the semaphore has been created in redis, without creating it.
+async fn read_exists(ts: ThreadState) -> SLResult<bool> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    Ok(with_timeout(ts.response_timeout, connection.exists(ts.exists_key())).await?)
+}
+
+/// Read the number of permits available, or `None` if the semaphore doesn't exist in redis.
+async fn read_state(ts: ThreadState) -> SLResult<Option<u32>> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let mut pipe = redis::pipe();
+    pipe.exists(ts.exists_key());
+    if ts.counter {
+        pipe.get(&ts.name);
+    } else {
+        pipe.llen(&ts.name);
+    }
+    let (exists, available): (bool, Option<u32>) =
+        with_timeout(ts.response_timeout, pipe.query_async(&mut *connection)).await?;
+    Ok(exists.then_some(available.unwrap_or(0)))
+}
+
+/// Read the number of tickets in the queue, i.e., fair waiters that haven't acquired yet.
+async fn read_waiters(ts: ThreadState) -> SLResult<u32> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    Ok(with_timeout(ts.response_timeout, connection.llen(ts.queue_key())).await?)
+}
+
+/// Replace the semaphore in redis with one holding `available` permits, or remove it if `None`.
+async fn write_state(ts: ThreadState, available: Option<u32>) -> SLResult<()> {
+    let mut connection = ts.open_connection_pool.get().await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic().del(&ts.name).del(ts.exists_key());
+    if let Some(available) = available {
+        pipe.set(ts.exists_key(), ts.capacity);
+        if ts.counter {
+            pipe.set(&ts.name, available);
+        } else {
+            push_permits(&mut pipe, &ts.name, available);
+        }
+        if let Some(expiry) = ts.expiry {
+            pipe.expire(&ts.name, expiry).expire(ts.exists_key(), expiry);
+        }
+    }
+    with_timeout(ts.response_timeout, pipe.query_async::<_, ()>(&mut *connection)).await?;
+    Ok(())
+}
+
+/// Move up to `permits` free permits from `source` to `destination`, adjusting the capacities stored in redis.
+/// Returns the number of permits transferred.
+///
+/// Both semaphores are created first if needed, so a fresh source has its full capacity to give.
+async fn transfer_permits(source: ThreadState, destination: ThreadState, permits: u32, partial: bool) -> SLResult<u32> {
+    let mut connection = source.open_connection_pool.get().await?;
+    create_semaphore(&source, &mut *connection).await?;
+    create_semaphore(&destination, &mut *connection).await?;
+    let mut invocation = TRANSFER_SEMAPHORE.get().prepare_invoke();
+    invocation
+        .key(&source.name)
+        .key(&source.exists_key())
+        .key(&destination.name)
+        .key(&destination.exists_key())
+        .arg(permits)
+        .arg(partial as u8);
+    let transferred: u32 = with_timeout(source.response_timeout, invocation.invoke_async(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "transfer_semaphore"))?;
+    if !source.quiet {
+        info!(
+            "Transferred {} of {} permits from {} to {}",
+            transferred, permits, source.name, destination.name
+        );
+    }
+    Ok(transferred)
+}
+
+#[derive(Default)]
+struct AcquisitionState {
+    acquired: AtomicBool,
+    released: AtomicBool,
+    waited_ms: AtomicU64,
+    /// The fencing token handed out with the permit, where 0 means none
+    fencing_token: AtomicU64,
+    /// One more than the number of tickets ahead of ours on arrival, where 0 means none
+    position: AtomicU32,
+    /// Background task renewing the semaphore's expiry while the permit is held, when enabled
+    renewal: Mutex<Option<JoinHandle<()>>>,
+    /// Set when the acquire raised or was cancelled, since `__aexit__` is then never called for it
+    failed: AtomicBool,
+}
+
+impl AcquisitionState {
+    /// Stop renewing the semaphore's expiry, if we were.
+    fn stop_renewal(&self) {
+        // A poisoned lock only means another thread panicked mid-update; the handle itself is still usable
+        if let Some(renewal) = self.renewal.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            renewal.abort();
+        }
+    }
+}
 
-    debug!("Released semaphore");
+// Acquisitions that are never released stop renewing once they're garbage collected,
+// so the semaphore expires as it would have without renewal
+impl Drop for AcquisitionState {
+    fn drop(&mut self) {
+        self.stop_renewal();
+    }
+}
+
+/// The running asyncio task, or `None` outside one, e.g., when entering with `with` from a thread.
+fn current_task(py: Python<'_>) -> Option<&PyAny> {
+    py.import("asyncio")
+        .and_then(|asyncio| asyncio.call_method0("current_task"))
+        .ok()
+        .filter(|task| !task.is_none())
+}
+
+/// A single acquisition of a semaphore, returned from `__aenter__` and `acquire`.
+#[pyclass(frozen)]
+#[pyo3(name = "Acquisition")]
+#[pyo3(module = "self_limiters")]
+pub(crate) struct Acquisition {
+    #[pyo3(get)]
+    holder_id: String,
+    state: Arc<AcquisitionState>,
+    ts: ThreadState,
+    /// Weak reference to the asyncio task that acquired, or `None` if acquired outside a task
+    task: Option<PyObject>,
+}
+
+impl Acquisition {
+    /// Whether the acquisition has been acquired, and not released yet.
+    fn holds_permit(&self) -> bool {
+        self.state.acquired.load(Ordering::Relaxed) && !self.state.released.load(Ordering::Relaxed)
+    }
+
+    /// Whether the acquisition was made by `task`, as returned by `current_task`.
+    ///
+    /// Tasks created inside an `async with` block inherit its acquisitions with the context,
+    /// so this tells a task's own acquisitions apart from its parent's.
+    fn acquired_by(&self, py: Python<'_>, task: Option<&PyAny>) -> bool {
+        match (&self.task, task) {
+            (Some(acquired_by), Some(task)) => acquired_by
+                .call0(py)
+                .map_or(false, |acquired_by| acquired_by.as_ref(py).is(task)),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Mark the acquisition as released, returning the state needed to release it,
+    /// unless it was never acquired or has already been released.
+    fn take_release(&self) -> Option<ThreadState> {
+        if self.state.acquired.load(Ordering::Relaxed) && !self.state.released.swap(true, Ordering::Relaxed) {
+            self.state.stop_renewal();
+            Some(self.ts.clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Return a Python awaitable releasing the acquisition's permit. See `release_acquired`.
+fn release_acquisition(
+    py: Python<'_>,
+    ts: Option<ThreadState>,
+    holder_id: Option<String>,
+    suppress_errors: bool,
+) -> PyResult<&PyAny> {
+    future_into_py(py, release_acquired(ts, holder_id, suppress_errors))
+}
+
+/// Release the acquisition's permit, if it still holds one, and remove its holder record.
+///
+/// Only releases with a `holder_id` are of a tracked acquisition, and counted as exits.
+/// With `suppress_errors`, errors are logged instead of raised.
+async fn release_acquired(ts: Option<ThreadState>, holder_id: Option<String>, suppress_errors: bool) -> PyResult<()> {
+    if let Some(ts) = ts {
+        if let Some(holder_id) = &holder_id {
+            remove_holder(&ts, holder_id).await;
+            remove_lease(&ts, holder_id).await;
+        }
+        let result = release_semaphore(&ts, ts.weight).await;
+        track_released();
+        match result {
+            Ok(_) => {
+                ts.record_release(1);
+                if holder_id.is_some() {
+                    ts.counters.exited.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Err(e) if suppress_errors => warn!("Failed to release Semaphore {}: {:?}", ts.name, e),
+            Err(e) => return Err(e.for_limiter(&ts.name)),
+        }
+    }
     Ok(())
 }
 
+#[pymethods]
+impl Acquisition {
+    /// The number of milliseconds spent waiting to acquire the semaphore.
+    #[getter]
+    fn waited_ms(&self) -> u64 {
+        self.state.waited_ms.load(Ordering::Relaxed)
+    }
+
+    /// The number of permits taken, and returned on release.
+    #[getter]
+    fn weight(&self) -> u32 {
+        self.ts.weight
+    }
+
+    /// The fencing token handed out with the permit, when the semaphore was created with `fencing=True`.
+    ///
+    /// Tokens are greater for each acquisition of the semaphore than for any before it.
+    #[getter]
+    fn fencing_token(&self) -> Option<u64> {
+        Some(self.state.fencing_token.load(Ordering::Relaxed)).filter(|token| *token > 0)
+    }
+
+    /// The number of tickets ahead of ours when we started waiting, when the semaphore was created with `fair=True`.
+    ///
+    /// This is the position on arrival, not after waiting, and is 0 when a permit was taken right away.
+    #[getter]
+    fn position(&self) -> Option<u32> {
+        self.state.position.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    #[getter]
+    fn released(&self) -> bool {
+        self.state.released.load(Ordering::Relaxed)
+    }
+
+    /// Release the permit held. This is idempotent, so it's safe to call
+    /// from inside an `async with` block, which also releases on exit.
+    #[pyo3(text_signature = "($self)")]
+    fn release<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        release_acquisition(py, self.take_release(), Some(self.holder_id.clone()), false)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Acquisition {} for queue {}", &self.holder_id, &self.ts.name)
+    }
+}
+
 /// Async context manager useful for controlling client traffic
 /// in situations where you need to limit traffic to `n` requests concurrently.
 /// For example, when you can only have 2 active requests simultaneously.
-#[pyclass(frozen)]
+#[pyclass(
+    frozen,
+    text_signature = "(name, capacity, max_sleep=None, expiry=30, redis_url=None, connection_pool_size=None, \
+    eager_connect=None, wait_callback=None, wait_callback_interval=None, fair=None, dedicated_connection=None, \
+    quiet=None, connect_timeout=None, response_timeout=None, count_rejections=None, no_lua=None, backoff=None, \
+    backoff_interval=None, counter=None, suppress_release_errors=None, track_holders=None, \
+    latency_callback=None, dry_run=None, fencing=None, verify_tls=None, renew_expiry=None, reclaim=None, prefix=None, \
+    max_retries=None, retry_backoff=None, username=None, password=None)"
+)]
 #[pyo3(name = "Semaphore")]
 #[pyo3(module = "self_limiters")]
 pub(crate) struct Semaphore {
     #[pyo3(get)]
     name: String,
-    #[pyo3(get)]
-    capacity: u32,
+    capacity: AtomicU32,
     #[pyo3(get)]
     max_sleep: f32,
     #[pyo3(get)]
-    expiry: usize,
+    expiry: Option<usize>,
+    #[pyo3(get)]
+    wait_callback_interval: usize,
+    #[pyo3(get)]
+    fair: bool,
+    #[pyo3(get)]
+    dedicated_connection: bool,
+    #[pyo3(get)]
+    quiet: bool,
+    #[pyo3(get)]
+    count_rejections: bool,
+    #[pyo3(get)]
+    no_lua: bool,
+    #[pyo3(get)]
+    counter: bool,
+    #[pyo3(get)]
+    suppress_release_errors: bool,
+    #[pyo3(get)]
+    dry_run: bool,
+    #[pyo3(get)]
+    fencing: bool,
+    #[pyo3(get)]
+    verify_tls: bool,
+    #[pyo3(get)]
+    renew_expiry: bool,
+    #[pyo3(get)]
+    reclaim: bool,
+    holder_host: Option<String>,
+    connect_timeout: Option<Duration>,
+    response_timeout: Option<Duration>,
+    wait_callback: Option<PyObject>,
+    latency_callback: Option<PyObject>,
+    counters: Arc<Counters>,
+    wait_samples: Arc<WaitSamples>,
+    backoff: Backoff,
+    backoff_interval_ms: u64,
+    retries: RetryPolicy,
+    acquisitions: PyObject,
     open_connection_pool: Pool<RedisConnectionManager>,
     return_connection_pool: Pool<RedisConnectionManager>,
+    /// Pools for other redis servers, for handles returned by `with_redis_url`
+    open_pools: Arc<PoolCache>,
+    return_pools: Arc<PoolCache>,
+}
+
+impl Semaphore {
+    /// Create a semaphore using an existing connection pool, with default settings.
+    pub(crate) fn with_pool(
+        py: Python<'_>,
+        name: String,
+        capacity: u32,
+        max_sleep: f32,
+        expiry: Option<usize>,
+        quiet: bool,
+        connection_pool: Pool<RedisConnectionManager>,
+    ) -> PyResult<Self> {
+        // Acquisitions are tracked per context (i.e., per asyncio task), so that
+        // `__aexit__` knows which acquisition to release
+        let acquisitions = py
+            .import("contextvars")?
+            .getattr("ContextVar")?
+            .call1((format!("{}-acquisitions", name),))?
+            .into();
+
+        Ok(Self {
+            capacity: AtomicU32::new(capacity),
+            name,
+            max_sleep,
+            expiry,
+            wait_callback: None,
+            wait_callback_interval: 5,
+            latency_callback: None,
+            fair: false,
+            dedicated_connection: false,
+            quiet,
+            count_rejections: false,
+            no_lua: false,
+            counter: false,
+            suppress_release_errors: false,
+            dry_run: false,
+            fencing: false,
+            verify_tls: true,
+            renew_expiry: false,
+            reclaim: false,
+            holder_host: None,
+            connect_timeout: None,
+            response_timeout: None,
+            counters: Arc::new(Counters::default()),
+            wait_samples: Arc::new(WaitSamples::default()),
+            backoff: Backoff::Constant,
+            backoff_interval_ms: FAIR_POLL_INTERVAL_MS,
+            retries: RetryPolicy::default(),
+            acquisitions,
+            open_connection_pool: connection_pool.clone(),
+            return_connection_pool: connection_pool,
+            open_pools: Arc::new(PoolCache::new(DEFAULT_POOL_SIZE, None, true, Credentials::default())),
+            return_pools: Arc::new(PoolCache::new(DEFAULT_POOL_SIZE, None, true, Credentials::default())),
+        })
+    }
+
+    /// Fail commands that take longer than `response_timeout` to respond.
+    pub(crate) fn with_response_timeout(self, response_timeout: Option<Duration>) -> Self {
+        Self {
+            response_timeout,
+            ..self
+        }
+    }
+
+    /// Create a semaphore for `name` with the same settings, using the given connection pools.
+    fn with_name_and_pools(
+        &self,
+        py: Python<'_>,
+        name: String,
+        open_connection_pool: Pool<RedisConnectionManager>,
+        return_connection_pool: Pool<RedisConnectionManager>,
+    ) -> PyResult<Self> {
+        Ok(Self {
+            wait_callback: self.wait_callback.clone(),
+            wait_callback_interval: self.wait_callback_interval,
+            latency_callback: self.latency_callback.clone(),
+            fair: self.fair,
+            dedicated_connection: self.dedicated_connection,
+            count_rejections: self.count_rejections,
+            no_lua: self.no_lua,
+            counter: self.counter,
+            suppress_release_errors: self.suppress_release_errors,
+            dry_run: self.dry_run,
+            fencing: self.fencing,
+            verify_tls: self.verify_tls,
+            renew_expiry: self.renew_expiry,
+            reclaim: self.reclaim,
+            holder_host: self.holder_host.clone(),
+            connect_timeout: self.connect_timeout,
+            response_timeout: self.response_timeout,
+            backoff: self.backoff,
+            backoff_interval_ms: self.backoff_interval_ms,
+            retries: self.retries,
+            open_pools: self.open_pools.clone(),
+            return_pools: self.return_pools.clone(),
+            return_connection_pool,
+            ..Self::with_pool(
+                py,
+                name,
+                self.capacity(),
+                self.max_sleep,
+                self.expiry,
+                self.quiet,
+                open_connection_pool,
+            )?
+        })
+    }
+
+    /// Create an acquisition, and a Python awaitable acquiring the semaphore on its behalf.
+    fn acquisition<'p>(
+        &self,
+        py: Python<'p>,
+        id: String,
+        cancel: Option<PyObject>,
+        weight: u32,
+    ) -> PyResult<(Py<Acquisition>, &'p PyAny)> {
+        let (acquisition, future) = self.prepare_acquisition(py, id, cancel, weight)?;
+        let handle = acquisition.clone_ref(py);
+        let future = future_into_py(py, async move {
+            future.await?;
+            Ok(handle)
+        })?;
+        Ok((acquisition, future))
+    }
+
+    /// Create an acquisition, and a future acquiring the semaphore on its behalf.
+    ///
+    /// Raises if the current task holds so many permits that there wouldn't be `weight` left,
+    /// and there's no `max_sleep`, since the acquire would then wait forever for the caller to release.
+    fn prepare_acquisition(
+        &self,
+        py: Python<'_>,
+        id: String,
+        cancel: Option<PyObject>,
+        weight: u32,
+    ) -> PyResult<(Py<Acquisition>, impl Future<Output = PyResult<()>> + Send + 'static)> {
+        let task = current_task(py);
+        if self.max_sleep == 0.0 {
+            let held: u32 = self
+                .entered_acquisitions(py)?
+                .iter()
+                .filter_map(|entered| {
+                    let acquisition = entered.extract::<PyRef<Acquisition>>().ok()?;
+                    // Dry runs never wait for the real semaphore, so they can't deadlock on it
+                    (acquisition.holds_permit() && !acquisition.ts.dry_run && acquisition.acquired_by(py, task))
+                        .then_some(acquisition.ts.weight)
+                })
+                .sum();
+            if held + weight > self.capacity() {
+                return Err(SLError::WouldDeadlock(format!(
+                    "Semaphore {} is already held {} times in this task, which leaves less than the {} \
+                    permits requested of its capacity of {}. Acquiring it would wait forever",
+                    self.name,
+                    held,
+                    weight,
+                    self.capacity()
+                ))
+                .for_limiter(&self.name));
+            }
+        }
+        let ts = ThreadState {
+            weight,
+            ..ThreadState::from(self)
+        };
+        let ts = if self.dry_run { ts.dry_run_shadow() } else { ts };
+        let state = Arc::new(AcquisitionState::default());
+        let acquisition = Py::new(
+            py,
+            Acquisition {
+                holder_id: id.clone(),
+                state: state.clone(),
+                ts: ts.clone(),
+                task: task
+                    .map(|task| py.import("weakref")?.getattr("ref")?.call1((task,)).map(Into::into))
+                    .transpose()?,
+            },
+        )?;
+        let dry_run = self.dry_run;
+        let failed = {
+            let state = state.clone();
+            DropGuard::new(move || state.failed.store(true, Ordering::Relaxed))
+        };
+        let future = async move {
+            let name = ts.name.clone();
+            if dry_run {
+                // Only acquisitions that got a permit are marked as acquired, and release it again
+                let acquired = measured_if(&ts.metrics_name, acquire_dry_run(&ts), |acquired| *acquired)
+                    .await
+                    .map_err(|e| e.for_limiter(&ts.metrics_name))?;
+                state.acquired.store(acquired, Ordering::Relaxed);
+                failed.defuse();
+                return Ok(());
+            }
+            let (waited, position) = create_and_acquire_semaphore(ts.clone(), &id, cancel)
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            if ts.fencing {
+                match next_fencing_token(&ts).await {
+                    Ok(token) => state.fencing_token.store(token, Ordering::Relaxed),
+                    Err(e) => {
+                        // Without a token the permit can't be used safely, so we give it back
+                        release_acquired(Some(ts), Some(id), true).await?;
+                        return Err(e.for_limiter(&name));
+                    }
+                }
+            }
+            record_holder(&ts, &id).await;
+            record_lease(&ts, &id).await;
+            *state.renewal.lock().unwrap_or_else(|e| e.into_inner()) = spawn_expiry_renewal(&ts, &id);
+            state.waited_ms.store(waited, Ordering::Relaxed);
+            state
+                .position
+                .store(position.map_or(0, |position| position + 1), Ordering::Relaxed);
+            state.acquired.store(true, Ordering::Relaxed);
+            failed.defuse();
+            Ok(())
+        };
+        Ok((acquisition, future))
+    }
+
+    /// Track an entered acquisition in the current context, for `__aexit__` and `__exit__`.
+    fn push_entered(&self, py: Python<'_>, acquisition: PyObject) -> PyResult<()> {
+        let mut entered: Vec<PyObject> = self.entered_acquisitions(py)?.iter().map(Into::into).collect();
+        entered.push(acquisition);
+        self.acquisitions
+            .call_method1(py, "set", (PyTuple::new(py, entered),))?;
+        Ok(())
+    }
+
+    /// Stop tracking the innermost acquisition entered in the current context, returning the
+    /// state needed to release it and its holder id, unless it was already released.
+    ///
+    /// If nothing was entered in this context, which happens when the semaphore was entered
+    /// from another one, we release a permit without tracking the acquisition.
+    fn pop_entered(&self, py: Python<'_>) -> PyResult<(Option<ThreadState>, Option<String>)> {
+        let entered = self.entered_acquisitions(py)?;
+        if entered.is_empty() {
+            let ts = ThreadState::from(self);
+            return Ok((Some(if self.dry_run { ts.dry_run_shadow() } else { ts }), None));
+        }
+        let acquisition: Py<Acquisition> = entered.get_item(entered.len() - 1)?.extract()?;
+        self.acquisitions
+            .call_method1(py, "set", (entered.get_slice(0, entered.len() - 1),))?;
+        let acquisition = acquisition.borrow(py);
+        Ok((acquisition.take_release(), Some(acquisition.holder_id.clone())))
+    }
+
+    /// The acquisitions entered in the current context, innermost last.
+    ///
+    /// Acquisitions whose acquire failed are left out, so they're dropped the next time the context is updated.
+    fn entered_acquisitions<'p>(&self, py: Python<'p>) -> PyResult<&'p PyTuple> {
+        let entered = self
+            .acquisitions
+            .call_method1(py, "get", (PyTuple::empty(py),))?
+            .into_ref(py)
+            .downcast::<PyTuple>()?;
+        let pending: Vec<&PyAny> = entered
+            .iter()
+            .filter(|entered| {
+                entered
+                    .extract::<PyRef<Acquisition>>()
+                    .map_or(true, |acquisition| !acquisition.state.failed.load(Ordering::Relaxed))
+            })
+            .collect();
+        Ok(PyTuple::new(py, pending))
+    }
+}
+
+/// Check the settings `new` and `from_client` both take, returning the prefixed name and the max sleep.
+fn check_settings(
+    name: &str,
+    capacity: u32,
+    max_sleep: Option<f32>,
+    expiry: Option<usize>,
+    prefix: Option<&str>,
+) -> PyResult<(String, f32)> {
+    at_most_max_capacity("Capacity", capacity.into())?;
+    check_expiry(expiry)?;
+    Ok((prefixed_name(prefix, name)?, max_sleep_seconds(max_sleep)?))
 }
 
 #[pymethods]
 impl Semaphore {
     /// Create a new class instance.
+    ///
+    /// The expiry defaults to 30 seconds. Passing `None` explicitly means the semaphore never expires.
     #[new]
+    #[args(expiry = "30")]
+    #[allow(clippy::too_many_arguments)]
     fn new(
+        py: Python<'_>,
         name: String,
         capacity: u32,
         max_sleep: Option<f32>,
         expiry: Option<usize>,
         redis_url: Option<&str>,
         connection_pool_size: Option<u32>,
+        eager_connect: Option<bool>,
+        wait_callback: Option<PyObject>,
+        wait_callback_interval: Option<usize>,
+        fair: Option<bool>,
+        dedicated_connection: Option<bool>,
+        quiet: Option<bool>,
+        connect_timeout: Option<f32>,
+        response_timeout: Option<f32>,
+        count_rejections: Option<bool>,
+        no_lua: Option<bool>,
+        backoff: Option<&str>,
+        backoff_interval: Option<f32>,
+        counter: Option<bool>,
+        suppress_release_errors: Option<bool>,
+        track_holders: Option<bool>,
+        latency_callback: Option<PyObject>,
+        dry_run: Option<bool>,
+        fencing: Option<bool>,
+        verify_tls: Option<bool>,
+        renew_expiry: Option<bool>,
+        reclaim: Option<bool>,
+        prefix: Option<&str>,
+        max_retries: Option<u32>,
+        retry_backoff: Option<f32>,
+        username: Option<String>,
+        password: Option<String>,
     ) -> PyResult<Self> {
         debug!("Creating new Semaphore instance");
+        let eager_connect = eager_connect.unwrap_or(false);
+
+        let (name, max_sleep) = check_settings(&name, capacity, max_sleep, expiry, prefix)?;
+        let retries = RetryPolicy::new(max_retries, retry_backoff)?;
+
+        let renew_expiry = renew_expiry.unwrap_or(false);
+        if renew_expiry && expiry.is_none() {
+            return Err(PyValueError::new_err(
+                "Renewing the expiry requires an expiry, so can't be used with expiry=None",
+            ));
+        }
+
+        let (fair, no_lua) = (fair.unwrap_or(false), no_lua.unwrap_or(false));
+        if fair && no_lua {
+            return Err(PyValueError::new_err(
+                "Fair semaphores require Lua scripts, so can't be used with no_lua=True",
+            ));
+        }
+
+        let counter = counter.unwrap_or(false);
+        if counter && (fair || no_lua) {
+            return Err(PyValueError::new_err(
+                "Counter semaphores can't be used with fair=True or no_lua=True",
+            ));
+        }
+
+        let reclaim = reclaim.unwrap_or(false);
+        if reclaim && (expiry.is_none() || no_lua) {
+            return Err(PyValueError::new_err(
+                "Reclaiming permits requires an expiry and Lua scripts, so can't be used with expiry=None or \
+                no_lua=True",
+            ));
+        }
+
+        let backoff = Backoff::parse(backoff.unwrap_or("constant"))?;
+        let backoff_interval = backoff_interval
+            .map(|i| positive_seconds("Backoff interval", i))
+            .transpose()?;
+
+        let wait_callback_interval = wait_callback_interval.unwrap_or(5);
+        if wait_callback_interval == 0 {
+            return Err(PyValueError::new_err("Wait callback interval must be greater than 0"));
+        }
+
+        let connect_timeout = connect_timeout
+            .map(|t| positive_seconds("Connect timeout", t))
+            .transpose()?;
+        let response_timeout = response_timeout
+            .map(|t| positive_seconds("Response timeout", t))
+            .transpose()?;
+
+        // Look the hostname up once, rather than on every acquire
+        let holder_host = if track_holders.unwrap_or(false) {
+            Some(py.import("socket")?.call_method0("gethostname")?.extract()?)
+        } else {
+            None
+        };
 
         // Create redis connection manager
-        let open_manager = create_connection_manager(redis_url)?;
-        let return_manager = create_connection_manager(redis_url)?;
+        let verify_tls = verify_tls.unwrap_or(true);
+        let credentials = Credentials { username, password };
+        let open_manager = create_connection_manager(redis_url, verify_tls, &credentials)?;
+        let return_manager = create_connection_manager(redis_url, verify_tls, &credentials)?;
 
         // Create connection pool
-        let open_pool = create_connection_pool(open_manager, connection_pool_size.unwrap_or(15))?;
-        let return_pool = create_connection_pool(return_manager, connection_pool_size.unwrap_or(15))?;
+        let connection_pool_size = connection_pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+        let open_pool = create_connection_pool(open_manager, connection_pool_size, eager_connect, connect_timeout)?;
+        let return_pool = create_connection_pool(return_manager, connection_pool_size, false, connect_timeout)?;
 
         Ok(Self {
-            capacity,
-            name: format!("{}{}", REDIS_KEY_PREFIX, name),
-            max_sleep: max_sleep.unwrap_or(0.0),
-            expiry: expiry.unwrap_or(30),
-            open_connection_pool: open_pool,
+            wait_callback,
+            wait_callback_interval,
+            latency_callback,
+            fair,
+            dedicated_connection: dedicated_connection.unwrap_or(false),
+            count_rejections: count_rejections.unwrap_or(false),
+            no_lua,
+            counter,
+            suppress_release_errors: suppress_release_errors.unwrap_or(false),
+            dry_run: dry_run.unwrap_or(false),
+            fencing: fencing.unwrap_or(false),
+            verify_tls,
+            renew_expiry,
+            reclaim,
+            holder_host,
+            connect_timeout,
+            response_timeout,
+            backoff,
+            backoff_interval_ms: backoff_interval
+                .map_or(FAIR_POLL_INTERVAL_MS, |i| (i.as_secs_f64() * 1000.0).ceil() as u64),
+            retries,
             return_connection_pool: return_pool,
+            open_pools: Arc::new(PoolCache::new(
+                connection_pool_size,
+                connect_timeout,
+                verify_tls,
+                credentials.clone(),
+            )),
+            return_pools: Arc::new(PoolCache::new(
+                connection_pool_size,
+                connect_timeout,
+                verify_tls,
+                credentials.clone(),
+            )),
+            ..Self::with_pool(py, name, capacity, max_sleep, expiry, quiet.unwrap_or(false), open_pool)?
+        })
+    }
+
+    /// Create a semaphore that connects through a shared `client`, rather than opening its own connections.
+    ///
+    /// The client's timeouts and retry settings apply. Other settings not taken here are left at their defaults.
+    #[staticmethod]
+    #[args(expiry = "30")]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(text_signature = "(client, name, capacity, max_sleep=None, expiry=30, quiet=None, prefix=None)")]
+    fn from_client(
+        py: Python<'_>,
+        client: PyRef<'_, RedisClient>,
+        name: String,
+        capacity: u32,
+        max_sleep: Option<f32>,
+        expiry: Option<usize>,
+        quiet: Option<bool>,
+        prefix: Option<&str>,
+    ) -> PyResult<Self> {
+        let (name, max_sleep) = check_settings(&name, capacity, max_sleep, expiry, prefix)?;
+
+        Ok(Self {
+            verify_tls: client.verify_tls,
+            connect_timeout: client.connect_timeout,
+            response_timeout: client.response_timeout,
+            retries: client.retries,
+            return_connection_pool: client.return_pool.clone(),
+            open_pools: client.open_pools.clone(),
+            return_pools: client.return_pools.clone(),
+            ..Self::with_pool(
+                py,
+                name,
+                capacity,
+                max_sleep,
+                expiry,
+                quiet.unwrap_or(false),
+                client.open_pool.clone(),
+            )?
         })
     }
 
+    /// Acquire the semaphore. Returns an `Acquisition`.
+    #[pyo3(text_signature = "($self)")]
     fn __aenter__<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (acquisition, future) = self.acquisition(py, nanoid!(10), None, 1)?;
+        self.push_entered(py, acquisition.into_py(py))?;
+        Ok(future)
+    }
+
+    /// Acquire the semaphore from synchronous code, e.g., in a thread, blocking until we get a permit.
+    ///
+    /// This runs the same acquire as `__aenter__`, and respects `max_sleep` the same way. The GIL
+    /// is released while blocking, so other threads keep running. Returns an `Acquisition`.
+    #[pyo3(text_signature = "($self)")]
+    fn __enter__(&self, py: Python<'_>) -> PyResult<Py<Acquisition>> {
+        let (acquisition, future) = self.prepare_acquisition(py, nanoid!(10), None, 1)?;
+        block_on(py, future)?;
+        self.push_entered(py, acquisition.clone_ref(py).into_py(py))?;
+        Ok(acquisition)
+    }
+
+    /// Release the permit taken by `__enter__`, blocking until it's returned.
+    #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
+    fn __exit__(&self, py: Python<'_>, _a: &PyTuple) -> PyResult<()> {
+        let (ts, holder_id) = self.pop_entered(py)?;
+        block_on(py, release_acquired(ts, holder_id, self.suppress_release_errors))
+    }
+
+    /// Acquire the semaphore, like `__aenter__`, with `id` identifying the holder.
+    ///
+    /// The id is used verbatim as the holder's ticket when fairness is enabled,
+    /// so it must be unique among concurrent waiters. A random id is generated
+    /// when none is passed. Returns an `Acquisition`, which must be released explicitly.
+    ///
+    /// Setting the `cancel` event, e.g., an `asyncio.Event`, while waiting makes the acquire
+    /// give up its place and raise an `AbortedError`. Cancellable acquires wait on a dedicated connection.
+    ///
+    /// Passing a `weight` takes that many permits at once, and releasing the acquisition returns
+    /// them all. Weighted acquires wait for all the permits to be free, polling like counter semaphores.
+    #[args(weight = "1")]
+    #[pyo3(text_signature = "($self, id=None, cancel=None, weight=1)")]
+    fn acquire<'p>(
+        &self,
+        py: Python<'p>,
+        id: Option<String>,
+        cancel: Option<PyObject>,
+        weight: u32,
+    ) -> PyResult<&'p PyAny> {
+        if let Some(cancel) = &cancel {
+            if !cancel.as_ref(py).hasattr("is_set")? {
+                return Err(PyTypeError::new_err("Cancel must be an event, with an is_set method"));
+            }
+        }
+        if weight == 0 || weight > self.capacity() {
+            return Err(PyValueError::new_err(
+                "Weight must be greater than 0, and at most the capacity",
+            ));
+        }
+        if weight > 1 && (self.fair || self.no_lua) {
+            return Err(PyValueError::new_err(
+                "Weighted acquires are not supported when fair=True or no_lua=True",
+            ));
+        }
+        let (_, future) = self.acquisition(py, id.unwrap_or_else(|| nanoid!(10)), cancel, weight)?;
+        Ok(future)
+    }
+
+    /// Take a permit if one is free right away, without waiting. Returns whether we got one.
+    ///
+    /// Unlike `acquire`, this never enters the queue, so with `fair=True` it bypasses the
+    /// waiters ahead in it. When this returns `True`, release the permit with `release` when done.
+    #[pyo3(text_signature = "($self)")]
+    fn try_acquire<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
         let ts = ThreadState::from(self);
-        future_into_py(py, async { Ok(create_and_acquire_semaphore(ts).await?) })
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            let acquired = measured_if(&name, try_acquire_semaphore(&ts), |acquired| *acquired)
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            if acquired && !ts.quiet {
+                debug!("Acquired permit without waiting");
+            }
+            Ok(acquired)
+        })
     }
 
+    /// Acquire a permit from any of `shards` semaphores, each holding a share of the capacity.
+    ///
+    /// Shards are named `{name}-{index}`, and tried starting from a random one. Raises
+    /// `MaxSleepExceededError` right away if no shard has a free permit. Returns an
+    /// `Acquisition`, which must be released explicitly, and releases to its own shard.
+    #[pyo3(text_signature = "($self, shards)")]
+    fn acquire_sharded<'p>(&self, py: Python<'p>, shards: u32) -> PyResult<&'p PyAny> {
+        if shards == 0 || shards > self.capacity() {
+            return Err(PyValueError::new_err(
+                "Shards must be greater than 0, and at most the capacity",
+            ));
+        }
+        if self.fair || self.counter || self.dry_run {
+            return Err(PyValueError::new_err(
+                "Sharded acquires are not supported when fair=True, counter=True or dry_run=True",
+            ));
+        }
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            let (shard, waited) = measured(&name, acquire_sharded(ts, shards))
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            let holder_id = nanoid!(10);
+            let state = AcquisitionState {
+                acquired: AtomicBool::new(true),
+                released: AtomicBool::new(false),
+                waited_ms: AtomicU64::new(waited),
+                fencing_token: AtomicU64::new(0),
+                position: AtomicU32::new(0),
+                renewal: Mutex::new(spawn_expiry_renewal(&shard, &holder_id)),
+                failed: AtomicBool::new(false),
+            };
+            Python::with_gil(|py| {
+                let acquisition = Acquisition {
+                    holder_id,
+                    state: Arc::new(state),
+                    ts: shard,
+                    task: None,
+                };
+                Ok(Py::new(py, acquisition)?.to_object(py))
+            })
+        })
+    }
+
+    /// Decorate an async function, so that every call to it runs inside an `async with` block on the semaphore.
+    ///
+    /// The wrapped function's return value and exceptions are passed through as is.
+    #[pyo3(text_signature = "($self, func)")]
+    fn limit(slf: &PyCell<Self>, func: PyObject) -> PyResult<PyObject> {
+        limit(slf.py(), slf.as_ref().into(), func)
+    }
+
+    /// Release the innermost acquisition entered in the current context.
+    ///
+    /// With `suppress_release_errors`, errors releasing are logged rather than raised,
+    /// so they don't replace an exception raised in the `async with` block.
     #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
     fn __aexit__<'p>(&self, py: Python<'p>, _a: &'p PyTuple) -> PyResult<&'p PyAny> {
+        let (ts, holder_id) = self.pop_entered(py)?;
+        release_acquisition(py, ts, holder_id, self.suppress_release_errors)
+    }
+
+    /// Release permits back to the semaphore, without exceeding its capacity.
+    ///
+    /// Returns the number of permits released.
+    #[args(permits = "1")]
+    #[pyo3(text_signature = "($self, permits=1)")]
+    fn release<'p>(&self, py: Python<'p>, permits: u32) -> PyResult<&'p PyAny> {
+        if permits == 0 {
+            return Err(PyValueError::new_err("Permits must be greater than 0"));
+        }
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            let released = release_semaphore(&ts, permits)
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            // Each permit matches a `try_acquire`, which is the only way to take one without an acquisition
+            ts.record_release(permits as u64);
+            Ok(released)
+        })
+    }
+
+    /// Make everyone currently waiting for the semaphore raise an `AbortedError`, e.g., on shutdown.
+    ///
+    /// This applies to waiters in all processes. Holders keep their permits,
+    /// and clients that start waiting afterwards are unaffected.
+    #[pyo3(text_signature = "($self)")]
+    fn abort_all<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
         let ts = ThreadState::from(self);
-        future_into_py(py, async { Ok(release_semaphore(ts).await?) })
+        future_into_py(py, async move { Ok(abort_waiters(ts).await?) })
+    }
+
+    /// Return the capacity the semaphore was created with in redis, or None if it doesn't exist.
+    ///
+    /// This can differ from `capacity` if another process created the
+    /// semaphore with a different capacity, which is worth alerting on.
+    #[pyo3(text_signature = "($self)")]
+    fn actual_capacity<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move { Ok(read_actual_capacity(ts).await?) })
+    }
+
+    /// Return whether the semaphore currently exists in redis.
+    ///
+    /// This is read-only, and says nothing about free capacity: a fully acquired semaphore
+    /// still exists, while one that has expired, or was never entered, doesn't.
+    #[pyo3(text_signature = "($self)")]
+    fn exists<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move { Ok(read_exists(ts).await?) })
+    }
+
+    /// Return the number of permits free right now, without acquiring them.
+    ///
+    /// This is read-only. A semaphore that doesn't exist yet is created full on first use,
+    /// so its capacity is returned then.
+    #[pyo3(text_signature = "($self)")]
+    fn available<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move {
+            let capacity = ts.capacity;
+            Ok(read_state(ts).await?.unwrap_or(capacity))
+        })
+    }
+
+    /// Return the number of waiters queued for a permit, when the semaphore was created with `fair=True`.
+    ///
+    /// Without fairness, waiters block in redis without joining a queue, so there's nothing to count,
+    /// and None is returned. Tickets of waiters that gave up are counted until they're discarded.
+    #[pyo3(text_signature = "($self)")]
+    fn waiters<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move {
+            if !ts.fair {
+                return Ok(None);
+            }
+            Ok(Some(read_waiters(ts).await?))
+        })
+    }
+
+    /// Return who currently holds permits, as a list of dicts with each holder's `id`, `host`, `pid`
+    /// and `since`, the unix timestamp it acquired at, oldest first.
+    ///
+    /// Holders are only recorded by instances created with `track_holders=True`. Holders that
+    /// crash without releasing are listed until the semaphore expires.
+    #[pyo3(text_signature = "($self)")]
+    fn holders<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move {
+            let holders = read_holders(ts).await?;
+            Python::with_gil(|py| {
+                let list = holders
+                    .into_iter()
+                    .map(|(id, host, pid, since)| {
+                        let holder = PyDict::new(py);
+                        holder.set_item("id", id)?;
+                        holder.set_item("host", host)?;
+                        holder.set_item("pid", pid)?;
+                        holder.set_item("since", since as f64 / 1000.0)?;
+                        Ok(holder.to_object(py))
+                    })
+                    .collect::<PyResult<Vec<_>>>()?;
+                Ok(list.to_object(py))
+            })
+        })
+    }
+
+    /// Whether acquires record their holder's host and pid in redis, for `holders`.
+    #[getter]
+    fn track_holders(&self) -> bool {
+        self.holder_host.is_some()
+    }
+
+    /// Return the number of rejections counted across all clients, in the current window.
+    ///
+    /// Rejections are only counted by instances created with `count_rejections=True`.
+    #[pyo3(text_signature = "($self)")]
+    fn rejection_rate<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (pool, name) = (self.open_connection_pool.clone(), self.name.clone());
+        future_into_py(py, async move { Ok(read_rejections(pool, name).await?) })
+    }
+
+    /// Return the semaphore's configuration and state in redis, as a dict.
+    ///
+    /// The state is the number of permits available, which is None if the semaphore doesn't exist.
+    #[pyo3(text_signature = "($self)")]
+    fn snapshot<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        let (name, capacity, expiry) = (self.name.clone(), self.capacity(), self.expiry);
+        future_into_py(py, async move {
+            let available = read_state(ts).await?;
+            Python::with_gil(|py| {
+                let snapshot = PyDict::new(py);
+                snapshot.set_item("name", name)?;
+                snapshot.set_item("capacity", capacity)?;
+                snapshot.set_item("expiry", expiry)?;
+                snapshot.set_item("available", available)?;
+                Ok(snapshot.to_object(py))
+            })
+        })
+    }
+
+    /// Write the state from a snapshot to this semaphore in redis, replacing any existing state.
+    ///
+    /// Available permits are capped at this semaphore's capacity.
+    #[pyo3(text_signature = "($self, snapshot)")]
+    fn restore<'p>(&self, py: Python<'p>, snapshot: &'p PyDict) -> PyResult<&'p PyAny> {
+        let available: Option<u32> = snapshot_item(snapshot, "available")?;
+        let available = available.map(|available| available.min(self.capacity()));
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move { Ok(write_state(ts, available).await?) })
+    }
+
+    /// The max number of concurrent holders. This changes when capacity is transferred with `transfer_capacity`.
+    #[getter]
+    fn capacity(&self) -> u32 {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// How many times taking a connection is retried when redis can't be reached.
+    #[getter]
+    fn max_retries(&self) -> u32 {
+        self.retries.max_retries
+    }
+
+    /// How long to wait before the first retry, in seconds. Later retries wait exponentially longer.
+    #[getter]
+    fn retry_backoff(&self) -> f32 {
+        self.retries.backoff.as_secs_f32()
+    }
+
+    /// The number of acquisitions this instance has successfully made.
+    ///
+    /// Permits taken by `try_acquire` aren't tracked by an `Acquisition`, so they're not counted.
+    #[getter]
+    fn entered_count(&self) -> u64 {
+        self.counters.entered.load(Ordering::Relaxed)
+    }
+
+    /// The number of acquisitions this instance has successfully released.
+    ///
+    /// Permits returned with `release` aren't tracked by an `Acquisition`, so they're not counted.
+    #[getter]
+    fn exited_count(&self) -> u64 {
+        self.counters.exited.load(Ordering::Relaxed)
+    }
+
+    /// Return percentiles of the time this instance recently waited to acquire the semaphore.
+    ///
+    /// The dict has the number of samples as `count`, and `p50`, `p90` and `p99` in milliseconds,
+    /// which are None until the semaphore has been acquired.
+    #[pyo3(text_signature = "($self)")]
+    fn wait_stats<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        self.wait_samples.to_dict(py)
+    }
+
+    /// The backoff between polls for fair and counter waiters, as one of `constant`, `linear` or `exponential`.
+    #[getter]
+    fn backoff(&self) -> &'static str {
+        self.backoff.name()
+    }
+
+    /// The base interval between polls for fair and counter waiters, in seconds.
+    #[getter]
+    fn backoff_interval(&self) -> f32 {
+        self.backoff_interval_ms as f32 / 1000.0
+    }
+
+    /// Return how long a fair waiter sleeps between polls, in seconds, with `position` tickets ahead of it.
+    #[pyo3(text_signature = "($self, position)")]
+    fn backoff_delay(&self, position: u32) -> f32 {
+        self.backoff.delay(self.backoff_interval_ms, position) as f32 / 1000.0
+    }
+
+    /// Return a semaphore for the key `{name}:{key_suffix}`, with the same settings.
+    ///
+    /// The returned semaphore shares this instance's connection pools,
+    /// which makes it cheap to limit per tenant, e.g., per API key.
+    #[pyo3(text_signature = "($self, key_suffix)")]
+    fn with_key(&self, py: Python<'_>, key_suffix: &str) -> PyResult<Self> {
+        if key_suffix.is_empty() {
+            return Err(PyValueError::new_err("Key suffix must not be empty"));
+        }
+        self.with_name_and_pools(
+            py,
+            format!("{}:{}", self.name, key_suffix),
+            self.open_connection_pool.clone(),
+            self.return_connection_pool.clone(),
+        )
+    }
+
+    /// Return a handle on this semaphore that acquires against the redis server at `redis_url`, with the same settings.
+    ///
+    /// Connection pools for each url are created on first use, and shared by all handles derived
+    /// from this instance, which makes it cheap to route acquires per tenant in a sharded setup.
+    #[pyo3(text_signature = "($self, redis_url)")]
+    fn with_redis_url(&self, py: Python<'_>, redis_url: &str) -> PyResult<Self> {
+        self.with_name_and_pools(
+            py,
+            self.name.clone(),
+            self.open_pools.get(redis_url)?,
+            self.return_pools.get(redis_url)?,
+        )
     }
 
     fn __repr__(&self) -> String {
         format!("Semaphore instance for queue {}", &self.name)
     }
 }
+
+/// Move `n` free permits from `from_semaphore` to `to_semaphore` in a single script, adjusting both capacities.
+///
+/// If the source has fewer than `n` free permits, nothing is transferred, unless `partial` is set,
+/// in which case all its free permits are. Returns the number of permits transferred. Both semaphores
+/// must use the same redis, and other instances of them keep their own capacity until recreated.
+#[pyfunction]
+#[pyo3(text_signature = "(from_semaphore, to_semaphore, n, partial=False)")]
+pub(crate) fn transfer_capacity(
+    py: Python<'_>,
+    from_semaphore: Py<Semaphore>,
+    to_semaphore: Py<Semaphore>,
+    n: u32,
+    partial: Option<bool>,
+) -> PyResult<&PyAny> {
+    let partial = partial.unwrap_or(false);
+    let (source, destination) = {
+        let (source, destination) = (from_semaphore.borrow(py), to_semaphore.borrow(py));
+        if source.name == destination.name {
+            return Err(PyValueError::new_err(
+                "Can't transfer capacity from a semaphore to itself",
+            ));
+        }
+        if source.counter || destination.counter || source.no_lua || destination.no_lua {
+            return Err(PyValueError::new_err(
+                "Capacity can't be transferred to or from semaphores with counter=True or no_lua=True",
+            ));
+        }
+        if n == 0 || n > source.capacity() {
+            return Err(PyValueError::new_err(
+                "Permits must be greater than 0, and at most the source's capacity",
+            ));
+        }
+        at_most_max_capacity("Capacity", f64::from(destination.capacity()) + f64::from(n))?;
+        (ThreadState::from(&*source), ThreadState::from(&*destination))
+    };
+    let name = source.name.clone();
+    future_into_py(py, async move {
+        let transferred = transfer_permits(source, destination, n, partial)
+            .await
+            .map_err(|e| e.for_limiter(&name))?;
+        Python::with_gil(|py| {
+            let _ = from_semaphore
+                .borrow(py)
+                .capacity
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |c| {
+                    Some(c.saturating_sub(transferred))
+                });
+            to_semaphore
+                .borrow(py)
+                .capacity
+                .fetch_add(transferred, Ordering::Relaxed);
+        });
+        Ok(transferred)
+    })
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3_asyncio::tokio::future_into_py;
+
+use crate::utils::{now_millis, SLResult};
+
+/// Semaphore permits acquired in this process, and not released yet.
+///
+/// Only permits tracked by an acquisition are counted, so `try_acquire` and `Semaphore.release` don't change it.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// How often `shutdown` checks whether all permits have been released.
+const SHUTDOWN_POLL_INTERVAL_MS: u64 = 10;
+
+/// Track a semaphore permit being acquired.
+pub(crate) fn track_acquired() {
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Track a semaphore permit being released, whether or not releasing it succeeded.
+pub(crate) fn track_released() {
+    // Each release follows its acquire, but we never take the count below 0 regardless
+    let _ = IN_FLIGHT.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+}
+
+/// Wait for permits in flight to be released, for at most `timeout` milliseconds.
+/// Returns the number still held.
+async fn wait_for_releases(timeout: u64) -> SLResult<usize> {
+    let start = now_millis()?;
+    loop {
+        let in_flight = IN_FLIGHT.load(Ordering::Relaxed);
+        if in_flight == 0 || now_millis()? - start >= timeout {
+            return Ok(in_flight);
+        }
+        tokio::time::sleep(Duration::from_millis(SHUTDOWN_POLL_INTERVAL_MS)).await;
+    }
+}
+
+/// Wait up to `timeout` seconds for all semaphore permits acquired in this process to be released.
+///
+/// Permits taken with `try_acquire` aren't tracked by an acquisition, so they're not waited for.
+/// Returns the number of permits that weren't released in time.
+#[pyfunction]
+#[pyo3(text_signature = "(timeout)")]
+pub(crate) fn shutdown(py: Python<'_>, timeout: f32) -> PyResult<&PyAny> {
+    if timeout.is_nan() || timeout < 0.0 {
+        return Err(PyValueError::new_err("Timeout must not be negative"));
+    }
+    let timeout = (timeout as f64 * 1000.0) as u64;
+    future_into_py(py, async move { Ok(wait_for_releases(timeout).await?) })
+}
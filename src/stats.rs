@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+/// How many of the most recent wait times are kept per limiter.
+pub(crate) const WAIT_SAMPLES_SIZE: usize = 1024;
+
+/// Process-local, bounded sample of the most recent acquire wait times, in milliseconds.
+///
+/// Once full, the oldest sample is dropped for each new one, so percentiles
+/// reflect recent traffic rather than the lifetime of the limiter.
+#[derive(Default)]
+pub(crate) struct WaitSamples {
+    samples: Mutex<VecDeque<u64>>,
+}
+
+/// Percentiles of the sampled wait times, in milliseconds.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct WaitStats {
+    pub(crate) count: usize,
+    pub(crate) p50: u64,
+    pub(crate) p90: u64,
+    pub(crate) p99: u64,
+}
+
+impl WaitSamples {
+    pub(crate) fn record(&self, waited_ms: u64) {
+        // A poisoned lock only means another thread panicked mid-update; the samples are still usable
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        if samples.len() == WAIT_SAMPLES_SIZE {
+            samples.pop_front();
+        }
+        samples.push_back(waited_ms);
+    }
+
+    /// Compute nearest-rank percentiles of the current samples, if there are any.
+    pub(crate) fn stats(&self) -> Option<WaitStats> {
+        let mut sorted: Vec<u64> = {
+            let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+            samples.iter().copied().collect()
+        };
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        let percentile = |p: usize| sorted[(sorted.len() * p).div_ceil(100) - 1];
+        Some(WaitStats {
+            count: sorted.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        })
+    }
+
+    /// Return the percentiles of the current samples as a dict, with None percentiles when there are no samples.
+    pub(crate) fn to_dict<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        let stats = self.stats();
+        let dict = PyDict::new(py);
+        dict.set_item("count", stats.as_ref().map_or(0, |s| s.count))?;
+        dict.set_item("p50", stats.as_ref().map(|s| s.p50))?;
+        dict.set_item("p90", stats.as_ref().map(|s| s.p90))?;
+        dict.set_item("p99", stats.as_ref().map(|s| s.p99))?;
+        Ok(dict)
+    }
+}
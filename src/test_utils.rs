@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bb8_redis::bb8::ManageConnection;
+use bb8_redis::RedisConnectionManager;
+use log::{debug, warn};
+use nanoid::nanoid;
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+use redis::AsyncCommands;
+
+use crate::utils::{block_on, create_connection_manager, Credentials, SLResult};
+
+/// Delete the keys of all limiters named by the scope `id`, on each server. Returns how many were deleted.
+async fn delete_scoped_keys(managers: Vec<RedisConnectionManager>, id: String) -> SLResult<usize> {
+    let mut deleted = 0;
+    for manager in managers {
+        // Connect to redis
+        let mut connection = manager.connect().await?;
+
+        // Collect the keys first, since the scan iterator borrows the connection.
+        // Names start with the id, so this matches any prefix, and all of a limiter's keys.
+        let mut keys: Vec<String> = vec![];
+        {
+            let mut iter = connection.scan_match::<_, String>(format!("*{}:*", id)).await?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+        if !keys.is_empty() {
+            deleted += connection.del::<_, usize>(&keys).await?;
+        }
+    }
+    debug!("Deleted {} keys of test limiter scope {}", deleted, id);
+    Ok(deleted)
+}
+
+/// Hands out limiter names unique to a test, and deletes their keys when closed, or when dropped.
+///
+/// This is only built with the `test-utils` feature, for tests running against a real redis.
+#[pyclass(
+    frozen,
+    text_signature = "(redis_urls=None, verify_tls=None, username=None, password=None)"
+)]
+#[pyo3(name = "TestLimiterScope")]
+#[pyo3(module = "self_limiters")]
+pub(crate) struct TestLimiterScope {
+    /// Random id starting every name handed out, which the keys to delete are matched by
+    #[pyo3(get)]
+    id: String,
+    managers: Vec<RedisConnectionManager>,
+    closed: AtomicBool,
+}
+
+#[pymethods]
+impl TestLimiterScope {
+    /// Create a new class instance, deleting keys from each of `redis_urls`, or the default url.
+    #[new]
+    fn new(
+        redis_urls: Option<Vec<String>>,
+        verify_tls: Option<bool>,
+        username: Option<String>,
+        password: Option<String>,
+    ) -> PyResult<Self> {
+        let credentials = Credentials { username, password };
+        let verify_tls = verify_tls.unwrap_or(true);
+        let managers = match redis_urls {
+            Some(urls) => urls
+                .iter()
+                .map(|url| create_connection_manager(Some(url), verify_tls, &credentials))
+                .collect::<SLResult<_>>()?,
+            None => vec![create_connection_manager(None, verify_tls, &credentials)?],
+        };
+        Ok(Self {
+            id: nanoid!(10),
+            managers,
+            closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Return a limiter name in the scope, `{id}:{name}`, with a random name when none is passed.
+    ///
+    /// The same name always maps to the same limiter, so it can be passed to several limiters sharing state.
+    #[pyo3(text_signature = "($self, name=None)")]
+    fn name(&self, name: Option<&str>) -> String {
+        format!("{}:{}", self.id, name.map_or_else(|| nanoid!(10), String::from))
+    }
+
+    /// Delete the keys of all limiters named by the scope, blocking until they're gone.
+    ///
+    /// Returns the number of keys deleted. Closing the scope again does nothing.
+    #[pyo3(text_signature = "($self)")]
+    fn close(&self, py: Python<'_>) -> PyResult<usize> {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return Ok(0);
+        }
+        Ok(block_on(
+            py,
+            delete_scoped_keys(self.managers.clone(), self.id.clone()),
+        )?)
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    /// Close the scope, deleting its keys.
+    #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
+    fn __exit__(&self, py: Python<'_>, _a: &PyTuple) -> PyResult<()> {
+        self.close(py).map(|_| ())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Test limiter scope {}", &self.id)
+    }
+}
+
+impl Drop for TestLimiterScope {
+    fn drop(&mut self) {
+        if *self.closed.get_mut() {
+            return;
+        }
+        let future = delete_scoped_keys(self.managers.clone(), self.id.clone());
+        if let Err(e) = Python::with_gil(|py| block_on(py, future)) {
+            warn!("Failed to delete the keys of test limiter scope {}: {:?}", self.id, e);
+        }
+    }
+}
@@ -1,149 +1,1248 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Duration;
 
 use bb8_redis::bb8::Pool;
 use bb8_redis::RedisConnectionManager;
-use log::debug;
+use log::{debug, info};
 use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
-use pyo3::types::PyTuple;
+use pyo3::types::{PyDict, PyTuple};
 use pyo3::{PyAny, PyResult, Python};
 use pyo3_asyncio::tokio::future_into_py;
-use redis::Script;
+use redis::AsyncCommands;
+use tokio::time::Instant;
 
-use crate::errors::SLError;
-use crate::generated::TOKEN_BUCKET_SCRIPT;
-use crate::utils::{create_connection_manager, create_connection_pool, now_millis, SLResult, REDIS_KEY_PREFIX};
+use crate::client::RedisClient;
+use crate::errors::{map_script_error, SLError};
+use crate::metrics::measured;
+use crate::scripts::{PEEK_TOKEN_BUCKET, REPORT_TOKEN_BUCKET, RESIZE_TOKEN_BUCKET, TOKEN_BUCKET};
+use crate::stats::WaitSamples;
+use crate::utils::{
+    at_most_max_capacity, block_on, check_expiry, create_connection_manager, create_connection_pool,
+    invoke_latency_callback, limit, max_sleep_seconds, now_millis, positive_seconds, prefixed_name, read_rejections,
+    record_rejection, snapshot_item, traced, with_timeout, Credentials, PoolCache, RetryPolicy, SLResult, MAX_CAPACITY,
+    MAX_SLEEP_SECONDS,
+};
 
-struct ThreadState {
+/// A tenant's share of a bucket shared between weighted tenants.
+#[derive(Clone)]
+struct Share {
+    /// Key of the tenant's usage in the current window
+    key: String,
+    /// How many slots the tenant may be assigned per window
+    quota: u32,
+    window_ms: u64,
+}
+
+impl Share {
+    /// Work out a tenant's quota, given its share of the bucket.
+    ///
+    /// A window is the time it takes to refill an empty bucket, and a tenant may
+    /// use its share of the tokens added in that time, and always at least one.
+    fn new(slf: &TokenBucket, tenant: &str, share: f32) -> Self {
+        let intervals = (slf.capacity() as f64 / slf.refill_amount as f64).ceil().max(1.0);
+        let tokens_per_window = intervals * slf.refill_amount as f64;
+        Self {
+            key: format!("{}-tenant:{}", slf.name, tenant),
+            quota: ((tokens_per_window * share as f64) as u32).max(1),
+            window_ms: (intervals * slf.refill_frequency as f64 * 1000.0).ceil() as u64,
+        }
+    }
+}
+
+/// Tokens leased from redis in a single call, to serve acquires locally.
+#[derive(Default)]
+struct Lease {
+    /// The slot the tokens were assigned, as a millisecond timestamp
+    slot: u64,
+    remaining: u32,
+}
+
+impl Lease {
+    /// Take a token, returning the slot it was assigned. Tokens are only
+    /// served until `ttl_ms` after their slot, and dropped after that.
+    fn take(&mut self, now: u64, ttl_ms: u64) -> Option<u64> {
+        if now >= self.slot + ttl_ms {
+            self.remaining = 0;
+        }
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.slot)
+    }
+
+    /// Add leased tokens. When tokens from an earlier lease are left, they're
+    /// served from the later slot, which never lets them through early.
+    fn add(&mut self, slot: u64, tokens: u32) {
+        self.slot = self.slot.max(slot);
+        self.remaining += tokens;
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ThreadState {
     capacity: u32,
     frequency: f32,
-    amount: u32,
+    amount: f32,
     max_sleep: f32,
+    max_backlog: f32,
+    initial_tokens: u32,
+    cost: f64,
+    expiry: Option<usize>,
     connection_pool: Pool<RedisConnectionManager>,
     name: String,
+    quiet: bool,
+    count_rejections: bool,
+    response_timeout: Option<Duration>,
+    wait_samples: Arc<WaitSamples>,
+    share: Option<Share>,
+    /// Key to record the assigned slot in, and for how many milliseconds
+    idempotency: Option<(String, u64)>,
+    /// Key of the factor to scale the refill rate by, for adaptive buckets
+    rate_key: Option<String>,
+    dry_run: bool,
+    /// Millisecond timestamp to schedule from, instead of the redis server time
+    now: Option<u64>,
+    /// Tokens to lease per call to redis, and the lease to serve acquires from
+    lease: Option<(u32, Arc<Mutex<Lease>>)>,
+    /// Callback to invoke while sleeping, and how often
+    heartbeat: Option<(PyObject, Duration)>,
+    latency_callback: Option<PyObject>,
+    retries: RetryPolicy,
 }
 
 impl ThreadState {
-    fn from(slf: &TokenBucket) -> Self {
+    pub(crate) fn from(slf: &TokenBucket) -> Self {
         Self {
-            capacity: slf.capacity,
+            capacity: slf.capacity(),
             frequency: slf.refill_frequency,
             amount: slf.refill_amount,
             max_sleep: slf.max_sleep,
+            max_backlog: slf.max_backlog,
+            initial_tokens: slf.initial_tokens.min(slf.capacity()),
+            cost: slf.cost,
+            expiry: slf.expiry,
             connection_pool: slf.connection_pool.clone(),
             name: slf.name.clone(),
+            quiet: slf.quiet,
+            count_rejections: slf.count_rejections,
+            response_timeout: slf.response_timeout,
+            wait_samples: slf.wait_samples.clone(),
+            share: slf
+                .share
+                .as_ref()
+                .map(|(tenant, share)| Share::new(slf, tenant, *share)),
+            idempotency: slf.idempotency.as_ref().map(|(key, window)| {
+                (
+                    format!("{}-idempotency:{}", slf.name, key),
+                    (*window as f64 * 1000.0).ceil() as u64,
+                )
+            }),
+            rate_key: slf.adaptive.then(|| format!("{}-rate", slf.name)),
+            dry_run: slf.dry_run,
+            now: None,
+            // Leases are only used for plain acquires, where each consumes a single token
+            lease: slf
+                .local_lease
+                .filter(|_| slf.cost == 1.0 && slf.share.is_none() && slf.idempotency.is_none() && !slf.dry_run)
+                .map(|size| (size.min(slf.capacity()), slf.lease.clone())),
+            heartbeat: slf
+                .heartbeat
+                .clone()
+                .map(|heartbeat| (heartbeat, Duration::from_secs_f32(slf.heartbeat_interval))),
+            latency_callback: slf.latency_callback.clone(),
+            retries: slf.retries,
+        }
+    }
+
+    /// The state to schedule dry runs with, which consume from `{name}-dry-run` rather than the
+    /// bucket itself, so that what would have been throttled reflects the load of the dry runs.
+    fn dry_run_shadow(&self) -> Self {
+        let shadow = format!("{}-dry-run", self.name);
+        Self {
+            share: self.share.as_ref().map(|share| Share {
+                key: share.key.replacen(&self.name, &shadow, 1),
+                ..*share
+            }),
+            idempotency: self
+                .idempotency
+                .as_ref()
+                .map(|(key, window_ms)| (key.replacen(&self.name, &shadow, 1), *window_ms)),
+            name: shadow,
+            ..self.clone()
         }
     }
 }
 
-async fn schedule_and_sleep(ts: ThreadState) -> SLResult<()> {
+/// Schedule a slot and sleep until it's our turn.
+///
+/// When the bucket is shared between tenants and ours is over its share,
+/// we sleep until the next window and try again.
+///
+/// Returns whether we had to sleep at all, which lets clients detect when they're at the rate limit.
+pub(crate) async fn schedule_and_sleep(ts: ThreadState) -> SLResult<bool> {
+    let name = ts.name.clone();
+    measured(&name, traced("token_bucket", &name, wait_for_slot(ts))).await
+}
+
+/// Schedule a slot and sleep until it's our turn, as described for `schedule_and_sleep`, without a span.
+async fn wait_for_slot(ts: ThreadState) -> SLResult<bool> {
+    if ts.dry_run {
+        return schedule_dry_run(ts).await;
+    }
+    let mut slept = Duration::from_millis(0);
+    let mut scheduling = 0;
+    // Only time calls to redis when someone's listening
+    let mut latency = ts.latency_callback.as_ref().map(|_| Duration::ZERO);
+    loop {
+        let before = now_millis()?;
+        let timer = latency.map(|_| Instant::now());
+        let (slot, granted) = match schedule_leased(&ts).await {
+            Err(SLError::BacklogExceeded(e)) => {
+                if ts.count_rejections {
+                    record_rejection(&ts.connection_pool, &ts.name).await;
+                }
+                return Err(SLError::BacklogExceeded(e));
+            }
+            result => result?,
+        };
+        scheduling += now_millis()? - before;
+        if let (Some(latency), Some(timer)) = (&mut latency, timer) {
+            *latency += timer.elapsed();
+        }
+
+        let now = now_millis()?;
+        let sleep_duration = {
+            // This might happen at very low refill frequencies.
+            // Current handling isn't robust enough to ensure
+            // exactly uniform traffic when this happens. Might be
+            // something worth looking at more in the future, if needed.
+            if slot <= now {
+                Duration::from_millis(0)
+            } else {
+                Duration::from_millis(slot - now)
+            }
+        };
+
+        if ts.max_sleep > 0.0 && slept + sleep_duration > Duration::from_millis((ts.max_sleep as f64 * 1000.0) as u64) {
+            if ts.count_rejections {
+                record_rejection(&ts.connection_pool, &ts.name).await;
+            }
+            // Break down where the time went, to tell a saturated bucket from a slow connection
+            return Err(SLError::MaxSleepExceeded(format!(
+                "Received wake up time in {} seconds, which is \
+                greater or equal to the specified max sleep of {} seconds. \
+                This is {:.3}s until the assigned slot, after {:.3}s waiting for the tenant's share, \
+                and {:.3}s waiting for redis",
+                (slept + sleep_duration).as_secs(),
+                ts.max_sleep,
+                sleep_duration.as_secs_f64(),
+                slept.as_secs_f64(),
+                scheduling as f64 / 1000.0,
+            )));
+        }
+
+        if !ts.quiet {
+            if granted {
+                debug!("Retrieved slot. Sleeping for {}.", sleep_duration.as_secs_f32());
+            } else {
+                debug!(
+                    "Tenant is over its share of the bucket. Retrying in {}.",
+                    sleep_duration.as_secs_f32()
+                );
+            }
+        }
+        sleep_with_heartbeat(&ts, sleep_duration, slept).await;
+        slept += sleep_duration;
+
+        if granted {
+            ts.wait_samples.record(slept.as_millis() as u64);
+            if let (Some(callback), Some(latency)) = (&ts.latency_callback, latency) {
+                invoke_latency_callback(callback, latency);
+            }
+            return Ok(!slept.is_zero());
+        }
+    }
+}
+
+/// Schedule a slot in the dry run bucket, without sleeping, and log what an acquire would have done.
+///
+/// Returns whether the acquire would have been throttled, by sleeping or being rejected.
+async fn schedule_dry_run(ts: ThreadState) -> SLResult<bool> {
+    let (slot, granted) = match schedule(&ts.dry_run_shadow(), ts.cost).await {
+        Err(SLError::BacklogExceeded(e)) => {
+            info!(
+                "Dry run: Token bucket {} would have rejected the acquire. {}",
+                ts.name, e
+            );
+            return Ok(true);
+        }
+        result => result?,
+    };
+    let sleep_duration = Duration::from_millis(slot.saturating_sub(now_millis()?));
+    if sleep_duration.is_zero() {
+        return Ok(false);
+    }
+    // A tenant over its share would retry in the next window, which may be further ahead still
+    let waiting_for = if granted {
+        "its slot"
+    } else {
+        "the tenant's next window"
+    };
+    if ts.max_sleep > 0.0 && sleep_duration > Duration::from_millis((ts.max_sleep as f64 * 1000.0) as u64) {
+        info!(
+            "Dry run: Token bucket {} would have rejected the acquire, since waiting {:.3}s for {} \
+            exceeds the max sleep of {} seconds",
+            ts.name,
+            sleep_duration.as_secs_f64(),
+            waiting_for,
+            ts.max_sleep
+        );
+    } else {
+        info!(
+            "Dry run: Token bucket {} would have slept for {:.3}s, waiting for {}",
+            ts.name,
+            sleep_duration.as_secs_f64(),
+            waiting_for
+        );
+    }
+    Ok(true)
+}
+
+/// Sleep for `duration`, invoking the heartbeat every interval, if one is set.
+///
+/// The heartbeat is called with the seconds slept so far, including the `slept` before this
+/// sleep, and the seconds left of it. Exceptions raised by the heartbeat are logged and swallowed.
+async fn sleep_with_heartbeat(ts: &ThreadState, duration: Duration, slept: Duration) {
+    let (heartbeat, interval) = match &ts.heartbeat {
+        Some(heartbeat) => heartbeat,
+        None => return tokio::time::sleep(duration).await,
+    };
+    // Sleep until fixed deadlines, so time spent in the heartbeat doesn't add up
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut next_beat = start + *interval;
+    while next_beat < deadline {
+        tokio::time::sleep_until(next_beat).await;
+        let elapsed = next_beat - start;
+        Python::with_gil(|py| {
+            let args = ((slept + elapsed).as_secs_f64(), (duration - elapsed).as_secs_f64());
+            if let Err(e) = heartbeat.call1(py, args) {
+                debug!("Heartbeat raised an exception: {}", e);
+            }
+        });
+        next_beat += *interval;
+    }
+    tokio::time::sleep_until(deadline).await;
+}
+
+/// Schedule a slot as if the current time were `now`, without sleeping. Returns the assigned slot.
+///
+/// When the bucket is shared between tenants and ours is over its share,
+/// we schedule again from the next window, rather than wait for it.
+async fn schedule_from(mut ts: ThreadState, now: u64) -> SLResult<u64> {
+    ts.now = Some(now);
+    loop {
+        let (slot, granted) = schedule(&ts, ts.cost).await?;
+        if granted {
+            return Ok(slot);
+        }
+        ts.now = Some(slot);
+    }
+}
+
+/// Schedule a slot, serving it from the local lease when the bucket has one.
+///
+/// When the lease is used up, a new one is fetched by consuming all its tokens in one slot.
+/// The lock isn't held while we call redis, so concurrent acquires may each fetch a lease,
+/// in which case the tokens are pooled.
+async fn schedule_leased(ts: &ThreadState) -> SLResult<(u64, bool)> {
+    let (size, lease) = match &ts.lease {
+        Some(lease) => lease,
+        None => return schedule(ts, ts.cost).await,
+    };
+    let ttl_ms = (ts.frequency as f64 * 1000.0).ceil() as u64;
+    let leased = lease
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .take(now_millis()?, ttl_ms);
+    if let Some(slot) = leased {
+        return Ok((slot, true));
+    }
+    let (slot, granted) = schedule(ts, *size as f64).await?;
+    lease.lock().unwrap_or_else(|e| e.into_inner()).add(slot, size - 1);
+    Ok((slot, granted))
+}
+
+/// Run the token bucket script, consuming `cost` tokens. Returns the slot assigned and
+/// whether it was granted, or the time to retry at when our tenant is over its share.
+///
+/// Raises if the slot is further ahead than the max backlog.
+async fn schedule(ts: &ThreadState, cost: f64) -> SLResult<(u64, bool)> {
     // Connect to redis
-    let mut connection = ts.connection_pool.get().await?;
+    let mut connection = ts.retries.get(&ts.connection_pool).await?;
 
     // Retrieve slot
-    let slot: u64 = Script::new(TOKEN_BUCKET_SCRIPT)
+    let mut invocation = TOKEN_BUCKET.get().prepare_invoke();
+    invocation
         .key(&ts.name)
         .arg(ts.capacity)
         .arg(ts.frequency * 1000.0) // in ms
         .arg(ts.amount)
-        .invoke_async(&mut *connection)
-        .await?;
-
-    let now = now_millis()?;
-    let sleep_duration = {
-        // This might happen at very low refill frequencies.
-        // Current handling isn't robust enough to ensure
-        // exactly uniform traffic when this happens. Might be
-        // something worth looking at more in the future, if needed.
-        if slot <= now {
-            Duration::from_millis(0)
-        } else {
-            Duration::from_millis(slot - now)
-        }
-    };
-
-    if ts.max_sleep > 0.0 && sleep_duration > Duration::from_secs_f32(ts.max_sleep) {
-        return Err(SLError::MaxSleepExceeded(format!(
-            "Received wake up time in {} seconds, which is \
-            greater or equal to the specified max sleep of {} seconds",
-            sleep_duration.as_secs(),
-            ts.max_sleep
+        .arg(ts.initial_tokens)
+        .arg(cost)
+        .arg(ts.now.unwrap_or_else(redis_time_override))
+        .arg((ts.max_backlog as f64 * 1000.0) as u64) // in ms
+        .arg(ts.idempotency.as_ref().map_or(0, |(_, window_ms)| *window_ms))
+        .arg(ts.expiry.unwrap_or(0)) // 0 means the state never expires
+        .arg(ts.rate_key.is_some() as u8);
+    if let Some((key, _)) = &ts.idempotency {
+        invocation.key(key);
+    }
+    if let Some(rate_key) = &ts.rate_key {
+        invocation.key(rate_key);
+    }
+    if let Some(share) = &ts.share {
+        invocation.key(&share.key).arg(share.quota).arg(share.window_ms);
+    }
+    let (slot, status): (u64, u8) = with_timeout(ts.response_timeout, invocation.invoke_async(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "token_bucket"))?;
+    if status == 2 {
+        return Err(SLError::BacklogExceeded(format!(
+            "The next slot is {:.3}s away, which exceeds the max backlog of {} seconds",
+            slot.saturating_sub(now_millis()?) as f64 / 1000.0,
+            ts.max_backlog
         )));
     }
+    Ok((slot, status == 1))
+}
+
+/// Redis server time to use in the token bucket script instead of `TIME`, in milliseconds, where 0 means none.
+///
+/// This only exists in debug builds, so tests can assert exact slots.
+#[cfg(debug_assertions)]
+static REDIS_TIME_OVERRIDE: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Override the redis server time seen by the token bucket script, or clear the override with None.
+#[cfg(debug_assertions)]
+#[pyfunction]
+#[pyo3(name = "_set_redis_time")]
+#[pyo3(text_signature = "(millis)")]
+pub(crate) fn set_redis_time(millis: Option<u64>) {
+    REDIS_TIME_OVERRIDE.store(millis.unwrap_or(0), Ordering::Relaxed);
+}
+
+#[cfg(debug_assertions)]
+fn redis_time_override() -> u64 {
+    REDIS_TIME_OVERRIDE.load(Ordering::Relaxed)
+}
+
+#[cfg(not(debug_assertions))]
+fn redis_time_override() -> u64 {
+    0
+}
+
+/// Cap the tokens left in the bucket state at the capacity, keeping the slots already handed out.
+async fn resize_bucket(ts: ThreadState) -> SLResult<()> {
+    let mut connection = ts.connection_pool.get().await?;
+    let mut invocation = RESIZE_TOKEN_BUCKET.get().prepare_invoke();
+    invocation.key(&ts.name).arg(ts.capacity).arg(ts.expiry.unwrap_or(0)); // 0 means the state never expires
+    with_timeout(ts.response_timeout, invocation.invoke_async::<_, i64>(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "resize_token_bucket"))?;
+    if !ts.quiet {
+        debug!("Resized token bucket to a capacity of {}", ts.capacity);
+    }
+    Ok(())
+}
+
+/// Read the next slot tokens are available at, and how many, without consuming any.
+async fn peek_bucket(ts: ThreadState) -> SLResult<(u64, f64)> {
+    let mut connection = ts.connection_pool.get().await?;
+    let mut invocation = PEEK_TOKEN_BUCKET.get().key(&ts.name);
+    if let Some(rate_key) = &ts.rate_key {
+        invocation.key(rate_key);
+    }
+    invocation
+        .arg(ts.capacity)
+        .arg(ts.frequency * 1000.0) // in ms
+        .arg(ts.amount)
+        .arg(ts.initial_tokens);
+    let (slot, tokens): (u64, String) = with_timeout(ts.response_timeout, invocation.invoke_async(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "peek_token_bucket"))?;
+    let tokens = tokens
+        .parse()
+        .map_err(|_| SLError::Redis(format!("Failed to parse the tokens available '{}'", tokens)))?;
+    Ok((slot, tokens))
+}
+
+/// Adjust the rate of an adaptive bucket to the outcome of a downstream call. Returns the new factor.
+async fn report_outcome(ts: ThreadState, overloaded: bool, cooldown: Duration) -> SLResult<f64> {
+    let rate_key = match &ts.rate_key {
+        Some(rate_key) => rate_key,
+        None => return Err(SLError::RuntimeError("The bucket is not adaptive".to_string())),
+    };
+    let mut connection = ts.connection_pool.get().await?;
+    let mut invocation = REPORT_TOKEN_BUCKET.get().key(rate_key);
+    invocation
+        .arg(overloaded as u8)
+        .arg(cooldown.as_millis() as u64)
+        .arg(ADAPTIVE_DECREASE)
+        .arg(ADAPTIVE_INCREASE)
+        .arg(ADAPTIVE_MIN_FACTOR)
+        .arg(ts.expiry.unwrap_or(0)); // 0 means the state never expires
+    let factor: String = with_timeout(ts.response_timeout, invocation.invoke_async(&mut *connection))
+        .await
+        .map_err(|e| map_script_error(e, "report_token_bucket"))?;
+    factor
+        .parse()
+        .map_err(|_| SLError::Redis(format!("Failed to parse adaptive rate factor '{}'", factor)))
+}
+
+/// What an adaptive bucket's rate is multiplied by when a downstream reports overload.
+const ADAPTIVE_DECREASE: f64 = 0.5;
+
+/// How much is added to an adaptive bucket's rate factor for each ok outcome, after the cooldown.
+const ADAPTIVE_INCREASE: f64 = 0.1;
 
-    debug!("Retrieved slot. Sleeping for {}.", sleep_duration.as_secs_f32());
-    tokio::time::sleep(sleep_duration).await;
+/// The lowest factor an adaptive bucket's rate is scaled by, so it always recovers eventually.
+const ADAPTIVE_MIN_FACTOR: f64 = 0.01;
 
+/// How long bucket state is kept without use by default, in seconds.
+const DEFAULT_EXPIRY_SECONDS: usize = 30;
+
+/// How many connections a bucket's connection pool holds by default.
+const DEFAULT_POOL_SIZE: u32 = 30;
+
+/// How often the heartbeat is invoked while sleeping by default, in seconds.
+const DEFAULT_HEARTBEAT_INTERVAL_SECONDS: f32 = 5.0;
+
+/// Read the bucket state, as the last slot assigned and the tokens left for it, if any.
+async fn read_state(ts: ThreadState) -> SLResult<Option<(u64, f64)>> {
+    let mut connection = ts.connection_pool.get().await?;
+    let data: Option<String> = with_timeout(ts.response_timeout, connection.get(&ts.name)).await?;
+    match data {
+        Some(data) => data
+            .split_once(' ')
+            .and_then(|(slot, tokens)| Some((slot.parse().ok()?, tokens.parse().ok()?)))
+            .map(Some)
+            .ok_or_else(|| SLError::Redis(format!("Failed to parse token bucket state '{}'", data))),
+        None => Ok(None),
+    }
+}
+
+/// Write the bucket state, in the same format and with the same expiry as the token bucket script.
+async fn write_state(ts: ThreadState, state: Option<(u64, f64)>) -> SLResult<()> {
+    let mut connection = ts.connection_pool.get().await?;
+    match state {
+        Some((slot, tokens)) => {
+            let data = format!("{} {}", slot, tokens);
+            match ts.expiry {
+                Some(expiry) => {
+                    with_timeout(
+                        ts.response_timeout,
+                        connection.set_ex::<_, _, ()>(&ts.name, data, expiry),
+                    )
+                    .await?
+                }
+                None => with_timeout(ts.response_timeout, connection.set::<_, _, ()>(&ts.name, data)).await?,
+            }
+        }
+        None => with_timeout(ts.response_timeout, connection.del::<_, ()>(&ts.name)).await?,
+    };
     Ok(())
 }
 
 /// Async context manager useful for controlling client traffic
 /// in situations where you need to limit traffic to `n` requests per `m` unit of time.
 /// For example, when you can only send 1 request per minute.
-#[pyclass(frozen)]
+#[pyclass(
+    frozen,
+    text_signature = "(name, capacity, refill_frequency, refill_amount, redis_url=None, max_sleep=None, \
+    connection_pool_size=None, eager_connect=None, quiet=None, initial_tokens=None, connect_timeout=None, \
+    response_timeout=None, count_rejections=None, max_backlog=None, expiry=30, local_lease=None, heartbeat=None, \
+    heartbeat_interval=None, latency_callback=None, adaptive=None, dry_run=None, verify_tls=None, prefix=None, \
+    max_retries=None, retry_backoff=None, username=None, password=None)"
+)]
 #[pyo3(name = "TokenBucket")]
 #[pyo3(module = "self_limiters")]
 pub(crate) struct TokenBucket {
-    #[pyo3(get)]
-    capacity: u32,
+    capacity: AtomicU32,
     #[pyo3(get)]
     refill_frequency: f32,
     #[pyo3(get)]
-    refill_amount: u32,
+    refill_amount: f32,
     #[pyo3(get)]
     name: String,
+    #[pyo3(get)]
+    quiet: bool,
+    #[pyo3(get)]
+    initial_tokens: u32,
+    #[pyo3(get)]
+    count_rejections: bool,
+    #[pyo3(get)]
+    cost: f64,
+    #[pyo3(get)]
+    max_backlog: f32,
+    #[pyo3(get)]
+    expiry: Option<usize>,
+    #[pyo3(get)]
+    local_lease: Option<u32>,
+    lease: Arc<Mutex<Lease>>,
+    /// Leases of the buckets handles derived from this instance use, by `lease_key`. Handles on the same
+    /// bucket share its lease, so they don't each lease tokens. Leases no handle uses are dropped.
+    leases: Arc<Mutex<HashMap<String, Weak<Mutex<Lease>>>>>,
+    heartbeat: Option<PyObject>,
+    #[pyo3(get)]
+    heartbeat_interval: f32,
+    latency_callback: Option<PyObject>,
+    #[pyo3(get)]
+    adaptive: bool,
+    #[pyo3(get)]
+    dry_run: bool,
+    #[pyo3(get)]
+    verify_tls: bool,
+    retries: RetryPolicy,
     max_sleep: f32,
+    response_timeout: Option<Duration>,
+    wait_samples: Arc<WaitSamples>,
+    share: Option<(String, f32)>,
+    idempotency: Option<(String, f32)>,
     connection_pool: Pool<RedisConnectionManager>,
+    /// The server this handle schedules against, when returned by `with_redis_url`
+    redis_url: Option<String>,
+    /// Pools for other redis servers, for handles returned by `with_redis_url`
+    pools: Arc<PoolCache>,
+}
+
+/// Key of the lease for the bucket `name`, on the redis server at `redis_url`, if not the instance's own.
+fn lease_key(redis_url: Option<&str>, name: &str) -> String {
+    match redis_url {
+        Some(redis_url) => format!("{} {}", redis_url, name),
+        None => name.to_string(),
+    }
+}
+
+impl TokenBucket {
+    /// Create a token bucket using an existing connection pool.
+    ///
+    /// The bucket starts out full, so an initial burst of up to `capacity` is let through right away.
+    pub(crate) fn with_pool(
+        name: String,
+        capacity: u32,
+        refill_frequency: f32,
+        refill_amount: f32,
+        max_sleep: f32,
+        quiet: bool,
+        connection_pool: Pool<RedisConnectionManager>,
+    ) -> Self {
+        let lease = Arc::new(Mutex::new(Lease::default()));
+        let leases = HashMap::from([(lease_key(None, &name), Arc::downgrade(&lease))]);
+        Self {
+            capacity: AtomicU32::new(capacity),
+            refill_amount,
+            refill_frequency,
+            max_sleep,
+            initial_tokens: capacity,
+            count_rejections: false,
+            cost: 1.0,
+            max_backlog: 0.0,
+            expiry: Some(DEFAULT_EXPIRY_SECONDS),
+            local_lease: None,
+            lease,
+            leases: Arc::new(Mutex::new(leases)),
+            heartbeat: None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL_SECONDS,
+            latency_callback: None,
+            adaptive: false,
+            dry_run: false,
+            verify_tls: true,
+            retries: RetryPolicy::default(),
+            response_timeout: None,
+            wait_samples: Arc::new(WaitSamples::default()),
+            share: None,
+            idempotency: None,
+            name,
+            quiet,
+            connection_pool,
+            redis_url: None,
+            pools: Arc::new(PoolCache::new(DEFAULT_POOL_SIZE, None, true, Credentials::default())),
+        }
+    }
+
+    /// Return the lease for the bucket `name` on the server at `redis_url`, shared with other handles on it.
+    fn shared_lease(&self, redis_url: Option<&str>, name: &str) -> Arc<Mutex<Lease>> {
+        let mut leases = self.leases.lock().unwrap_or_else(|e| e.into_inner());
+        leases.retain(|_, lease| lease.strong_count() > 0);
+        let key = lease_key(redis_url, name);
+        match leases.get(&key).and_then(Weak::upgrade) {
+            Some(lease) => lease,
+            None => {
+                let lease = Arc::new(Mutex::new(Lease::default()));
+                leases.insert(key, Arc::downgrade(&lease));
+                lease
+            }
+        }
+    }
+
+    /// Create a bucket for `name` with the same settings, sharing this instance's connection pool.
+    fn with_name(&self, name: String) -> Self {
+        Self {
+            initial_tokens: self.initial_tokens,
+            count_rejections: self.count_rejections,
+            cost: self.cost,
+            max_backlog: self.max_backlog,
+            expiry: self.expiry,
+            local_lease: self.local_lease,
+            lease: self.shared_lease(self.redis_url.as_deref(), &name),
+            leases: self.leases.clone(),
+            heartbeat: self.heartbeat.clone(),
+            heartbeat_interval: self.heartbeat_interval,
+            latency_callback: self.latency_callback.clone(),
+            adaptive: self.adaptive,
+            dry_run: self.dry_run,
+            verify_tls: self.verify_tls,
+            retries: self.retries,
+            response_timeout: self.response_timeout,
+            share: self.share.clone(),
+            idempotency: self.idempotency.clone(),
+            redis_url: self.redis_url.clone(),
+            pools: self.pools.clone(),
+            ..Self::with_pool(
+                name,
+                self.capacity(),
+                self.refill_frequency,
+                self.refill_amount,
+                self.max_sleep,
+                self.quiet,
+                self.connection_pool.clone(),
+            )
+        }
+    }
+}
+
+/// Check the settings `new` and `from_client` both take, returning the prefixed name and the max sleep.
+fn check_settings(
+    name: &str,
+    capacity: u32,
+    refill_frequency: f32,
+    refill_amount: f32,
+    max_sleep: Option<f32>,
+    prefix: Option<&str>,
+) -> PyResult<(String, f32)> {
+    at_most_max_capacity("Capacity", capacity.into())?;
+    positive_seconds("Refill frequency", refill_frequency)?;
+    if refill_amount.is_nan() || refill_amount <= 0.0 {
+        return Err(PyValueError::new_err("Refill amount must be greater than 0"));
+    }
+    at_most_max_capacity("Refill amount", refill_amount.into())?;
+    Ok((prefixed_name(prefix, name)?, max_sleep_seconds(max_sleep)?))
 }
 
 #[pymethods]
 impl TokenBucket {
     /// Create a new class instance.
+    ///
+    /// The expiry defaults to 30 seconds. Passing `None` explicitly means the bucket state never expires.
     #[new]
+    #[args(expiry = "30")]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         name: String,
         capacity: u32,
         refill_frequency: f32,
-        refill_amount: u32,
+        refill_amount: f32,
         redis_url: Option<&str>,
         max_sleep: Option<f32>,
         connection_pool_size: Option<u32>,
+        eager_connect: Option<bool>,
+        quiet: Option<bool>,
+        initial_tokens: Option<u32>,
+        connect_timeout: Option<f32>,
+        response_timeout: Option<f32>,
+        count_rejections: Option<bool>,
+        max_backlog: Option<f32>,
+        expiry: Option<usize>,
+        local_lease: Option<u32>,
+        heartbeat: Option<PyObject>,
+        heartbeat_interval: Option<f32>,
+        latency_callback: Option<PyObject>,
+        adaptive: Option<bool>,
+        dry_run: Option<bool>,
+        verify_tls: Option<bool>,
+        prefix: Option<&str>,
+        max_retries: Option<u32>,
+        retry_backoff: Option<f32>,
+        username: Option<String>,
+        password: Option<String>,
     ) -> PyResult<Self> {
         debug!("Creating new TokenBucket instance");
 
-        if refill_frequency <= 0.0 {
-            return Err(PyValueError::new_err("Refill frequency must be greater than 0"));
+        let (name, max_sleep) = check_settings(&name, capacity, refill_frequency, refill_amount, max_sleep, prefix)?;
+        let initial_tokens = initial_tokens.unwrap_or(capacity);
+        if initial_tokens > capacity {
+            return Err(PyValueError::new_err("Initial tokens must not exceed the capacity"));
+        }
+        let connect_timeout = connect_timeout
+            .map(|t| positive_seconds("Connect timeout", t))
+            .transpose()?;
+        let response_timeout = response_timeout
+            .map(|t| positive_seconds("Response timeout", t))
+            .transpose()?;
+        if max_backlog.map_or(false, |b| b < 0.0) {
+            return Err(PyValueError::new_err("Max backlog must not be negative"));
         }
+        check_expiry(expiry)?;
+        let retries = RetryPolicy::new(max_retries, retry_backoff)?;
+        if local_lease.map_or(false, |lease| lease == 0 || lease > capacity) {
+            return Err(PyValueError::new_err(
+                "Local lease must be greater than 0, and at most the capacity",
+            ));
+        }
+        // Checked here, since the interval is converted to a duration on every acquire
+        let heartbeat_interval = heartbeat_interval.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECONDS);
+        positive_seconds("Heartbeat interval", heartbeat_interval)?;
+
         // Create redis connection manager
-        let manager = create_connection_manager(redis_url)?;
+        let verify_tls = verify_tls.unwrap_or(true);
+        let credentials = Credentials { username, password };
+        let manager = create_connection_manager(redis_url, verify_tls, &credentials)?;
 
         // Create connection pool
-        let pool = create_connection_pool(manager, connection_pool_size.unwrap_or(30))?;
+        let connection_pool_size = connection_pool_size.unwrap_or(DEFAULT_POOL_SIZE);
+        let pool = create_connection_pool(
+            manager,
+            connection_pool_size,
+            eager_connect.unwrap_or(false),
+            connect_timeout,
+        )?;
 
         Ok(Self {
-            capacity,
-            refill_amount,
-            refill_frequency,
-            max_sleep: max_sleep.unwrap_or(0.0),
-            name: format!("{}{}", REDIS_KEY_PREFIX, name),
-            connection_pool: pool,
+            initial_tokens,
+            count_rejections: count_rejections.unwrap_or(false),
+            max_backlog: max_backlog.unwrap_or(0.0),
+            expiry,
+            local_lease,
+            heartbeat,
+            heartbeat_interval,
+            latency_callback,
+            adaptive: adaptive.unwrap_or(false),
+            dry_run: dry_run.unwrap_or(false),
+            verify_tls,
+            retries,
+            response_timeout,
+            pools: Arc::new(PoolCache::new(
+                connection_pool_size,
+                connect_timeout,
+                verify_tls,
+                credentials.clone(),
+            )),
+            ..Self::with_pool(
+                name,
+                capacity,
+                refill_frequency,
+                refill_amount,
+                max_sleep,
+                quiet.unwrap_or(false),
+                pool,
+            )
+        })
+    }
+
+    /// Create a bucket that connects through a shared `client`, rather than opening its own connections.
+    ///
+    /// The client's timeouts and retry settings apply. Other settings not taken here are left at their defaults.
+    #[staticmethod]
+    #[allow(clippy::too_many_arguments)]
+    #[pyo3(
+        text_signature = "(client, name, capacity, refill_frequency, refill_amount, max_sleep=None, quiet=None, \
+        prefix=None)"
+    )]
+    fn from_client(
+        client: PyRef<'_, RedisClient>,
+        name: String,
+        capacity: u32,
+        refill_frequency: f32,
+        refill_amount: f32,
+        max_sleep: Option<f32>,
+        quiet: Option<bool>,
+        prefix: Option<&str>,
+    ) -> PyResult<Self> {
+        let (name, max_sleep) = check_settings(&name, capacity, refill_frequency, refill_amount, max_sleep, prefix)?;
+
+        Ok(Self {
+            verify_tls: client.verify_tls,
+            retries: client.retries,
+            response_timeout: client.response_timeout,
+            pools: client.open_pools.clone(),
+            ..Self::with_pool(
+                name,
+                capacity,
+                refill_frequency,
+                refill_amount,
+                max_sleep,
+                quiet.unwrap_or(false),
+                client.open_pool.clone(),
+            )
         })
     }
 
     /// Spawn a scheduler thread to schedule wake-up times for nodes,
     /// and let the main thread wait for assignment of wake-up time
     /// then sleep until ready.
+    ///
+    /// Returns whether we had to sleep.
+    #[pyo3(text_signature = "($self)")]
     fn __aenter__<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
         let ts = ThreadState::from(self);
-        future_into_py(py, async { Ok(schedule_and_sleep(ts).await?) })
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            schedule_and_sleep(ts).await.map_err(|e| e.for_limiter(&name))
+        })
+    }
+
+    /// Sleep until it's our turn from synchronous code, e.g., in a thread, blocking the thread meanwhile.
+    ///
+    /// This runs the same schedule as `__aenter__`, on the shared runtime, and respects `max_sleep`
+    /// the same way. The GIL is released while blocking. Returns whether we had to sleep.
+    #[pyo3(text_signature = "($self)")]
+    fn __enter__(&self, py: Python<'_>) -> PyResult<bool> {
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        block_on(py, schedule_and_sleep(ts)).map_err(|e| e.for_limiter(&name))
+    }
+
+    /// Do nothing on exit.
+    #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
+    fn __exit__(&self, _a: &PyTuple) {}
+
+    /// Sleep until the bucket holds `n` tokens in a single slot, then consume them all at once.
+    ///
+    /// Unlike entering the bucket `n` times, which hands out tokens across consecutive slots,
+    /// this waits for enough tokens to accumulate for a burst. Returns whether we had to sleep.
+    #[pyo3(text_signature = "($self, n)")]
+    fn wait_for_burst<'p>(&self, py: Python<'p>, n: u32) -> PyResult<&'p PyAny> {
+        if n == 0 || n > self.capacity() {
+            return Err(PyValueError::new_err(
+                "Burst size must be greater than 0, and at most the capacity",
+            ));
+        }
+        let ts = ThreadState {
+            cost: n as f64,
+            lease: None,
+            ..ThreadState::from(self)
+        };
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            schedule_and_sleep(ts).await.map_err(|e| e.for_limiter(&name))
+        })
+    }
+
+    /// Schedule a slot as if the current time were `now`, a unix timestamp in seconds, without sleeping.
+    ///
+    /// Returns the assigned slot, as a unix timestamp in seconds. This consumes tokens like any
+    /// other acquire, so scheduling a batch of work ahead of time is best done on a dedicated bucket.
+    #[pyo3(text_signature = "($self, now)")]
+    fn schedule_at<'p>(&self, py: Python<'p>, now: f64) -> PyResult<&'p PyAny> {
+        if now.is_nan() || now <= 0.0 {
+            return Err(PyValueError::new_err("Timestamp must be greater than 0"));
+        }
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            let slot = schedule_from(ts, (now * 1000.0).round() as u64)
+                .await
+                .map_err(|e| e.for_limiter(&name))?;
+            Ok(slot as f64 / 1000.0)
+        })
     }
 
     /// Do nothing on aexit.
     #[args(_a = "*")]
+    #[pyo3(text_signature = "($self, *args)")]
     fn __aexit__<'p>(&self, py: Python<'p>, _a: &'p PyTuple) -> PyResult<&'p PyAny> {
         future_into_py(py, async { Ok(()) })
     }
 
+    /// The max number of tokens in the bucket.
+    #[getter]
+    fn capacity(&self) -> u32 {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// How many times taking a connection is retried when redis can't be reached.
+    #[getter]
+    fn max_retries(&self) -> u32 {
+        self.retries.max_retries
+    }
+
+    /// How long to wait before the first retry, in seconds. Later retries wait exponentially longer.
+    #[getter]
+    fn retry_backoff(&self) -> f32 {
+        self.retries.backoff.as_secs_f32()
+    }
+
+    /// Change the capacity of the bucket, for this instance and in redis.
+    ///
+    /// Slots already handed out are kept, while the tokens left are capped at the
+    /// new capacity. Other instances for the same bucket keep their own capacity,
+    /// so make sure to resize those too.
+    #[pyo3(text_signature = "($self, capacity)")]
+    fn resize<'p>(slf: Py<Self>, py: Python<'p>, capacity: u32) -> PyResult<&'p PyAny> {
+        if capacity == 0 {
+            return Err(PyValueError::new_err("Capacity must be greater than 0"));
+        }
+        at_most_max_capacity("Capacity", capacity.into())?;
+        let ts = ThreadState {
+            capacity,
+            ..ThreadState::from(&slf.borrow(py))
+        };
+        future_into_py(py, async move {
+            resize_bucket(ts).await?;
+            // The instance keeps its capacity unless redis was resized too
+            Python::with_gil(|py| slf.borrow(py).capacity.store(capacity, Ordering::Relaxed));
+            Ok(())
+        })
+    }
+
+    /// Decorate an async function, so that every call to it runs inside an `async with` block on the bucket.
+    ///
+    /// The wrapped function's return value and exceptions are passed through as is.
+    #[pyo3(text_signature = "($self, func)")]
+    fn limit(slf: &PyCell<Self>, func: PyObject) -> PyResult<PyObject> {
+        limit(slf.py(), slf.as_ref().into(), func)
+    }
+
+    /// Return percentiles of the time this instance recently slept for a token.
+    ///
+    /// The dict has the number of samples as `count`, and `p50`, `p90` and `p99` in milliseconds,
+    /// which are None until the bucket has been entered.
+    #[pyo3(text_signature = "($self)")]
+    fn wait_stats<'p>(&self, py: Python<'p>) -> PyResult<&'p PyDict> {
+        self.wait_samples.to_dict(py)
+    }
+
+    /// Return a token bucket for the key `{name}:{key_suffix}`, with the same settings.
+    ///
+    /// The returned bucket shares this instance's connection pool,
+    /// which makes it cheap to limit per tenant, e.g., per API key.
+    #[pyo3(text_signature = "($self, key_suffix)")]
+    fn with_key(&self, key_suffix: &str) -> PyResult<Self> {
+        if key_suffix.is_empty() {
+            return Err(PyValueError::new_err("Key suffix must not be empty"));
+        }
+        Ok(Self {
+            share: None,
+            idempotency: None,
+            ..self.with_name(format!("{}:{}", self.name, key_suffix))
+        })
+    }
+
+    /// Return a handle on this bucket that schedules against the redis server at `redis_url`, with the same settings.
+    ///
+    /// Connection pools for each url are created on first use, and shared by all handles derived
+    /// from this instance, which makes it cheap to route acquires per tenant in a sharded setup.
+    #[pyo3(text_signature = "($self, redis_url)")]
+    fn with_redis_url(&self, redis_url: &str) -> PyResult<Self> {
+        Ok(Self {
+            connection_pool: self.pools.get(redis_url)?,
+            lease: self.shared_lease(Some(redis_url), &self.name),
+            redis_url: Some(redis_url.to_string()),
+            ..self.with_name(self.name.clone())
+        })
+    }
+
+    /// Return a handle on this bucket for `tenant`, which may use at most `share` of its tokens.
+    ///
+    /// Tenants over their share of a window wait for the next one, even if the bucket
+    /// has tokens left. A window is the time it takes to refill an empty bucket.
+    /// The returned bucket shares this instance's settings and connection pool.
+    #[pyo3(text_signature = "($self, tenant, share)")]
+    fn with_share(&self, tenant: &str, share: f32) -> PyResult<Self> {
+        if tenant.is_empty() {
+            return Err(PyValueError::new_err("Tenant must not be empty"));
+        }
+        if share.is_nan() || share <= 0.0 || share > 1.0 {
+            return Err(PyValueError::new_err("Share must be greater than 0, and at most 1"));
+        }
+        Ok(Self {
+            share: Some((tenant.to_string(), share)),
+            ..self.with_name(self.name.clone())
+        })
+    }
+
+    /// Return a handle on this bucket where each acquire consumes `cost` tokens.
+    ///
+    /// Costs can be fractional, e.g., 0.1 for a cheap request. Costs above the capacity, e.g., for a bulk
+    /// request, wait for as many refills as they need, and leave the bucket empty.
+    /// The returned bucket shares this instance's settings and connection pool.
+    #[pyo3(text_signature = "($self, cost)")]
+    fn with_cost(&self, cost: f64) -> PyResult<Self> {
+        if cost.is_nan() || cost <= 0.0 || cost > MAX_CAPACITY as f64 {
+            return Err(PyValueError::new_err(format!(
+                "Cost must be greater than 0, and at most {}",
+                MAX_CAPACITY
+            )));
+        }
+        Ok(Self {
+            cost,
+            ..self.with_name(self.name.clone())
+        })
+    }
+
+    /// Return a handle on this bucket where acquires are deduplicated by `key`, e.g., a request id.
+    ///
+    /// The slot assigned to the first acquire is recorded for `window` seconds. Repeating the
+    /// acquire in that time, e.g., when retrying after a timeout, returns the same slot without
+    /// consuming more tokens. After the window, the key is forgotten, and acquiring consumes tokens again.
+    #[args(window = "60.0")]
+    #[pyo3(text_signature = "($self, key, window=60.0)")]
+    fn with_idempotency_key(&self, key: &str, window: f32) -> PyResult<Self> {
+        if key.is_empty() {
+            return Err(PyValueError::new_err("Idempotency key must not be empty"));
+        }
+        positive_seconds("Idempotency window", window)?;
+        Ok(Self {
+            idempotency: Some((key.to_string(), window)),
+            ..self.with_name(self.name.clone())
+        })
+    }
+
+    /// Report the outcome of a call to the downstream this bucket protects, when created with `adaptive=True`.
+    ///
+    /// An `"overloaded"` outcome halves the refill rate, and starts a cooldown of `retry_after`
+    /// seconds, or the time it takes to refill an empty bucket, whichever is longer. Further
+    /// overloads during the cooldown don't lower the rate again. After it, each `"ok"` outcome
+    /// raises the rate by a tenth of the configured rate, until it's back in full.
+    /// Returns the factor the configured rate is now scaled by.
+    #[pyo3(text_signature = "($self, outcome, retry_after=None)")]
+    fn report<'p>(&self, py: Python<'p>, outcome: &str, retry_after: Option<f32>) -> PyResult<&'p PyAny> {
+        if !self.adaptive {
+            return Err(PyValueError::new_err(
+                "Outcomes can only be reported to buckets created with adaptive=True",
+            ));
+        }
+        let overloaded = match outcome {
+            "ok" => false,
+            "overloaded" => true,
+            _ => return Err(PyValueError::new_err("Outcome must be 'ok' or 'overloaded'")),
+        };
+        let retry_after = retry_after.unwrap_or(0.0);
+        if !(0.0..=MAX_SLEEP_SECONDS).contains(&retry_after) {
+            return Err(PyValueError::new_err(format!(
+                "Retry after must be between 0 and {} seconds",
+                MAX_SLEEP_SECONDS
+            )));
+        }
+        let intervals = (self.capacity() as f64 / self.refill_amount as f64).ceil().max(1.0);
+        let refill_window = Duration::from_secs_f64(intervals * self.refill_frequency as f64);
+        let cooldown = refill_window.max(Duration::from_secs_f32(retry_after));
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        future_into_py(py, async move {
+            report_outcome(ts, overloaded, cooldown)
+                .await
+                .map_err(|e| e.for_limiter(&name))
+        })
+    }
+
+    /// The key acquires are deduplicated by, when created with `with_idempotency_key`.
+    #[getter]
+    fn idempotency_key(&self) -> Option<String> {
+        self.idempotency.as_ref().map(|(key, _)| key.clone())
+    }
+
+    /// The redis key holding the bucket state. This is the same as `name`.
+    #[getter]
+    fn state_key(&self) -> &str {
+        &self.name
+    }
+
+    /// The tenant this bucket is used by, when created with `with_share`.
+    #[getter]
+    fn tenant(&self) -> Option<String> {
+        self.share.as_ref().map(|(tenant, _)| tenant.clone())
+    }
+
+    /// The tenant's share of the bucket, when created with `with_share`.
+    #[getter]
+    fn share(&self) -> Option<f32> {
+        self.share.as_ref().map(|(_, share)| *share)
+    }
+
+    /// Return the number of rejections counted across all clients, in the current window.
+    ///
+    /// Rejections are only counted by instances created with `count_rejections=True`.
+    #[pyo3(text_signature = "($self)")]
+    fn rejection_rate<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let (pool, name) = (self.connection_pool.clone(), self.name.clone());
+        future_into_py(py, async move { Ok(read_rejections(pool, name).await?) })
+    }
+
+    /// Return the next slot tokens are available at, as a millisecond timestamp, and how many, without consuming any.
+    ///
+    /// Refills since the last acquire are taken into account, so the slot is now, or later if
+    /// the bucket is booked ahead. The tokens may be fractional, when acquires have fractional costs.
+    #[pyo3(text_signature = "($self)")]
+    fn tokens_remaining<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        let name = ts.name.clone();
+        future_into_py(
+            py,
+            async move { peek_bucket(ts).await.map_err(|e| e.for_limiter(&name)) },
+        )
+    }
+
+    /// Return the bucket's configuration and state in redis, as a dict.
+    ///
+    /// The state is the last slot assigned, as a millisecond timestamp, and the
+    /// tokens left for that slot. Both are None if the bucket has no state.
+    #[pyo3(text_signature = "($self)")]
+    fn snapshot<'p>(&self, py: Python<'p>) -> PyResult<&'p PyAny> {
+        let ts = ThreadState::from(self);
+        let (name, capacity, refill_frequency, refill_amount, expiry) = (
+            self.name.clone(),
+            self.capacity(),
+            self.refill_frequency,
+            self.refill_amount,
+            self.expiry,
+        );
+        future_into_py(py, async move {
+            let state = read_state(ts).await?;
+            Python::with_gil(|py| {
+                let snapshot = PyDict::new(py);
+                snapshot.set_item("name", name)?;
+                snapshot.set_item("capacity", capacity)?;
+                snapshot.set_item("refill_frequency", refill_frequency)?;
+                snapshot.set_item("refill_amount", refill_amount)?;
+                snapshot.set_item("expiry", expiry)?;
+                snapshot.set_item("slot", state.map(|(slot, _)| slot))?;
+                snapshot.set_item("tokens", state.map(|(_, tokens)| tokens))?;
+                Ok(snapshot.to_object(py))
+            })
+        })
+    }
+
+    /// Write the state from a snapshot to this bucket in redis, replacing any existing state.
+    ///
+    /// Tokens are capped at this bucket's capacity.
+    #[pyo3(text_signature = "($self, snapshot)")]
+    fn restore<'p>(&self, py: Python<'p>, snapshot: &'p PyDict) -> PyResult<&'p PyAny> {
+        let slot: Option<u64> = snapshot_item(snapshot, "slot")?;
+        let tokens: Option<f64> = snapshot_item(snapshot, "tokens")?;
+        let state = slot
+            .zip(tokens)
+            .map(|(slot, tokens)| (slot, tokens.min(self.capacity() as f64)));
+        let ts = ThreadState::from(self);
+        future_into_py(py, async move { Ok(write_state(ts, state).await?) })
+    }
+
     fn __repr__(&self) -> String {
         format!("Token bucket instance for queue {}", &self.name)
     }
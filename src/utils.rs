@@ -1,44 +1,482 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use bb8_redis::bb8::Pool;
+use bb8_redis::bb8::{ManageConnection, Pool, PooledConnection};
 use bb8_redis::RedisConnectionManager;
-use log::info;
-use redis::parse_redis_url;
+use log::{debug, info, warn};
+use pyo3::exceptions::PyValueError;
+use pyo3::once_cell::GILOnceCell;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use redis::{parse_redis_url, AsyncCommands, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisResult};
+use tokio::runtime::Handle;
 
 use crate::errors::SLError;
 
 pub(crate) type SLResult<T> = Result<T, SLError>;
 pub(crate) const REDIS_DEFAULT_URL: &str = "redis://127.0.0.1:6379";
 pub(crate) const REDIS_KEY_PREFIX: &str = "__self-limiters:";
+/// Upper bound for capacities and refill amounts. Values above this would
+/// make the Lua scripts create huge lists, or lose precision in their arithmetic.
+pub(crate) const MAX_CAPACITY: u32 = 1_000_000;
+/// Upper bound for max sleeps, of a year, in seconds. This keeps waits
+/// representable in milliseconds, and as `BLPOP` timeouts on 32-bit targets.
+pub(crate) const MAX_SLEEP_SECONDS: f32 = 31_536_000.0;
+
+/// Convert a setting given in seconds to a `Duration`, checking that it's greater than 0 and at most
+/// `MAX_SLEEP_SECONDS`, since `Duration::from_secs_f32` panics on NaN, infinite and huge values.
+///
+/// `setting` names the setting in the error, e.g., "Connect timeout".
+pub(crate) fn positive_seconds(setting: &str, seconds: f32) -> PyResult<Duration> {
+    if seconds.is_nan() || seconds <= 0.0 || seconds > MAX_SLEEP_SECONDS {
+        return Err(PyValueError::new_err(format!(
+            "{} must be greater than 0, and at most {} seconds",
+            setting, MAX_SLEEP_SECONDS
+        )));
+    }
+    Ok(Duration::from_secs_f32(seconds))
+}
+
+/// Check that a capacity, or a setting bounded like one, is at most `MAX_CAPACITY`.
+///
+/// `setting` names the setting in the error, e.g., "Refill amount".
+pub(crate) fn at_most_max_capacity(setting: &str, value: f64) -> PyResult<()> {
+    if value > MAX_CAPACITY as f64 {
+        return Err(PyValueError::new_err(format!(
+            "{} must be at most {}",
+            setting, MAX_CAPACITY
+        )));
+    }
+    Ok(())
+}
+
+/// Return the max sleep, in seconds, checking that it's between 0 and `MAX_SLEEP_SECONDS`. Defaults to 0.
+pub(crate) fn max_sleep_seconds(max_sleep: Option<f32>) -> PyResult<f32> {
+    let max_sleep = max_sleep.unwrap_or(0.0);
+    if !(0.0..=MAX_SLEEP_SECONDS).contains(&max_sleep) {
+        return Err(PyValueError::new_err(format!(
+            "Max sleep must be between 0 and {} seconds",
+            MAX_SLEEP_SECONDS
+        )));
+    }
+    Ok(max_sleep)
+}
+
+/// Check that an expiry, if any, is greater than 0. `None` means the keys never expire.
+pub(crate) fn check_expiry(expiry: Option<usize>) -> PyResult<()> {
+    if expiry == Some(0) {
+        return Err(PyValueError::new_err("Expiry must be greater than 0"));
+    }
+    Ok(())
+}
+
+/// Return the limiter's key, made from the prefix, which defaults to `REDIS_KEY_PREFIX`, and the name.
+pub(crate) fn prefixed_name(prefix: Option<&str>, name: &str) -> PyResult<String> {
+    if prefix == Some("") {
+        return Err(PyValueError::new_err("Prefix must not be empty"));
+    }
+    Ok(format!("{}{}", prefix.unwrap_or(REDIS_KEY_PREFIX), name))
+}
+
+/// Invoke the latency callback with the time spent calling redis, in seconds.
+///
+/// Exceptions raised by the callback are logged and swallowed.
+pub(crate) fn invoke_latency_callback(callback: &PyObject, latency: Duration) {
+    Python::with_gil(|py| {
+        if let Err(e) = callback.call1(py, (latency.as_secs_f64(),)) {
+            debug!("Latency callback raised an exception: {}", e);
+        }
+    });
+}
 
 pub(crate) fn now_millis() -> SLResult<u64> {
     // Beware: This will overflow in 500 thousand years
     Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64)
 }
 
-pub(crate) fn create_connection_manager(redis_url: Option<&str>) -> SLResult<RedisConnectionManager> {
-    match parse_redis_url(redis_url.unwrap_or(REDIS_DEFAULT_URL)) {
-        Some(url) => match RedisConnectionManager::new(url) {
-            Ok(manager) => Ok(manager),
-            Err(e) => Err(SLError::Redis(format!(
-                "Failed to open redis connection manager: {}",
-                e
-            ))),
-        },
-        None => Err(SLError::Redis(String::from("Failed to parse redis url"))),
+/// Run `acquire`, a wait for a limiter of the given kind, in a span when built with the `tracing` feature.
+///
+/// The span records the limiter's name, how long we waited, in milliseconds, and whether we
+/// gave up because of the max sleep. Semaphores also record the queue position on arrival,
+/// as `position`, when fair.
+#[cfg(feature = "tracing")]
+pub(crate) async fn traced<T>(
+    kind: &'static str,
+    name: &str,
+    acquire: impl Future<Output = SLResult<T>>,
+) -> SLResult<T> {
+    use tracing::field::Empty;
+    use tracing::Instrument;
+
+    let span = tracing::info_span!(
+        "self_limiters.acquire",
+        limiter = kind,
+        name = name,
+        position = Empty,
+        wait_ms = Empty,
+        max_sleep_exceeded = Empty,
+    );
+    let start = std::time::Instant::now();
+    let result = acquire.instrument(span.clone()).await;
+    span.record("wait_ms", start.elapsed().as_millis() as u64);
+    span.record(
+        "max_sleep_exceeded",
+        matches!(result, Err(SLError::MaxSleepExceeded(_))),
+    );
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) async fn traced<T>(
+    _kind: &'static str,
+    _name: &str,
+    acquire: impl Future<Output = SLResult<T>>,
+) -> SLResult<T> {
+    acquire.await
+}
+
+/// ACL credentials to authenticate with, overriding any in the redis url.
+///
+/// Passing these separately saves url-encoding passwords with special characters.
+/// Deliberately not `Debug`, so the password can't end up in logs.
+#[derive(Clone, Default)]
+pub(crate) struct Credentials {
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+}
+
+/// Parse the connection info for `redis_url`, or the default url.
+///
+/// `rediss://` urls connect over TLS. Setting `verify_tls` to false skips verifying the
+/// server's certificate, e.g., for self-signed certificates. It has no effect on other urls.
+pub(crate) fn connection_info(
+    redis_url: Option<&str>,
+    verify_tls: bool,
+    credentials: &Credentials,
+) -> SLResult<ConnectionInfo> {
+    let mut url = parse_redis_url(redis_url.unwrap_or(REDIS_DEFAULT_URL))
+        .ok_or_else(|| SLError::Redis(String::from("Failed to parse redis url")))?;
+    // The redis crate reads the `insecure` fragment of TLS urls as a request to skip verification
+    if !verify_tls && url.scheme() == "rediss" {
+        url.set_fragment(Some("insecure"));
+    }
+    let mut info = url
+        .into_connection_info()
+        .map_err(|e| SLError::Redis(format!("Failed to open redis connection manager: {}", e)))?;
+    if let Some(username) = &credentials.username {
+        info.redis.username = Some(username.clone());
     }
+    if let Some(password) = &credentials.password {
+        info.redis.password = Some(password.clone());
+    }
+    Ok(info)
 }
 
+/// Create a connection manager for `redis_url`, or the default url. See `connection_info`.
+pub(crate) fn create_connection_manager(
+    redis_url: Option<&str>,
+    verify_tls: bool,
+    credentials: &Credentials,
+) -> SLResult<RedisConnectionManager> {
+    match RedisConnectionManager::new(connection_info(redis_url, verify_tls, credentials)?) {
+        Ok(manager) => Ok(manager),
+        Err(e) => Err(SLError::Redis(format!(
+            "Failed to open redis connection manager: {}",
+            e
+        ))),
+    }
+}
+
+/// Await a redis command, failing if it doesn't complete within `timeout`.
+pub(crate) async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    command: impl Future<Output = RedisResult<T>>,
+) -> RedisResult<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, command)
+            .await
+            .unwrap_or_else(|_| Err((ErrorKind::IoError, "Timed out waiting for redis").into())),
+        None => command.await,
+    }
+}
+
+/// Create a connection pool for the given manager.
+///
+/// When `eager_connect` is set, we open a connection and `PING` the server
+/// before building the pool, so that bad configuration fails fast.
+/// Opening connections fails after `connect_timeout`, when set.
+///
+/// The pool is created on the `pyo3_asyncio` runtime, which runs all our futures,
+/// so that its background tasks live as long as the runtime does. When called from
+/// within another tokio runtime, we block in place rather than nesting runtimes.
 pub(crate) fn create_connection_pool(
     manager: RedisConnectionManager,
     max_size: u32,
+    eager_connect: bool,
+    connect_timeout: Option<Duration>,
 ) -> SLResult<Pool<RedisConnectionManager>> {
-    let future = async move { Pool::builder().max_size(max_size).build(manager).await.unwrap() };
-    let res = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(future);
+    let runtime = pyo3_asyncio::tokio::get_runtime();
+    if eager_connect {
+        let future = async {
+            let mut connection = with_timeout(connect_timeout, manager.connect()).await?;
+            manager.is_valid(&mut connection).await?;
+            debug!("Connected to redis");
+            Ok::<_, SLError>(())
+        };
+        match Handle::try_current() {
+            Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future))?,
+            Err(_) => runtime.block_on(future)?,
+        }
+    }
+
+    let _guard = runtime.enter();
+    let mut builder = Pool::builder().max_size(max_size);
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connection_timeout(connect_timeout);
+    }
+    let pool = builder.build_unchecked(manager);
     info!("Created connection pool of max {} connections", max_size);
-    Ok(res)
+    Ok(pool)
+}
+
+/// Run a future to completion from synchronous code, on the `pyo3_asyncio` runtime.
+///
+/// The GIL is released while blocking, so other Python threads, and the callbacks our
+/// futures invoke, can run meanwhile. Like `create_connection_pool`, we block in place
+/// when called from within another tokio runtime, rather than nesting runtimes.
+pub(crate) fn block_on<F>(py: Python<'_>, future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    py.allow_threads(|| match Handle::try_current() {
+        Ok(handle) => tokio::task::block_in_place(|| handle.block_on(future)),
+        Err(_) => pyo3_asyncio::tokio::get_runtime().block_on(future),
+    })
+}
+
+/// Runs a closure when dropped, unless defused first.
+///
+/// Futures use it to clean up when they fail, or are dropped because the awaiting task was cancelled.
+pub(crate) struct DropGuard<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> DropGuard<F> {
+    pub(crate) fn new(on_drop: F) -> Self {
+        Self(Some(on_drop))
+    }
+
+    /// Drop the guard without running the closure.
+    pub(crate) fn defuse(mut self) {
+        self.0 = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for DropGuard<F> {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.0.take() {
+            on_drop();
+        }
+    }
+}
+
+/// How long to wait before the first retry of a connection failure by default, in seconds.
+pub(crate) const DEFAULT_RETRY_BACKOFF_SECONDS: f32 = 0.1;
+
+/// The longest wait between retries, however many have failed before.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How to retry taking a connection from the pool when redis can't be reached, e.g., while it restarts.
+///
+/// Only connection failures are retried. Errors returned by redis, and limits like `max_sleep`, are raised as is.
+#[derive(Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    pub(crate) max_retries: u32,
+    pub(crate) backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            backoff: Duration::from_secs_f32(DEFAULT_RETRY_BACKOFF_SECONDS),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Create a policy from the `max_retries` and `retry_backoff` settings, in seconds, using the defaults for `None`.
+    pub(crate) fn new(max_retries: Option<u32>, retry_backoff: Option<f32>) -> PyResult<Self> {
+        Ok(Self {
+            max_retries: max_retries.unwrap_or(0),
+            backoff: positive_seconds("Retry backoff", retry_backoff.unwrap_or(DEFAULT_RETRY_BACKOFF_SECONDS))?,
+        })
+    }
+
+    /// Take a connection from `pool`, retrying connection failures up to `max_retries` times.
+    pub(crate) async fn get<'a>(
+        &self,
+        pool: &'a Pool<RedisConnectionManager>,
+    ) -> SLResult<PooledConnection<'a, RedisConnectionManager>> {
+        let mut attempt = 0;
+        loop {
+            match pool.get().await.map_err(SLError::from) {
+                Err(SLError::Connection(e)) if attempt < self.max_retries => {
+                    let delay = self.delay(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Failed to connect to redis, retrying in {:.3}s ({} of {}): {}",
+                        delay.as_secs_f64(),
+                        attempt,
+                        self.max_retries,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The time to wait before retrying after `attempt` failed retries.
+    ///
+    /// The wait doubles with each attempt, up to a cap, and half of it is random, so that
+    /// clients that lost their connection at the same time don't all reconnect at once.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(1 << attempt.min(16)).min(MAX_RETRY_BACKOFF);
+        let jitter = (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0;
+        delay.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+/// Connection pools for other redis servers, by url, for limiters routed with `with_redis_url`.
+///
+/// Pools are created on first use, with the same settings as the limiter's own pool,
+/// and kept for as long as the limiter, so routing doesn't reconnect on every acquire.
+pub(crate) struct PoolCache {
+    max_size: u32,
+    connect_timeout: Option<Duration>,
+    verify_tls: bool,
+    credentials: Credentials,
+    pools: Mutex<HashMap<String, Pool<RedisConnectionManager>>>,
+}
+
+impl PoolCache {
+    pub(crate) fn new(
+        max_size: u32,
+        connect_timeout: Option<Duration>,
+        verify_tls: bool,
+        credentials: Credentials,
+    ) -> Self {
+        Self {
+            max_size,
+            connect_timeout,
+            verify_tls,
+            credentials,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the pool for `redis_url`, creating it if this is the first time it's used.
+    pub(crate) fn get(&self, redis_url: &str) -> SLResult<Pool<RedisConnectionManager>> {
+        // A poisoned lock only means another thread panicked mid-update; the map itself is still usable
+        let mut pools = self.pools.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pool) = pools.get(redis_url) {
+            return Ok(pool.clone());
+        }
+        let manager = create_connection_manager(Some(redis_url), self.verify_tls, &self.credentials)?;
+        let pool = create_connection_pool(manager, self.max_size, false, self.connect_timeout)?;
+        pools.insert(redis_url.to_string(), pool.clone());
+        Ok(pool)
+    }
+}
+
+/// How long rejections are counted for, from the first rejection in a window.
+pub(crate) const REJECTION_WINDOW_SECONDS: usize = 60;
+
+/// Key of the counter of rejections for the limiter with the given name.
+pub(crate) fn rejections_key(name: &str) -> String {
+    format!("{}-rejections", name)
+}
+
+/// Count a rejection in the current window, for the limiter with the given name.
+///
+/// Failures are logged rather than raised, so they never hide the rejection itself.
+pub(crate) async fn record_rejection(pool: &Pool<RedisConnectionManager>, name: &str) {
+    let key = rejections_key(name);
+    let result = async {
+        let mut connection = pool.get().await?;
+        // Setting the counter with NX in the same transaction starts a new window
+        // when needed, without resetting the expiry of an ongoing window
+        redis::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg(0)
+            .arg("EX")
+            .arg(REJECTION_WINDOW_SECONDS)
+            .arg("NX")
+            .ignore()
+            .incr(&key, 1)
+            .ignore()
+            .query_async::<_, ()>(&mut *connection)
+            .await?;
+        Ok::<_, SLError>(())
+    };
+    if let Err(e) = result.await {
+        warn!("Failed to count rejection for {}: {:?}", name, e);
+    }
+}
+
+/// Read the number of rejections in the current window, for the limiter with the given name.
+pub(crate) async fn read_rejections(pool: Pool<RedisConnectionManager>, name: String) -> SLResult<u64> {
+    let mut connection = pool.get().await?;
+    let rejections: Option<u64> = connection.get(rejections_key(&name)).await?;
+    Ok(rejections.unwrap_or(0))
+}
+
+/// Extract a required item from a limiter snapshot.
+pub(crate) fn snapshot_item<'p, T: FromPyObject<'p>>(snapshot: &'p PyDict, key: &str) -> PyResult<T> {
+    snapshot
+        .get_item(key)
+        .ok_or_else(|| PyValueError::new_err(format!("Snapshot is missing the '{}' item", key)))?
+        .extract()
+}
+
+/// Python source of the `limit` decorators. Wrapping a coroutine function is much
+/// simpler in Python, and awaiting the wrapped coroutine from Python keeps its
+/// return value, exceptions, and cancellation behaviour untouched.
+const LIMIT_SOURCE: &str = r#"
+import functools
+import inspect
+
+
+def limit(limiter, func):
+    if not inspect.iscoroutinefunction(func):
+        raise TypeError(f'Can only limit async functions, got {func!r}')
+
+    @functools.wraps(func)
+    async def wrapper(*args, **kwargs):
+        async with limiter:
+            return await func(*args, **kwargs)
+
+    return wrapper
+"#;
+
+/// Wrap an async function so that every call runs inside `async with limiter`.
+pub(crate) fn limit(py: Python<'_>, limiter: PyObject, func: PyObject) -> PyResult<PyObject> {
+    static LIMIT: GILOnceCell<PyObject> = GILOnceCell::new();
+    let decorator = match LIMIT.get(py) {
+        Some(decorator) => decorator,
+        None => {
+            // Compiling the module can fail, and the cell can only be set infallibly, so we compile it first
+            let module = PyModule::from_code(py, LIMIT_SOURCE, "limit.py", "self_limiters._limit")?;
+            let decorator: PyObject = module.getattr("limit")?.into();
+            LIMIT.get_or_init(py, || decorator)
+        }
+    };
+    decorator.call1(py, (limiter, func))
 }
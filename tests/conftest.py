@@ -2,10 +2,11 @@ import asyncio
 import logging
 from functools import partial
 from pathlib import Path
-from typing import TYPE_CHECKING
+from typing import TYPE_CHECKING, Optional
 from uuid import uuid4
 
-from self_limiters import Semaphore, TokenBucket
+import pytest
+from self_limiters import CompositeLimiter, QuorumSemaphore, RedisClient, Semaphore, TestLimiterScope, TokenBucket
 
 if TYPE_CHECKING:
     from datetime import timedelta
@@ -14,6 +15,43 @@ logger = logging.getLogger(__name__)
 
 REPO_ROOT = Path(__file__).parent.parent
 
+# Tests use the first few databases of the dc redis, e.g., as separate instances for quorum semaphores
+TEST_DATABASES = range(3)
+
+# The scope of the running test, which limiter names are taken from
+_scope: Optional[TestLimiterScope] = None
+
+
+@pytest.fixture(autouse=True)
+def limiter_scope():
+    """
+    Scope the limiter names of each test, and delete their keys in all test databases after it.
+
+    This keeps tests from affecting each other through leftover state. Tasks still running in the
+    background when a test ends may recreate their keys, which then expire as usual.
+    """
+    global _scope
+    with TestLimiterScope(redis_urls=[f'redis://127.0.0.1:6389/{db}' for db in TEST_DATABASES]) as scope:
+        _scope = scope
+        yield scope
+        _scope = None
+
+
+@pytest.fixture
+def client() -> RedisClient:
+    return RedisClient(redis_url='redis://127.0.0.1:6389')
+
+
+def limiter_name(name: Optional[str] = None) -> str:
+    """
+    Return a limiter name unique to the running test, whose keys are deleted after it.
+
+    Factories are also called while tests are collected, outside any scope, where we make up a random name.
+    """
+    if _scope is None:
+        return f'{name or "limiter"}-{uuid4().hex[:6]}'
+    return _scope.name(name)
+
 
 def semaphore_factory(**kwargs) -> partial:
     """
@@ -22,7 +60,7 @@ def semaphore_factory(**kwargs) -> partial:
     This makes it easy to init semaphores with slightly different configurations in tests.
     """
 
-    defaults = {'name': uuid4().hex[:6], 'capacity': 1, 'redis_url': 'redis://127.0.0.1:6389'}
+    defaults = {'name': limiter_name(), 'capacity': 1, 'redis_url': 'redis://127.0.0.1:6389'}
     return partial(Semaphore, **{**defaults, **kwargs})
 
 
@@ -34,7 +72,7 @@ def tokenbucket_factory(**kwargs) -> partial:
     """
 
     defaults = {
-        'name': uuid4().hex[:6],
+        'name': limiter_name(),
         'capacity': 1,
         'refill_frequency': 1.0,
         'refill_amount': 1,
@@ -43,6 +81,35 @@ def tokenbucket_factory(**kwargs) -> partial:
     return partial(TokenBucket, **{**defaults, **kwargs})
 
 
+def composite_factory(**kwargs) -> partial:
+    """
+    Provide an almost initialized composite limiter with defaults.
+    """
+
+    defaults = {
+        'name': limiter_name(),
+        'capacity': 1,
+        'refill_frequency': 1.0,
+        'refill_amount': 1,
+        'concurrency': 1,
+        'redis_url': 'redis://127.0.0.1:6389',
+    }
+    return partial(CompositeLimiter, **{**defaults, **kwargs})
+
+
+def quorum_factory(**kwargs) -> partial:
+    """
+    Provide an almost initialized quorum semaphore with defaults, using three databases as instances.
+    """
+
+    defaults = {
+        'name': limiter_name(),
+        'capacity': 1,
+        'redis_urls': [f'redis://127.0.0.1:6389/{db}' for db in TEST_DATABASES],
+    }
+    return partial(QuorumSemaphore, **{**defaults, **kwargs})
+
+
 def delta_to_seconds(t: 'timedelta') -> float:
     return t.seconds + t.microseconds / 1_000_000
 
@@ -0,0 +1,68 @@
+import asyncio
+
+import pytest
+from self_limiters import RedisClient, RedisError, Semaphore, TokenBucket
+
+from .conftest import limiter_name, run
+
+
+def test_client_defaults(client):
+    assert (client.connection_pool_size, client.verify_tls) == (15, True)
+    assert (client.max_retries, client.retry_backoff) == (0, pytest.approx(0.1))
+
+
+def test_limiters_inherit_client_settings():
+    """
+    Limiters created from a client should retry like the client is configured to, rather than use the defaults.
+    """
+    client = RedisClient(redis_url='redis://127.0.0.1:6389', response_timeout=1, max_retries=3, retry_backoff=0.5)
+    semaphore = Semaphore.from_client(client, limiter_name(), 1)
+    bucket = TokenBucket.from_client(client, limiter_name(), 1, 1.0, 1)
+    for limiter in [semaphore, bucket]:
+        assert (limiter.max_retries, limiter.retry_backoff) == (3, pytest.approx(0.5))
+
+
+async def test_limiters_from_client():
+    """
+    Limiters created from one client should share its connections, and work like any other.
+    """
+    client = RedisClient(redis_url='redis://127.0.0.1:6389', connection_pool_size=5, eager_connect=True)
+    semaphores = [Semaphore.from_client(client, limiter_name(), 1, max_sleep=1) for _ in range(3)]
+    buckets = [TokenBucket.from_client(client, limiter_name(), 1, 1.0, 1) for _ in range(3)]
+
+    await asyncio.gather(*(run(lambda limiter=limiter: limiter, 0.05) for limiter in semaphores + buckets))
+
+    semaphore = semaphores[0]
+    assert (semaphore.capacity, semaphore.max_sleep, semaphore.expiry) == (1, 1, 30)
+    assert semaphore.name.startswith('__self-limiters:')
+    assert Semaphore.from_client(client, 'foo', 1, prefix='app:').name == 'app:foo'
+    assert (await semaphore.snapshot())['capacity'] == 1
+
+
+async def test_from_client_unreachable():
+    client = RedisClient(redis_url='redis://127.0.0.1:1', connect_timeout=0.1)
+    with pytest.raises(RedisError):
+        await run(lambda: Semaphore.from_client(client, limiter_name(), 1), 0)
+    with pytest.raises(RedisError):
+        RedisClient(redis_url='redis://127.0.0.1:1', eager_connect=True, connect_timeout=0.1)
+
+
+def test_from_client_validation(client):
+    with pytest.raises(ValueError, match='Capacity must be at most'):
+        Semaphore.from_client(client, 'foo', 2_000_000)
+    with pytest.raises(ValueError, match='Expiry must be greater than 0'):
+        Semaphore.from_client(client, 'foo', 1, expiry=0)
+    with pytest.raises(ValueError, match='Refill amount must be greater than 0'):
+        TokenBucket.from_client(client, 'foo', 1, 1.0, 0)
+    with pytest.raises(ValueError, match='Refill frequency must be greater than 0'):
+        TokenBucket.from_client(client, 'foo', 1, float('nan'), 1)
+    with pytest.raises(ValueError, match='Prefix must not be empty'):
+        TokenBucket.from_client(client, 'foo', 1, 1.0, 1, prefix='')
+    with pytest.raises(ValueError, match='Max sleep must be between 0'):
+        Semaphore.from_client(client, 'foo', 1, max_sleep=-1)
+    with pytest.raises(ValueError, match='Connect timeout must be greater than 0'):
+        RedisClient(connect_timeout=0)
+    with pytest.raises(ValueError, match='Response timeout must be greater than 0'):
+        RedisClient(response_timeout=float('nan'))
+    with pytest.raises(ValueError, match='Retry backoff must be greater than 0'):
+        RedisClient(retry_backoff=0)
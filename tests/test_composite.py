@@ -0,0 +1,78 @@
+import asyncio
+import logging
+import re
+from datetime import datetime
+
+import pytest
+from self_limiters import MaxSleepExceededError
+
+from .conftest import composite_factory, delta_to_seconds, limiter_name, run
+
+logger = logging.getLogger(__name__)
+
+
+async def test_composite_limits_rate_and_concurrency():
+    """
+    Tasks should be spaced out by the refill frequency, and never overlap when concurrency is 1.
+    """
+    name = limiter_name()
+    active = 0
+    max_active = 0
+
+    async def _run():
+        nonlocal active, max_active
+        async with composite_factory(name=name, refill_frequency=0.1, concurrency=1)():
+            active += 1
+            max_active = max(active, max_active)
+            await asyncio.sleep(0.2)
+            active -= 1
+
+    before = datetime.now()
+    await asyncio.gather(*[asyncio.create_task(_run()) for _ in range(3)])
+    assert max_active == 1
+    assert 0.6 <= delta_to_seconds(datetime.now() - before)
+
+
+async def test_max_sleep_budget_is_shared():
+    """
+    Time spent waiting for a token should count against the time allowed waiting for a slot.
+
+    The second task waits for a token, then ~0.7s for the first task to release its slot.
+    Neither wait exceeds the max sleep of 1s on its own, but together they do.
+    """
+    name = limiter_name()
+    pt = composite_factory(name=name, refill_frequency=0.5, max_sleep=1)
+
+    first = asyncio.create_task(run(pt, 1.2))
+    await asyncio.sleep(0.05)
+
+    with pytest.raises(MaxSleepExceededError):
+        await run(pt, 0)
+    await first
+
+
+def test_class_attributes():
+    limiter = composite_factory(name='test', capacity=2, concurrency=3)()
+    assert limiter.name == '__self-limiters:test'
+    assert limiter.capacity == 2
+    assert limiter.concurrency == 3
+    assert limiter.refill_frequency == 1.0
+    assert limiter.refill_amount == 1
+    assert limiter.max_sleep == 0
+
+
+def test_repr():
+    limiter = composite_factory(name='test')()
+    assert re.match(r'Composite limiter instance for queue __self-limiters:test', str(limiter))
+
+
+@pytest.mark.parametrize('refill_frequency', [0, float('nan')])
+def test_refill_frequency_validation(refill_frequency):
+    with pytest.raises(ValueError, match='Refill frequency must be greater than 0'):
+        composite_factory(refill_frequency=refill_frequency)()
+
+
+@pytest.mark.parametrize('argument', ['capacity', 'refill_amount', 'concurrency'])
+def test_upper_bound_validation(argument):
+    with pytest.raises(ValueError, match='must be at most'):
+        composite_factory(**{argument: 2**32 - 1})()
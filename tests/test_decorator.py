@@ -0,0 +1,50 @@
+import asyncio
+from datetime import datetime
+
+import pytest
+
+from .conftest import composite_factory, delta_to_seconds, semaphore_factory, tokenbucket_factory
+
+
+@pytest.mark.parametrize('factory', [semaphore_factory, tokenbucket_factory, composite_factory])
+async def test_limit_passes_through_results_and_exceptions(factory):
+    limiter = factory(capacity=2)
+
+    @limiter().limit
+    async def add(a, b=1):
+        """Add two numbers."""
+        return a + b
+
+    @limiter().limit
+    async def fail():
+        raise KeyError('boom')
+
+    assert await add(1, b=2) == 3
+    assert add.__name__ == 'add'
+    assert add.__doc__ == 'Add two numbers.'
+
+    with pytest.raises(KeyError, match='boom'):
+        await fail()
+
+    # The limiter is released when the wrapped function raises
+    assert await add(2) == 3
+
+
+async def test_limit_limits_concurrency():
+    semaphore = semaphore_factory(capacity=1)()
+
+    @semaphore.limit
+    async def work():
+        await asyncio.sleep(0.1)
+
+    before = datetime.now()
+    await asyncio.gather(*[work() for _ in range(3)])
+    assert delta_to_seconds(datetime.now() - before) >= 0.3
+
+
+def test_limit_requires_async_function():
+    with pytest.raises(TypeError, match='Can only limit async functions'):
+
+        @tokenbucket_factory()().limit
+        def work():
+            pass
@@ -0,0 +1,83 @@
+import logging
+from uuid import uuid4
+
+import pytest
+from redis.asyncio.client import Redis
+from self_limiters import RedisError, purge
+
+from .conftest import limiter_name, semaphore_factory
+
+logger = logging.getLogger(__name__)
+
+REDIS_URL = 'redis://127.0.0.1:6389'
+
+
+async def test_purge():
+    r = Redis.from_url(REDIS_URL)
+    stale_key = f'__self-limiters:{limiter_name()}'
+    expiring_key = f'__self-limiters:{limiter_name()}'
+    await r.set(stale_key, 1)
+    await r.set(expiring_key, 1, ex=30)
+
+    # Dry runs shouldn't delete anything
+    keys = await purge(REDIS_URL, 0, dry_run=True)
+    assert stale_key in keys
+    assert expiring_key not in keys
+    assert await r.exists(stale_key)
+
+    # Keys younger than the threshold are left alone
+    assert stale_key not in await purge(REDIS_URL, 60)
+    assert await r.exists(stale_key)
+
+    assert stale_key in await purge(REDIS_URL, 0)
+    assert not await r.exists(stale_key)
+    assert await r.exists(expiring_key)
+
+
+async def test_purge_credentials():
+    """
+    Purges should authenticate with the credentials passed, like the limiters.
+    """
+    r = Redis.from_url(REDIS_URL)
+    username, password = f'purger-{uuid4().hex[:6]}', 'p@ss:w/rd#%'
+    await r.execute_command('ACL', 'SETUSER', username, 'on', f'>{password}', '~*', '+@all')
+    try:
+        assert isinstance(await purge(REDIS_URL, 60, dry_run=True, username=username, password=password), list)
+        with pytest.raises(RedisError):
+            await purge(REDIS_URL, 60, dry_run=True, username=username, password='wrong')
+    finally:
+        await r.execute_command('ACL', 'DELUSER', username)
+
+
+async def test_purge_fence_suffix():
+    """
+    Keys ending in `-fence` are purged like any other, since fencing counters have an expiry.
+    """
+    r = Redis.from_url(REDIS_URL)
+    fence_key = f'__self-limiters:{limiter_name()}-fence'
+    await r.set(fence_key, 5)
+
+    assert fence_key in await purge(REDIS_URL, 0)
+    assert not await r.exists(fence_key)
+
+
+async def test_purge_prefix():
+    """
+    Keys of limiters with a custom prefix are only purged when the same prefix is passed.
+    """
+    r = Redis.from_url(REDIS_URL)
+    semaphore = semaphore_factory(prefix='purge-test:', expiry=None)()
+    async with semaphore:
+        pass
+    assert await r.exists(semaphore.name)
+
+    assert semaphore.name not in await purge(REDIS_URL, 0)
+    assert await r.exists(semaphore.name)
+
+    assert semaphore.name in await purge(REDIS_URL, 0, prefix='purge-test:')
+    assert not await r.exists(semaphore.name)
+
+
+async def test_purge_empty_prefix():
+    with pytest.raises(ValueError):
+        await purge(REDIS_URL, 0, prefix='')
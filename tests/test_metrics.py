@@ -0,0 +1,78 @@
+import pytest
+import self_limiters
+from self_limiters import MaxSleepExceededError
+
+from .conftest import semaphore_factory, tokenbucket_factory
+
+
+@pytest.fixture
+def metrics():
+    self_limiters.enable_metrics()
+    yield
+    self_limiters.enable_metrics(False)
+
+
+async def test_collect_metrics_semaphore(metrics):
+    semaphore = semaphore_factory(capacity=1, max_sleep=0.05)()
+    for _ in range(3):
+        async with semaphore:
+            pass
+
+    # Holding the only permit forces the next acquire to give up
+    async with semaphore:
+        with pytest.raises(MaxSleepExceededError):
+            async with semaphore:
+                pass
+
+    stats = self_limiters.collect_metrics()[semaphore.name]
+    assert stats['acquisitions'] == 4
+    assert stats['max_sleep_exceeded'] == 1
+    assert stats['releases'] == 4
+    assert stats['wait_ms'] >= 50
+
+
+async def test_collect_metrics_token_bucket(metrics):
+    bucket = tokenbucket_factory(capacity=3, refill_frequency=60, max_sleep=0.05)()
+    for _ in range(3):
+        async with bucket:
+            pass
+
+    # The bucket is empty until the next refill, a minute away
+    with pytest.raises(MaxSleepExceededError):
+        async with bucket:
+            pass
+
+    stats = self_limiters.collect_metrics()[bucket.name]
+    assert stats['acquisitions'] == 3
+    assert stats['max_sleep_exceeded'] == 1
+    assert stats['releases'] == 0
+
+
+async def test_collect_metrics_without_waiting(metrics):
+    """
+    Acquires that don't wait should be counted like any other, so releases never exceed acquisitions.
+    """
+    semaphore = semaphore_factory(capacity=2)()
+    assert await semaphore.try_acquire()
+    assert await semaphore.try_acquire()
+    assert not await semaphore.try_acquire()
+    await semaphore.release(2)
+
+    acquisition = await semaphore.acquire_sharded(2)
+    await acquisition.release()
+
+    metrics = self_limiters.collect_metrics()
+    stats = metrics[semaphore.name]
+    assert stats['acquisitions'] == 3
+    assert stats['releases'] == 3
+
+    # Shards are counted under the semaphore's name
+    assert not [name for name in metrics if name.startswith(f'{semaphore.name}-')]
+
+
+async def test_metrics_disabled():
+    limiter = semaphore_factory()()
+    self_limiters.enable_metrics(False)
+    async with limiter:
+        pass
+    assert limiter.name not in self_limiters.collect_metrics()
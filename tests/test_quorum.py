@@ -0,0 +1,90 @@
+import asyncio
+import logging
+
+import pytest
+from redis.asyncio.client import Redis
+from self_limiters import MaxSleepExceededError, RedisError
+
+from .conftest import limiter_name, quorum_factory, run
+
+logger = logging.getLogger(__name__)
+
+UNREACHABLE = 'redis://127.0.0.1:1'
+
+
+async def test_quorum_limits_concurrency():
+    name = limiter_name()
+    active = 0
+    max_active = 0
+
+    async def _run():
+        nonlocal active, max_active
+        async with quorum_factory(name=name)():
+            active += 1
+            max_active = max(active, max_active)
+            await asyncio.sleep(0.1)
+            active -= 1
+
+    await asyncio.gather(*[asyncio.create_task(_run()) for _ in range(5)])
+    assert max_active == 1
+
+
+async def test_permits_are_returned_to_every_instance():
+    semaphore = quorum_factory(capacity=2)()
+    assert semaphore.quorum == 2
+
+    await run(lambda: semaphore, 0)
+
+    for db in range(3):
+        r = Redis.from_url(f'redis://127.0.0.1:6389/{db}')
+        assert await r.llen(semaphore.name) == 2
+
+
+async def test_quorum_survives_losing_a_minority():
+    urls = ['redis://127.0.0.1:6389/0', 'redis://127.0.0.1:6389/1', UNREACHABLE]
+    await run(quorum_factory(redis_urls=urls, connect_timeout=0.2), 0)
+
+
+async def test_quorum_fails_when_a_majority_is_lost():
+    urls = ['redis://127.0.0.1:6389/0', UNREACHABLE, UNREACHABLE]
+    with pytest.raises(RedisError, match='fewer than the quorum of 2'):
+        await run(quorum_factory(redis_urls=urls, connect_timeout=0.2), 0)
+
+
+async def test_minority_grants_are_rolled_back():
+    """
+    Permits granted by a minority of the instances should be returned, rather than held while retrying.
+    """
+    semaphore = quorum_factory(max_sleep=0.3)()
+
+    # Make the semaphore exist with no free permits on two of the instances
+    for db in range(2):
+        r = Redis.from_url(f'redis://127.0.0.1:6389/{db}')
+        await r.set(f'{semaphore.name}-exists', 1, ex=30)
+
+    with pytest.raises(MaxSleepExceededError):
+        await run(lambda: semaphore, 0)
+
+    r = Redis.from_url('redis://127.0.0.1:6389/2')
+    assert await r.llen(semaphore.name) == 1
+
+
+async def test_failed_acquire_is_not_exited():
+    """
+    A failed acquire is never exited, so exiting the block around it should return the outer permits.
+    """
+    semaphore = quorum_factory(max_sleep=0.1)()
+    async with semaphore:
+        with pytest.raises(MaxSleepExceededError):
+            async with semaphore:
+                pass
+
+    for db in range(3):
+        r = Redis.from_url(f'redis://127.0.0.1:6389/{db}')
+        assert await r.llen(semaphore.name) == 1
+
+
+@pytest.mark.parametrize('urls', [[], ['redis://127.0.0.1:6389/0', 'redis://127.0.0.1:6389/1']])
+def test_at_least_three_instances(urls):
+    with pytest.raises(ValueError, match='Quorum semaphores need at least 3 redis urls'):
+        quorum_factory(redis_urls=urls)()
@@ -0,0 +1,28 @@
+import pytest
+import self_limiters
+
+from .conftest import composite_factory, semaphore_factory, tokenbucket_factory
+
+
+@pytest.mark.parametrize('factory', [semaphore_factory, tokenbucket_factory, composite_factory])
+def test_register_and_get(factory):
+    limiter = factory()()
+    self_limiters.register('registry-test', limiter)
+    assert self_limiters.get('registry-test') is limiter
+
+
+def test_register_replaces():
+    first, second = semaphore_factory()(), tokenbucket_factory()()
+    self_limiters.register('registry-replace-test', first)
+    self_limiters.register('registry-replace-test', second)
+    assert self_limiters.get('registry-replace-test') is second
+
+
+def test_get_missing():
+    with pytest.raises(KeyError, match="No limiter registered as 'registry-missing-test'"):
+        self_limiters.get('registry-missing-test')
+
+
+def test_register_requires_limiter():
+    with pytest.raises(TypeError, match='Only limiters can be registered, got str'):
+        self_limiters.register('registry-type-test', 'limiter')
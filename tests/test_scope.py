@@ -0,0 +1,42 @@
+import gc
+
+from redis.asyncio.client import Redis
+from self_limiters import Semaphore, TestLimiterScope
+
+from .conftest import semaphore_factory
+
+REDIS_URL = 'redis://127.0.0.1:6389'
+
+
+async def test_scope_deletes_its_keys():
+    """
+    Closing a scope should delete the keys of the limiters named by it, and nothing else.
+    """
+    r = Redis.from_url(REDIS_URL)
+    other = semaphore_factory()()
+    with TestLimiterScope(redis_urls=[REDIS_URL]) as scope:
+        assert scope.name('shared') == scope.name('shared') == f'{scope.id}:shared'
+        assert scope.name() != scope.name()
+        scoped = Semaphore(scope.name(), 1, redis_url=REDIS_URL)
+        async with scoped:
+            pass
+        async with other:
+            pass
+        assert await r.exists(scoped.name, f'{scoped.name}-exists') == 2
+
+    assert await r.exists(scoped.name, f'{scoped.name}-exists') == 0
+    assert await r.exists(other.name) == 1
+    assert scope.close() == 0
+
+
+async def test_scope_deletes_its_keys_when_dropped():
+    r = Redis.from_url(REDIS_URL)
+    scope = TestLimiterScope(redis_urls=[REDIS_URL])
+    scoped = Semaphore(scope.name(), 1, redis_url=REDIS_URL)
+    async with scoped:
+        pass
+    assert await r.exists(scoped.name) == 1
+
+    del scope
+    gc.collect()
+    assert await r.exists(scoped.name) == 0
@@ -1,14 +1,26 @@
 import asyncio
 import logging
+import os
 import re
+import socket
+import threading
+import time
 from datetime import datetime
-from uuid import uuid4
 
 import pytest
 from redis.asyncio.client import Monitor, Redis
-from self_limiters import MaxSleepExceededError, Semaphore
+from self_limiters import (
+    AbortedError,
+    Acquisition,
+    MaxSleepExceededError,
+    RedisError,
+    ScriptError,
+    Semaphore,
+    WouldDeadlockError,
+    transfer_capacity,
+)
 
-from .conftest import delta_to_seconds, run, semaphore_factory
+from .conftest import delta_to_seconds, limiter_name, run, semaphore_factory
 
 logger = logging.getLogger(__name__)
 
@@ -30,7 +42,7 @@ async def test_semaphore_runtimes(n, capacity, sleep, timeout):
     a Semaphore with a capacity of 5, where each instance sleeps 1 second, then it should
     always take 1 >= seconds to run those.
     """
-    name = f'runtimes-{uuid4()}'
+    name = limiter_name('runtimes')
     tasks = [
         asyncio.create_task(run(semaphore_factory(name=name, capacity=capacity), duration=sleep)) for _ in range(n)
     ]
@@ -83,6 +95,7 @@ def test_repr():
         ({'capacity': 2.2}, TypeError),
         ({'capacity': None}, TypeError),
         ({'capacity': 'test'}, TypeError),
+        ({'capacity': 2**32 - 1}, ValueError),
         ({'redis_url': 'redis://a.b'}, None),
         ({'redis_url': 1}, TypeError),
         ({'redis_url': True}, TypeError),
@@ -100,25 +113,291 @@ def test_init_types(config, e):
         semaphore_factory(**config)()
 
 
+async def test_prefix():
+    """
+    Semaphores with the same name but different prefixes shouldn't share state.
+    """
+    name = limiter_name('prefix-test')
+    first = semaphore_factory(name=name, capacity=1, max_sleep=0.2, prefix='app-a:')()
+    second = semaphore_factory(name=name, capacity=1, max_sleep=0.2, prefix='app-b:')()
+    assert first.name == f'app-a:{name}'
+    assert second.name == f'app-b:{name}'
+
+    # Both can be entered at once, though each has a capacity of 1
+    async with first, second:
+        pass
+
+
+def test_prefix_validation():
+    with pytest.raises(ValueError, match='Prefix must not be empty'):
+        semaphore_factory(prefix='')()
+
+
+async def test_large_capacity():
+    """
+    Capacities beyond what Lua's `unpack` can handle in one go should still create a full list.
+    """
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    semaphore = semaphore_factory(capacity=10_000)()
+    async with semaphore:
+        assert await r.llen(semaphore.name) == 9_999
+
+
 @pytest.mark.filterwarnings('ignore::RuntimeWarning')
 async def test_max_sleep():
-    name = uuid4().hex[:6]
-    with pytest.raises(MaxSleepExceededError, match='Max sleep exceeded waiting for Semaphore'):
+    name = limiter_name()
+    with pytest.raises(MaxSleepExceededError, match='Max sleep exceeded waiting for Semaphore') as e:
         await asyncio.gather(
             *[asyncio.create_task(run(semaphore_factory(name=name, max_sleep=1), 1)) for _ in range(3)]
         )
 
+    # The error breaks down where the time was spent
+    assert re.search(
+        r'after [0-9.]+s connecting to redis, [0-9.]+s creating the semaphore, and [0-9.]+s waiting in the queue',
+        str(e.value),
+    )
+
+
+async def test_wait_callback():
+    name = limiter_name()
+    calls = []
+
+    def callback(waited, position):
+        calls.append((waited, position))
+        raise Exception('Should be swallowed')
+
+    holder = asyncio.create_task(run(semaphore_factory(name=name), 2.5))
+    await asyncio.sleep(0.1)
+    await run(semaphore_factory(name=name, wait_callback=callback, wait_callback_interval=1), 0)
+    await holder
+
+    assert len(calls) >= 1
+    assert all(waited >= 1 for waited, _ in calls)
+
+
+async def test_latency_callback():
+    """
+    The latency callback should be called once per acquire, without counting the time spent waiting.
+    """
+    latencies = []
+
+    def callback(latency):
+        latencies.append(latency)
+        raise Exception('Should be swallowed')
+
+    name = limiter_name()
+    holder = asyncio.create_task(run(semaphore_factory(name=name), 1))
+    await asyncio.sleep(0.1)
+    await run(semaphore_factory(name=name, latency_callback=callback), 0)
+    await holder
+
+    assert len(latencies) == 1
+    assert 0 < latencies[0] < 0.5
+
+
+def test_wait_callback_interval_validation():
+    with pytest.raises(ValueError, match='Wait callback interval must be greater than 0'):
+        semaphore_factory(wait_callback_interval=0)()
+
+
+async def test_fair_semaphore_order():
+    """
+    Waiters should be let through in the order they arrived.
+    """
+    name = limiter_name()
+    order = []
+
+    async def _run(i: int):
+        async with semaphore_factory(name=name, fair=True)():
+            order.append(i)
+            await asyncio.sleep(0.05)
+
+    tasks = []
+    for i in range(5):
+        tasks.append(asyncio.create_task(_run(i)))
+        await asyncio.sleep(0.05)
+
+    await asyncio.gather(*tasks)
+    assert order == list(range(5))
+
+
+@pytest.mark.filterwarnings('ignore::RuntimeWarning')
+async def test_fair_semaphore_max_sleep():
+    name = limiter_name()
+    with pytest.raises(MaxSleepExceededError, match='Max sleep exceeded waiting for Semaphore'):
+        await asyncio.gather(
+            *[asyncio.create_task(run(semaphore_factory(name=name, max_sleep=1, fair=True), 1)) for _ in range(3)]
+        )
+
+
+async def test_entered_and_exited_counts():
+    semaphore = semaphore_factory(capacity=2)()
+    assert semaphore.entered_count == semaphore.exited_count == 0
+
+    async with semaphore:
+        assert semaphore.entered_count == 1
+        assert semaphore.exited_count == 0
+
+    async with semaphore:
+        pass
+
+    assert semaphore.entered_count == semaphore.exited_count == 2
+
+
+async def test_untracked_permits_are_not_counted():
+    """
+    Permits taken with `try_acquire` and returned with `release` have no acquisition, and shouldn't skew the counts.
+    """
+    semaphore = semaphore_factory(capacity=2)()
+    assert await semaphore.try_acquire()
+    assert await semaphore.try_acquire()
+    await semaphore.release(2)
+    await semaphore.release()
+    assert semaphore.entered_count == semaphore.exited_count == 0
+
+    async with semaphore:
+        pass
+    assert semaphore.entered_count == semaphore.exited_count == 1
+
+
+@pytest.mark.parametrize('expiry', [None, 30])
+async def test_expiry(expiry):
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    semaphore = semaphore_factory(expiry=expiry)()
+    assert semaphore.expiry == expiry
+
+    async with semaphore:
+        pass
+
+    for key in [semaphore.name, f'{semaphore.name}-exists']:
+        ttl = await r.ttl(key)
+        if expiry is None:
+            assert ttl == -1
+        else:
+            assert 0 < ttl <= expiry
+
+
+def test_expiry_validation():
+    with pytest.raises(ValueError, match='Expiry must be greater than 0'):
+        semaphore_factory(expiry=0)()
+
+
+async def test_release_permits():
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    semaphore = semaphore_factory(capacity=3)()
+
+    for _ in range(3):
+        await semaphore.__aenter__()
+    assert await r.llen(semaphore.name) == 0
+
+    # Releasing all held permits at once nets to zero change
+    assert await semaphore.release(3) == 3
+    assert await r.llen(semaphore.name) == 3
+
+    # Releases can never push the semaphore above its capacity
+    assert await semaphore.release() == 0
+    assert await r.llen(semaphore.name) == 3
+
+    with pytest.raises(ValueError, match='Permits must be greater than 0'):
+        await semaphore.release(0)
+
+
+@pytest.mark.parametrize('fair', [True, False])
+async def test_acquire_with_id(fair):
+    semaphore = semaphore_factory(fair=fair)()
+    acquisition = await semaphore.acquire(id='fixed-id')
+    assert acquisition.holder_id == 'fixed-id'
+    await acquisition.release()
+
+    # Ids are generated when none is passed
+    acquisition = await semaphore.acquire()
+    assert len(acquisition.holder_id) == 10
+    await acquisition.release()
+
+
+async def test_acquisition_metadata():
+    semaphore = semaphore_factory(capacity=1)()
+
+    async def _hold():
+        async with semaphore:
+            await asyncio.sleep(0.3)
+
+    task = asyncio.create_task(_hold())
+    await asyncio.sleep(0.05)
+
+    async with semaphore as acquisition:
+        assert len(acquisition.holder_id) == 10
+        assert acquisition.waited_ms >= 200
+        assert not acquisition.released
+    assert acquisition.released
+    await task
+
+
+async def test_acquisition_is_released_once():
+    """
+    Releasing explicitly inside a context manager shouldn't release a second permit on exit.
+    """
+    semaphore = semaphore_factory(capacity=1)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    async with semaphore as acquisition:
+        await acquisition.release()
+        await acquisition.release()
+        assert acquisition.released
+
+    assert semaphore.exited_count == 1
+    assert await r.llen(semaphore.name) == 1
+
+
+async def test_quiet(caplog):
+    with caplog.at_level(logging.DEBUG):
+        await run(semaphore_factory(quiet=True), 0)
+    assert not [r for r in caplog.records if 'Acquired semaphore' in r.getMessage()]
+    assert not [r for r in caplog.records if 'Released' in r.getMessage()]
+
+
+async def test_recovers_from_expiry_while_waiting():
+    """
+    If the semaphore expires while we wait, we should recreate it rather than wait forever.
+    """
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    name = limiter_name()
+    holder = semaphore_factory(name=name, capacity=1)()
+    acquisition = await holder.acquire()
+
+    waiter = asyncio.create_task(run(semaphore_factory(name=name, capacity=1, expiry=1), 0))
+    await asyncio.sleep(0.2)
+
+    # Simulate the keys expiring between the create script and `blpop`
+    await r.delete(f'{holder.name}-exists')
+
+    await asyncio.wait_for(waiter, 3)
+    await acquisition.release()
+
+
+async def test_dedicated_connection():
+    """
+    Waiters shouldn't exhaust the pool when waiting on dedicated connections.
+    """
+    semaphore = semaphore_factory(capacity=1, connection_pool_size=1, dedicated_connection=True)()
+
+    async def _run():
+        async with semaphore:
+            await asyncio.sleep(0.1)
+
+    await asyncio.wait_for(asyncio.gather(*[asyncio.create_task(_run()) for _ in range(5)]), 2)
+
 
 async def test_redis_instructions():
     r = Redis.from_url('redis://127.0.0.1:6389')
-    name = uuid4().hex
+    name = limiter_name()
 
     m: Monitor
     async with r.monitor() as m:
         await m.connect()
         await run(semaphore_factory(name=name, expiry=1), 0)
 
-        # We expect the eval to generate 7 calls
+        # We expect the eval to generate 10 calls
         commands = [
             # EVALSHA
             str(await m.connection.read_response()),
@@ -128,6 +407,12 @@ async def test_redis_instructions():
             str(await m.connection.read_response()),
             # BLPOP
             str(await m.connection.read_response()),
+            # EVALSHA
+            str(await m.connection.read_response()),
+            # EXISTS
+            str(await m.connection.read_response()),
+            # LLEN
+            str(await m.connection.read_response()),
             # LPUSH
             str(await m.connection.read_response()),
             # EXPIRE
@@ -148,8 +433,871 @@ async def test_redis_instructions():
         assert 'RPUSH' in commands[2]
         assert f'__self-limiters:{name}' in commands[2]
         assert 'BLPOP' in commands[3]
-        assert 'LPUSH' in commands[4]
-        assert 'EXPIRE' in commands[5]
-        assert f'__self-limiters:{name}' in commands[5]
-        assert 'EXPIRE' in commands[6]
-        assert f'__self-limiters:{name}-exists' in commands[6]
+        assert 'EVALSHA' in commands[4]
+        assert 'EXISTS' in commands[5]
+        assert f'__self-limiters:{name}-exists' in commands[5]
+        assert 'LLEN' in commands[6]
+        assert 'LPUSH' in commands[7]
+        assert 'EXPIRE' in commands[8]
+        assert f'__self-limiters:{name}' in commands[8]
+        assert 'EXPIRE' in commands[9]
+        assert f'__self-limiters:{name}-exists' in commands[9]
+
+        # Expiries are refreshed from within the release script, atomically with the push
+        assert 'lua' in commands[7]
+        assert 'lua' in commands[8]
+        assert 'lua' in commands[9]
+
+
+async def test_with_key():
+    semaphore = semaphore_factory(capacity=1)()
+    tenant_a = semaphore.with_key('a')
+    tenant_b = semaphore.with_key('b')
+    assert tenant_a.name == f'{semaphore.name}:a'
+    assert tenant_a.capacity == semaphore.capacity
+
+    # Keys are limited separately, so one tenant can't block another
+    async with tenant_a:
+        await asyncio.wait_for(run(lambda: tenant_b, 0), 0.5)
+
+    with pytest.raises(ValueError, match='Key suffix must not be empty'):
+        semaphore.with_key('')
+
+
+async def test_with_redis_url():
+    semaphore = semaphore_factory(capacity=1)()
+    routed = semaphore.with_redis_url('redis://127.0.0.1:6389/1')
+    assert routed.name == semaphore.name
+
+    # Each server has its own semaphore, so acquiring on one doesn't block the other
+    async with routed:
+        await asyncio.wait_for(run(lambda: semaphore, 0), 0.5)
+        assert await Redis.from_url('redis://127.0.0.1:6389/1').exists(f'{semaphore.name}-exists')
+
+    # Handles for the same url share the cached pools, and can be derived from routed handles
+    async with semaphore.with_redis_url('redis://127.0.0.1:6389/1').with_key('a'):
+        pass
+
+    with pytest.raises(RedisError, match='Failed to parse redis url'):
+        semaphore.with_redis_url('not a url')
+
+
+async def test_actual_capacity():
+    name = limiter_name()
+    semaphore = semaphore_factory(name=name, capacity=2)()
+    assert await semaphore.actual_capacity() is None
+
+    async with semaphore:
+        assert await semaphore.actual_capacity() == 2
+
+    # Instances configured differently see the capacity the semaphore was created with
+    drifted = semaphore_factory(name=name, capacity=5)()
+    assert await drifted.actual_capacity() == 2
+    assert drifted.capacity == 5
+
+
+async def test_exists():
+    semaphore = semaphore_factory(capacity=1)()
+
+    # Checking doesn't create the semaphore
+    assert await semaphore.exists() is False
+    assert await semaphore.exists() is False
+    assert (await semaphore.snapshot())['available'] is None
+
+    # A fully acquired semaphore still exists
+    async with semaphore:
+        assert await semaphore.exists() is True
+    assert await semaphore.exists() is True
+
+
+@pytest.mark.parametrize('counter', [False, True])
+async def test_available(counter):
+    semaphore = semaphore_factory(capacity=3, counter=counter)()
+
+    # Checking doesn't create the semaphore
+    assert await semaphore.available() == 3
+    assert await semaphore.exists() is False
+
+    first = await semaphore.acquire()
+    assert await semaphore.available() == 2
+    second = await semaphore.acquire()
+    assert await semaphore.available() == 1
+
+    await first.release()
+    await second.release()
+    assert await semaphore.available() == 3
+
+
+async def test_waiters():
+    assert await semaphore_factory()().waiters() is None
+
+    semaphore = semaphore_factory(capacity=1, fair=True, max_sleep=5)()
+    assert await semaphore.waiters() == 0
+
+    async with semaphore:
+        tasks = [asyncio.create_task(run(lambda: semaphore, 0)) for _ in range(2)]
+        await asyncio.sleep(0.1)
+        assert await semaphore.waiters() == 2
+    await asyncio.gather(*tasks)
+    assert await semaphore.waiters() == 0
+
+
+async def test_snapshot_and_restore():
+    semaphore = semaphore_factory(capacity=3)()
+    assert (await semaphore.snapshot())['available'] is None
+
+    acquisition = await semaphore.acquire()
+    snapshot = await semaphore.snapshot()
+    assert snapshot == {'name': semaphore.name, 'capacity': 3, 'expiry': 30, 'available': 2}
+
+    # Restoring into another semaphore recreates the same state
+    other = semaphore_factory(capacity=3)()
+    await other.restore(snapshot)
+    assert (await other.snapshot())['available'] == 2
+    assert await other.actual_capacity() == 3
+
+    await acquisition.release()
+
+
+@pytest.mark.filterwarnings('ignore::RuntimeWarning')
+async def test_count_rejections():
+    name = limiter_name()
+    holder = semaphore_factory(name=name)()
+    acquisition = await holder.acquire()
+
+    # Rejections aren't counted by default
+    with pytest.raises(MaxSleepExceededError):
+        await run(semaphore_factory(name=name, max_sleep=0.1), 0)
+    assert await holder.rejection_rate() == 0
+
+    with pytest.raises(MaxSleepExceededError):
+        await run(semaphore_factory(name=name, max_sleep=0.1, count_rejections=True), 0)
+    assert await holder.rejection_rate() == 1
+
+    await acquisition.release()
+
+
+async def test_wait_stats():
+    semaphore = semaphore_factory()()
+    assert semaphore.wait_stats() == {'count': 0, 'p50': None, 'p90': None, 'p99': None}
+
+    acquisition = await semaphore.acquire()
+    asyncio.get_running_loop().call_later(0.2, lambda: asyncio.ensure_future(acquisition.release()))
+    async with semaphore:
+        pass
+
+    stats = semaphore.wait_stats()
+    assert stats['count'] == 2
+    assert stats['p50'] < 100
+    assert 200 <= stats['p99'] < 400
+
+
+async def test_acquire_sharded():
+    semaphore = semaphore_factory(capacity=5)()
+    acquisitions = [await semaphore.acquire_sharded(shards=2) for _ in range(5)]
+
+    # The capacity is spread across the shards, with the remainder going to the lowest
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    assert await r.get(f'{semaphore.name}-0-exists') == b'3'
+    assert await r.get(f'{semaphore.name}-1-exists') == b'2'
+
+    # Fails right away once all shards are exhausted
+    with pytest.raises(MaxSleepExceededError):
+        await semaphore.acquire_sharded(shards=2)
+
+    # Permits are released to the shard they were taken from
+    await acquisitions[0].release()
+    assert await r.llen(f'{semaphore.name}-0') + await r.llen(f'{semaphore.name}-1') == 1
+    await semaphore.acquire_sharded(shards=2)
+
+
+def test_acquire_sharded_validation():
+    with pytest.raises(ValueError, match='Shards must be greater than 0'):
+        semaphore_factory(capacity=2)().acquire_sharded(shards=3)
+    with pytest.raises(ValueError, match='not supported when fair=True'):
+        semaphore_factory(capacity=2, fair=True)().acquire_sharded(shards=2)
+
+
+@pytest.mark.filterwarnings('ignore::RuntimeWarning')
+async def test_no_lua():
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    name = limiter_name()
+    semaphore = semaphore_factory(name=name, capacity=2, max_sleep=0.5, no_lua=True)()
+    assert semaphore.no_lua is True
+
+    m: Monitor
+    async with r.monitor() as m:
+        await m.connect()
+        acquisitions = [await semaphore.acquire() for _ in range(2)]
+        with pytest.raises(MaxSleepExceededError):
+            await semaphore.acquire()
+        for acquisition in acquisitions:
+            await acquisition.release()
+
+        commands = []
+        with pytest.raises(asyncio.TimeoutError):
+            while True:
+                commands.append(str(await asyncio.wait_for(timeout=1, fut=m.connection.read_response())))
+
+    # Only plain commands are used
+    assert commands
+    assert not [c for c in commands if 'EVALSHA' in c or 'lua' in c]
+
+    # Releasing never pushes the queue beyond its capacity
+    assert await r.llen(semaphore.name) == 2
+    assert await semaphore.release(5) == 0
+    assert await r.llen(semaphore.name) == 2
+
+
+def test_no_lua_validation():
+    with pytest.raises(ValueError, match="can't be used with no_lua=True"):
+        semaphore_factory(fair=True, no_lua=True)()
+
+
+@pytest.mark.filterwarnings('ignore::RuntimeWarning')
+async def test_max_sleep_never_drops_permits():
+    semaphore = semaphore_factory(capacity=1, max_sleep=1)()
+    holder = await semaphore.acquire()
+
+    # Release the permit right around when the waiter gives up
+    asyncio.get_running_loop().call_later(0.95, lambda: asyncio.ensure_future(holder.release()))
+    try:
+        acquisition = await semaphore.acquire()
+        await acquisition.release()
+    except MaxSleepExceededError:
+        pass
+
+    # Whether the waiter got the permit or not, it must be back in the semaphore
+    assert (await semaphore.snapshot())['available'] == 1
+
+
+async def test_reconnect_while_waiting():
+    """
+    Waiters whose connection drops mid-wait should reconnect and keep waiting, rather than fail.
+    """
+    name = limiter_name()
+    acquisition = await semaphore_factory(name=name, capacity=1)().acquire()
+    waiter = asyncio.create_task(run(semaphore_factory(name=name, capacity=1, max_sleep=5), 0))
+    await asyncio.sleep(0.3)
+
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    killed = 0
+    for client in await r.client_list():
+        if client['cmd'] == 'blpop':
+            await r.client_kill_filter(_id=client['id'])
+            killed += 1
+    assert killed == 1
+
+    await asyncio.sleep(0.3)
+    assert not waiter.done()
+    await acquisition.release()
+    await asyncio.wait_for(waiter, 2)
+
+
+@pytest.mark.parametrize('fair', [False, True])
+async def test_abort_all(fair):
+    name = limiter_name()
+    semaphore = semaphore_factory(name=name, capacity=1, fair=fair)()
+    acquisition = await semaphore.acquire()
+
+    waiters = [asyncio.create_task(run(semaphore_factory(name=name, capacity=1, fair=fair), 0)) for _ in range(3)]
+    await asyncio.sleep(0.3)
+    await semaphore_factory(name=name, capacity=1, fair=fair)().abort_all()
+
+    results = await asyncio.wait_for(asyncio.gather(*waiters, return_exceptions=True), 2)
+    assert all(isinstance(result, AbortedError) for result in results)
+
+    # The holder keeps its permit, and later clients aren't aborted
+    assert not acquisition.released
+    await acquisition.release()
+    await asyncio.wait_for(run(semaphore_factory(name=name, capacity=1, fair=fair), 0), 2)
+
+
+@pytest.mark.parametrize(
+    'backoff, delays',
+    [
+        ('constant', [0.05, 0.05, 0.05, 0.05]),
+        ('linear', [0.05, 0.1, 0.15, 0.5]),
+        ('exponential', [0.05, 0.1, 0.2, 1]),
+    ],
+)
+def test_backoff_delay(backoff, delays):
+    semaphore = semaphore_factory(fair=True, backoff=backoff, backoff_interval=0.05)()
+    assert semaphore.backoff == backoff
+    assert semaphore.backoff_interval == pytest.approx(0.05)
+    assert [semaphore.backoff_delay(position) for position in [0, 1, 2, 9]] == pytest.approx(delays)
+
+
+def test_backoff_defaults_and_validation():
+    semaphore = semaphore_factory()()
+    assert semaphore.backoff == 'constant'
+    assert semaphore.backoff_delay(100) == pytest.approx(0.02)
+
+    with pytest.raises(ValueError, match="Backoff must be one of 'constant', 'linear' or 'exponential'"):
+        semaphore_factory(backoff='quadratic')()
+    for interval in [0, float('nan')]:
+        with pytest.raises(ValueError, match='Backoff interval must be greater than 0'):
+            semaphore_factory(backoff_interval=interval)()
+
+
+@pytest.mark.parametrize('backoff', ['linear', 'exponential'])
+async def test_fair_semaphore_with_backoff(backoff):
+    pt = semaphore_factory(capacity=1, fair=True, backoff=backoff)
+    await asyncio.wait_for(asyncio.gather(*[run(pt, 0.05) for _ in range(5)]), 5)
+
+
+async def test_counter_semaphore_with_huge_capacity():
+    """
+    Counter semaphores should store a single integer, however large the capacity.
+    """
+    semaphore = semaphore_factory(capacity=1_000_000, counter=True)()
+    assert semaphore.counter
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    acquisition = await semaphore.acquire()
+    assert await r.type(semaphore.name) == b'string'
+    assert int(await r.get(semaphore.name)) == 999_999
+    assert (await semaphore.snapshot())['available'] == 999_999
+
+    await acquisition.release()
+    assert int(await r.get(semaphore.name)) == 1_000_000
+
+    # Releasing never exceeds the capacity
+    assert await semaphore.release(5) == 0
+    assert (await semaphore.snapshot())['available'] == 1_000_000
+
+
+async def test_counter_semaphore_limits_concurrency():
+    pt = semaphore_factory(capacity=2, counter=True)
+    active, max_active = 0, 0
+
+    async def run_counted():
+        nonlocal active, max_active
+        async with pt():
+            active += 1
+            max_active = max(max_active, active)
+            await asyncio.sleep(0.05)
+            active -= 1
+
+    await asyncio.wait_for(asyncio.gather(*[run_counted() for _ in range(6)]), 5)
+    assert max_active == 2
+
+
+async def test_counter_semaphore_max_sleep_and_abort():
+    name = limiter_name()
+    semaphore = semaphore_factory(name=name, counter=True)()
+    acquisition = await semaphore.acquire()
+    with pytest.raises(MaxSleepExceededError):
+        await run(semaphore_factory(name=name, counter=True, max_sleep=0.1), 0)
+
+    waiter = asyncio.create_task(run(semaphore_factory(name=name, counter=True), 0))
+    await asyncio.sleep(0.1)
+    await semaphore.abort_all()
+    with pytest.raises(AbortedError):
+        await asyncio.wait_for(waiter, 1)
+    await acquisition.release()
+
+
+def test_counter_semaphore_validation():
+    for kwargs in [{'fair': True}, {'no_lua': True}]:
+        with pytest.raises(ValueError, match="Counter semaphores can't be used with fair=True or no_lua=True"):
+            semaphore_factory(counter=True, **kwargs)()
+
+
+@pytest.mark.parametrize('suppress, expected', [(True, ValueError), (False, ScriptError)])
+async def test_suppress_release_errors(suppress, expected):
+    """
+    Release errors should only replace the error raised in the body when not suppressed.
+    """
+    semaphore = semaphore_factory(suppress_release_errors=suppress)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    with pytest.raises(expected):
+        async with semaphore:
+            # The release script can't push to a key holding a string
+            await r.delete(semaphore.name)
+            await r.set(semaphore.name, 'test')
+            raise ValueError('Raised in the body')
+
+
+async def test_holders():
+    semaphore = semaphore_factory(capacity=2, track_holders=True)()
+    assert semaphore.track_holders
+
+    before = time.time()
+    async with semaphore as first:
+        second = await semaphore.acquire()
+        holders = await semaphore.holders()
+        # Both may have acquired in the same millisecond, so their order isn't checked
+        assert {holder['id'] for holder in holders} == {first.holder_id, second.holder_id}
+        for holder in holders:
+            assert holder['host'] == socket.gethostname()
+            assert holder['pid'] == os.getpid()
+            assert before - 1 <= holder['since'] <= time.time() + 1
+
+        await second.release()
+        assert [holder['id'] for holder in await semaphore.holders()] == [first.holder_id]
+
+    assert await semaphore.holders() == []
+
+
+async def test_holders_not_tracked_by_default():
+    semaphore = semaphore_factory()()
+    assert not semaphore.track_holders
+    async with semaphore:
+        assert await semaphore.holders() == []
+
+
+async def test_transfer_capacity():
+    low = semaphore_factory(capacity=4)()
+    high = semaphore_factory(capacity=1)()
+
+    assert await transfer_capacity(low, high, 3) == 3
+    assert (low.capacity, high.capacity) == (1, 4)
+    assert await low.actual_capacity() == 1
+    assert await high.actual_capacity() == 4
+    assert (await low.snapshot())['available'] == 1
+    assert (await high.snapshot())['available'] == 4
+
+    # Permits released after a transfer are capped at the new capacity
+    acquisitions = [await high.acquire() for _ in range(4)]
+    for acquisition in acquisitions:
+        await acquisition.release()
+    assert (await high.snapshot())['available'] == 4
+
+
+async def test_transfer_capacity_without_enough_free_permits():
+    source = semaphore_factory(capacity=3)()
+    destination = semaphore_factory(capacity=1)()
+    acquisition = await source.acquire()
+
+    # Held permits can't be moved, so nothing is moved unless partial transfers are allowed
+    assert await transfer_capacity(source, destination, 3) == 0
+    assert (source.capacity, destination.capacity) == (3, 1)
+
+    assert await transfer_capacity(source, destination, 3, partial=True) == 2
+    assert (source.capacity, destination.capacity) == (1, 3)
+
+    await acquisition.release()
+    assert (await source.snapshot())['available'] == 1
+
+
+def test_transfer_capacity_validation():
+    semaphore = semaphore_factory(capacity=2)()
+    with pytest.raises(ValueError, match="Can't transfer capacity from a semaphore to itself"):
+        transfer_capacity(semaphore, semaphore, 1)
+    with pytest.raises(ValueError, match="Permits must be greater than 0, and at most the source's capacity"):
+        transfer_capacity(semaphore, semaphore_factory()(), 3)
+    with pytest.raises(ValueError, match="can't be transferred"):
+        transfer_capacity(semaphore, semaphore_factory(counter=True)(), 1)
+
+
+async def test_would_deadlock():
+    """
+    Re-entering a semaphore the current task holds all the capacity of should raise, rather than hang.
+    """
+    semaphore = semaphore_factory(capacity=2)()
+    async with semaphore:
+        async with semaphore:
+            with pytest.raises(WouldDeadlockError) as e:
+                async with semaphore:
+                    pass
+            assert e.value.limiter_name == semaphore.name
+            with pytest.raises(WouldDeadlockError):
+                await semaphore.acquire()
+
+    # Once released, the semaphore can be entered again
+    await asyncio.wait_for(run(lambda: semaphore, 0), 1)
+
+
+async def test_would_deadlock_ignores_parent_task():
+    """
+    Tasks created inside an `async with` block inherit its acquisitions, but should wait for them rather than raise.
+    """
+    semaphore = semaphore_factory()()
+    async with semaphore:
+        worker = asyncio.create_task(run(lambda: semaphore, 0))
+        await asyncio.sleep(0.1)
+        assert not worker.done()
+    await asyncio.wait_for(worker, 1)
+
+
+async def test_failed_acquire_is_not_exited():
+    """
+    A failed acquire is never exited, so exiting the block around it should release the outer permit.
+    """
+    semaphore = semaphore_factory(max_sleep=0.1)()
+    async with semaphore:
+        with pytest.raises(MaxSleepExceededError):
+            async with semaphore:
+                pass
+
+    await asyncio.wait_for(run(lambda: semaphore, 0), 1)
+
+
+async def test_would_deadlock_with_max_sleep():
+    """
+    With a max sleep, the acquire can't hang forever, so it's left to time out.
+    """
+    semaphore = semaphore_factory(max_sleep=0.1)()
+    async with semaphore:
+        with pytest.raises(MaxSleepExceededError):
+            async with semaphore:
+                pass
+
+
+@pytest.mark.parametrize('fair', [False, True])
+async def test_acquire_with_cancel(fair):
+    semaphore = semaphore_factory(fair=fair)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    acquisition = await semaphore.acquire()
+
+    cancel = asyncio.Event()
+    waiter = asyncio.create_task(semaphore.acquire(cancel=cancel))
+    await asyncio.sleep(0.1)
+    assert not waiter.done()
+    cancel.set()
+    with pytest.raises(AbortedError, match='Cancelled while waiting'):
+        await asyncio.wait_for(waiter, 1)
+    if fair:
+        assert await r.llen(f'{semaphore.name}-queue') == 0
+
+    # The permit is still there for the next waiter
+    await acquisition.release()
+    other = await asyncio.wait_for(semaphore.acquire(cancel=asyncio.Event()), 1)
+    await other.release()
+
+
+@pytest.mark.parametrize('fair, counter', [(False, False), (True, False), (False, True)])
+async def test_cancel_while_permit_is_pushed(fair, counter):
+    """
+    Cancelling as a permit is pushed must never lose it: either the waiter keeps it, or it's put back.
+    """
+    semaphore = semaphore_factory(fair=fair, counter=counter)()
+    for _ in range(20):
+        acquisition = await semaphore.acquire()
+        cancel = asyncio.Event()
+        waiter = asyncio.create_task(semaphore.acquire(cancel=cancel))
+        await asyncio.sleep(0.05)
+        release = asyncio.create_task(acquisition.release())
+        cancel.set()
+        await release
+        try:
+            other = await asyncio.wait_for(waiter, 1)
+        except AbortedError:
+            pass
+        else:
+            await other.release()
+
+        # The permit is free again either way
+        await asyncio.wait_for(run(lambda: semaphore, 0), 1)
+
+
+def test_acquire_with_cancel_validation():
+    with pytest.raises(TypeError, match='Cancel must be an event, with an is_set method'):
+        semaphore_factory()().acquire(cancel=object())
+
+
+@pytest.mark.parametrize('counter', [False, True])
+async def test_dry_run(caplog, counter):
+    """
+    Dry run acquires should log whether they would have waited, without waiting, taking
+    permits from a shadow semaphore rather than the semaphore itself.
+    """
+    name = limiter_name('dry-run')
+    semaphore = semaphore_factory(name=name, counter=counter)()
+    dry_run = semaphore_factory(name=name, counter=counter, dry_run=True)()
+    assert dry_run.dry_run is True
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    async with dry_run:
+        # The semaphore itself isn't created by dry runs
+        assert await r.exists(semaphore.name, f'{semaphore.name}-exists') == 0
+        assert await r.exists(f'{semaphore.name}-dry-run') == 1
+
+        # The only permit of the shadow semaphore is held, so the next dry run would have waited
+        with caplog.at_level(logging.INFO):
+            acquisition = await asyncio.wait_for(dry_run.acquire(), 1)
+        assert [r for r in caplog.records if 'would have waited' in r.getMessage()]
+        await acquisition.release()
+
+        # Enforced acquires don't see the dry run traffic
+        await asyncio.wait_for(run(lambda: semaphore, 0), 1)
+
+    # The permit is back once the dry run exits
+    caplog.clear()
+    with caplog.at_level(logging.INFO):
+        async with dry_run:
+            pass
+    assert not [r for r in caplog.records if 'would have waited' in r.getMessage()]
+    assert dry_run.entered_count == dry_run.exited_count == 2
+
+
+def test_dry_run_sharded():
+    with pytest.raises(ValueError, match='dry_run=True'):
+        semaphore_factory(capacity=2, dry_run=True)().acquire_sharded(2)
+
+
+def test_sync_context_manager():
+    """
+    Threads should be able to use the semaphore with a plain `with`, limited like tasks are.
+    """
+    semaphore = semaphore_factory(capacity=2)()
+    active, peak = 0, 0
+    lock = threading.Lock()
+
+    def work():
+        nonlocal active, peak
+        with semaphore as acquisition:
+            assert isinstance(acquisition, Acquisition)
+            with lock:
+                active += 1
+                peak = max(peak, active)
+            time.sleep(0.1)
+            with lock:
+                active -= 1
+
+    threads = [threading.Thread(target=work) for _ in range(6)]
+    before = time.monotonic()
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+
+    assert peak == 2
+    assert 0.3 <= time.monotonic() - before < 0.6
+    assert semaphore.entered_count == semaphore.exited_count == 6
+
+
+def test_sync_context_manager_max_sleep():
+    semaphore = semaphore_factory(max_sleep=0.1)()
+    errors = []
+
+    def hold():
+        with semaphore:
+            time.sleep(0.5)
+
+    def wait():
+        try:
+            with semaphore:
+                pass
+        except MaxSleepExceededError as e:
+            errors.append(e)
+
+    holder = threading.Thread(target=hold)
+    holder.start()
+    time.sleep(0.1)
+    waiter = threading.Thread(target=wait)
+    waiter.start()
+    waiter.join()
+    holder.join()
+
+    assert len(errors) == 1
+    assert errors[0].limiter_name == semaphore.name
+
+
+@pytest.mark.parametrize('counter', [False, True])
+async def test_weighted_acquire(counter):
+    """
+    Weighted acquires should take all their permits at once, and return them all on release.
+    """
+    semaphore = semaphore_factory(capacity=4, counter=counter)()
+    heavy = await semaphore.acquire(weight=3)
+    assert heavy.weight == 3
+    assert (await semaphore.snapshot())['available'] == 1
+
+    # Two permits aren't free, so the acquire waits without taking the one that is
+    waiter = asyncio.create_task(semaphore.acquire(weight=2))
+    await asyncio.sleep(0.1)
+    assert not waiter.done()
+    assert (await semaphore.snapshot())['available'] == 1
+
+    await heavy.release()
+    light = await asyncio.wait_for(waiter, 1)
+    assert (await semaphore.snapshot())['available'] == 2
+
+    await light.release()
+    assert (await semaphore.snapshot())['available'] == 4
+
+
+async def test_weighted_acquire_max_sleep():
+    semaphore = semaphore_factory(capacity=2, max_sleep=0.1)()
+    acquisition = await semaphore.acquire()
+    with pytest.raises(MaxSleepExceededError):
+        await semaphore.acquire(weight=2)
+    await acquisition.release()
+    assert (await semaphore.snapshot())['available'] == 2
+
+
+def test_weighted_acquire_validation():
+    semaphore = semaphore_factory(capacity=2)()
+    for weight in [0, 3]:
+        with pytest.raises(ValueError, match='Weight must be greater than 0, and at most the capacity'):
+            semaphore.acquire(weight=weight)
+    with pytest.raises(ValueError, match='not supported when fair=True or no_lua=True'):
+        semaphore_factory(capacity=2, fair=True)().acquire(weight=2)
+
+
+@pytest.mark.parametrize('counter', [False, True])
+async def test_try_acquire(counter):
+    """
+    Try-acquires should take a free permit, or return False right away when there's none.
+    """
+    semaphore = semaphore_factory(capacity=1, counter=counter)()
+    assert await semaphore.try_acquire() is True
+    before = time.monotonic()
+    assert await semaphore.try_acquire() is False
+    assert time.monotonic() - before < 0.1
+
+    await semaphore.release()
+    assert (await semaphore.snapshot())['available'] == 1
+    assert await semaphore.try_acquire() is True
+    await semaphore.release()
+
+
+async def test_try_acquire_bypasses_fair_queue():
+    # Waiters poll slowly, so the released permit is still there when we try
+    semaphore = semaphore_factory(capacity=1, fair=True, backoff_interval=0.5)()
+    acquisition = await semaphore.acquire()
+    waiter = asyncio.create_task(semaphore.acquire())
+    await asyncio.sleep(0.1)
+
+    # The permit released goes to whoever takes it first, rather than the waiter at the head of the queue
+    await acquisition.release()
+    assert await semaphore.try_acquire() is True
+    assert not waiter.done()
+    await semaphore.release()
+    await (await asyncio.wait_for(waiter, 2)).release()
+
+
+async def test_fencing_token():
+    """
+    Fencing tokens should increase with every acquisition, across instances for the same semaphore.
+    """
+    name = limiter_name('fencing')
+    semaphore = semaphore_factory(name=name, capacity=2, fencing=True)()
+    other = semaphore_factory(name=name, capacity=2, fencing=True)()
+    assert semaphore.fencing is True
+
+    tokens = []
+    for limiter in [semaphore, other, semaphore, other]:
+        async with limiter as acquisition:
+            tokens.append(acquisition.fencing_token)
+    assert tokens == sorted(tokens)
+    assert len(set(tokens)) == 4
+
+    # The counter expires with the semaphore
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    assert 0 < await r.ttl(f'{semaphore.name}-fence') <= 30
+
+
+async def test_fencing_token_after_counter_expires():
+    """
+    Tokens should keep increasing after the counter expires, or is deleted.
+    """
+    semaphore = semaphore_factory(fencing=True)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    async with semaphore as acquisition:
+        first = acquisition.fencing_token
+    await r.delete(f'{semaphore.name}-fence')
+    async with semaphore as acquisition:
+        assert acquisition.fencing_token > first
+
+
+async def test_fencing_token_disabled():
+    async with semaphore_factory()() as acquisition:
+        assert acquisition.fencing_token is None
+
+
+@pytest.mark.parametrize('renew_expiry', [True, False])
+async def test_renew_expiry(renew_expiry):
+    """
+    Holders should keep the semaphore from expiring while renewing, and stop renewing on release.
+    """
+    semaphore = semaphore_factory(capacity=1, expiry=1, renew_expiry=renew_expiry)()
+    assert semaphore.renew_expiry is renew_expiry
+
+    async with semaphore:
+        await asyncio.sleep(2.5)
+        assert await semaphore.exists() is renew_expiry
+
+    # The release refreshes the expiry, after which the semaphore expires as usual
+    await asyncio.sleep(2.5)
+    assert await semaphore.exists() is False
+
+
+def test_renew_expiry_validation():
+    with pytest.raises(ValueError, match='requires an expiry'):
+        semaphore_factory(expiry=None, renew_expiry=True)()
+
+
+@pytest.mark.filterwarnings('ignore::RuntimeWarning')
+@pytest.mark.parametrize('counter', [False, True])
+@pytest.mark.parametrize('reclaim', [True, False])
+async def test_reclaim(counter, reclaim):
+    """
+    Permits of holders that never release should be reclaimed once their lease is older than the expiry.
+    """
+    name = limiter_name('reclaim')
+    crashed = semaphore_factory(name=name, capacity=1, expiry=1, counter=counter, reclaim=reclaim)()
+    assert crashed.reclaim is reclaim
+
+    # Acquire without ever releasing, like a holder that died
+    await crashed.acquire()
+    await asyncio.sleep(1.5)
+
+    other = semaphore_factory(name=name, capacity=1, expiry=1, counter=counter, reclaim=reclaim, max_sleep=1)()
+    if reclaim:
+        async with other:
+            pass
+    else:
+        with pytest.raises(MaxSleepExceededError):
+            await run(lambda: other, 0)
+
+
+async def test_reclaim_renewed_lease():
+    """
+    Holders renewing their lease shouldn't have their permits reclaimed, however long they hold them.
+    """
+    name = limiter_name('reclaim')
+    semaphore = semaphore_factory(name=name, capacity=1, expiry=1, reclaim=True, renew_expiry=True)()
+    other = semaphore_factory(name=name, capacity=1, expiry=1, reclaim=True, max_sleep=0.5)()
+
+    async with semaphore:
+        await asyncio.sleep(2)
+        with pytest.raises(MaxSleepExceededError):
+            await run(lambda: other, 0)
+
+    # The lease is removed on release
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    assert await r.zcard(f'{semaphore.name}-leases') == 0
+
+
+@pytest.mark.parametrize('kwargs', [{'expiry': None}, {'no_lua': True}])
+def test_reclaim_validation(kwargs):
+    with pytest.raises(ValueError, match='Reclaiming permits requires an expiry and Lua scripts'):
+        semaphore_factory(reclaim=True, **kwargs)()
+
+
+async def test_position():
+    """
+    Fair acquisitions should know how many tickets were ahead of theirs on arrival, rather than after waiting.
+    """
+    name = limiter_name('position')
+    semaphore = semaphore_factory(name=name, capacity=1, fair=True)()
+
+    async def enter():
+        async with semaphore as acquisition:
+            await asyncio.sleep(0.2)
+        return acquisition.position
+
+    tasks = []
+    for _ in range(4):
+        tasks.append(asyncio.create_task(enter()))
+        # Let each waiter join the queue before the next one arrives
+        await asyncio.sleep(0.03)
+    assert await asyncio.gather(*tasks) == [0, 0, 1, 2]
+
+    async with semaphore_factory()() as acquisition:
+        assert acquisition.position is None
@@ -0,0 +1,67 @@
+import subprocess
+import sys
+import textwrap
+from uuid import uuid4
+
+import pytest
+import self_limiters
+
+from .conftest import composite_factory, semaphore_factory
+
+
+def test_shutdown_waits_for_releases():
+    """
+    Permits in flight are counted per process, and other tests may leave some unreleased, so this runs in its own.
+    """
+    script = textwrap.dedent(
+        f"""
+        import asyncio
+        import time
+
+        import self_limiters
+
+        async def main():
+            semaphore = self_limiters.Semaphore('{uuid4().hex[:6]}', 2, redis_url='redis://127.0.0.1:6389')
+            assert await self_limiters.shutdown(0) == 0
+            acquisition = await semaphore.acquire()
+            assert await self_limiters.shutdown(0.05) == 1
+
+            async def release_later():
+                await asyncio.sleep(0.1)
+                await acquisition.release()
+
+            task = asyncio.create_task(release_later())
+            before = time.monotonic()
+            assert await self_limiters.shutdown(1) == 0
+            assert time.monotonic() - before < 0.5
+            await task
+
+        asyncio.run(main())
+        """
+    )
+    subprocess.run([sys.executable, '-c', script], check=True)
+
+
+async def test_shutdown_counts_context_managers():
+    baseline = await self_limiters.shutdown(0)
+    for limiter in [semaphore_factory()(), composite_factory()()]:
+        async with limiter:
+            assert await self_limiters.shutdown(0) == baseline + 1
+        assert await self_limiters.shutdown(0) == baseline
+
+
+async def test_shutdown_ignores_untracked_permits():
+    """
+    Permits taken with `try_acquire` have no acquisition to release, so `shutdown` doesn't wait for them.
+    """
+    baseline = await self_limiters.shutdown(0)
+    semaphore = semaphore_factory()()
+    assert await semaphore.try_acquire()
+    assert await self_limiters.shutdown(0) == baseline
+    await semaphore.release()
+    assert await self_limiters.shutdown(0) == baseline
+
+
+def test_shutdown_validation():
+    with pytest.raises(ValueError, match='Timeout must not be negative'):
+        self_limiters.shutdown(-1)
@@ -0,0 +1,63 @@
+import inspect
+
+import pytest
+from self_limiters import (
+    CompositeLimiter,
+    QuorumSemaphore,
+    Semaphore,
+    TokenBucket,
+    get,
+    purge,
+    register,
+    shutdown,
+    transfer_capacity,
+)
+
+
+@pytest.mark.parametrize(
+    'cls, required',
+    [
+        (Semaphore, ['name', 'capacity']),
+        (TokenBucket, ['name', 'capacity', 'refill_frequency', 'refill_amount']),
+        (CompositeLimiter, ['name', 'capacity', 'refill_frequency', 'refill_amount', 'concurrency']),
+        (QuorumSemaphore, ['name', 'capacity', 'redis_urls']),
+    ],
+)
+def test_constructor_signatures(cls, required):
+    """
+    Constructors should expose their arguments to introspection, e.g., for help() and IDEs.
+    """
+    assert cls.__text_signature__
+    parameters = inspect.signature(cls).parameters
+    assert [name for name, p in parameters.items() if p.default is inspect.Parameter.empty] == required
+    assert parameters['max_sleep'].default is None
+
+
+@pytest.mark.parametrize('cls', [Semaphore, TokenBucket])
+def test_expiry_default(cls):
+    assert inspect.signature(cls).parameters['expiry'].default == 30
+
+
+@pytest.mark.parametrize(
+    'method, parameters',
+    [
+        (Semaphore.acquire, ['id', 'cancel', 'weight']),
+        (Semaphore.release, ['permits']),
+        (Semaphore.with_key, ['key_suffix']),
+        (Semaphore.with_redis_url, ['redis_url']),
+        (TokenBucket.with_redis_url, ['redis_url']),
+        (TokenBucket.wait_for_burst, ['n']),
+        (TokenBucket.with_share, ['tenant', 'share']),
+        (TokenBucket.with_idempotency_key, ['key', 'window']),
+        (TokenBucket.report, ['outcome', 'retry_after']),
+        (CompositeLimiter.limit, ['func']),
+        (register, ['name', 'limiter']),
+        (get, ['name']),
+        (transfer_capacity, ['from_semaphore', 'to_semaphore', 'n', 'partial']),
+        (purge, ['redis_url', 'older_than', 'dry_run', 'verify_tls', 'username', 'password', 'prefix']),
+        (shutdown, ['timeout']),
+    ],
+)
+def test_method_signatures(method, parameters):
+    assert method.__text_signature__
+    assert [p for p in inspect.signature(method).parameters if p != 'self'] == parameters
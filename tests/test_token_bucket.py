@@ -1,13 +1,15 @@
 import asyncio
 import logging
 import re
+import time
 from datetime import datetime
-from uuid import uuid4
 
 import pytest
-from self_limiters import MaxSleepExceededError
+import self_limiters
+from redis.asyncio.client import Redis
+from self_limiters import BacklogExceededError, MaxSleepExceededError
 
-from .conftest import delta_to_seconds, run, tokenbucket_factory
+from .conftest import delta_to_seconds, limiter_name, run, tokenbucket_factory
 
 logger = logging.getLogger(__name__)
 
@@ -15,14 +17,14 @@ logger = logging.getLogger(__name__)
 @pytest.mark.parametrize(
     'n, frequency, timeout',
     [
-        (10, 0.1, 1),
-        (10, 0.01, 0.1),
-        (2, 1, 2),
+        (10, 0.1, 0.9),
+        (10, 0.01, 0.09),
+        (2, 1, 1),
     ],
 )
 async def test_token_bucket_runtimes(n, frequency, timeout):
-    # Ensure n tasks never complete in less than n/(refill_frequency * refill_amount)
-    name = f'runtimes-{uuid4()}'
+    # Ensure n tasks never complete in less than (n - 1) * refill_frequency, since the first token is free
+    name = limiter_name('runtimes')
     tasks = [
         asyncio.create_task(run(tokenbucket_factory(name=name, capacity=1, refill_frequency=frequency), duration=0))
         for _ in range(n)
@@ -33,6 +35,22 @@ async def test_token_bucket_runtimes(n, frequency, timeout):
     assert timeout <= delta_to_seconds(datetime.now() - before)
 
 
+async def test_high_refill_rate_accuracy():
+    # At 1ms slots, the effective rate should stay within 25% of the configured rate
+    n, frequency = 200, 0.001
+    tb = tokenbucket_factory(capacity=1, refill_frequency=frequency)()
+
+    async def acquire():
+        async with tb:
+            pass
+
+    before = datetime.now()
+    await asyncio.gather(*[acquire() for _ in range(n)])
+    elapsed = delta_to_seconds(datetime.now() - before)
+    expected = (n - 1) * frequency
+    assert expected <= elapsed <= expected * 1.25
+
+
 async def test_sleep_is_non_blocking():
     async def _sleep(duration: float) -> None:
         await asyncio.sleep(duration)
@@ -59,6 +77,8 @@ def test_class_attributes():
     assert tb.capacity == 1
     assert tb.refill_frequency == 1.0
     assert tb.refill_amount == 1
+    assert tb.expiry == 30
+    assert tb.state_key == tb.name
 
     with pytest.raises(
         AttributeError, match="attribute 'refill_amount' of 'self_limiters.TokenBucket' objects is not writable"
@@ -83,12 +103,19 @@ def test_repr():
         ({'capacity': -1}, OverflowError),
         ({'capacity': None}, TypeError),
         ({'capacity': 'test'}, TypeError),
+        ({'capacity': 2**32 - 1}, ValueError),
+        ({'refill_amount': 2**32 - 1}, ValueError),
         ({'refill_frequency': 2.2}, None),
         ({'refill_frequency': 'test'}, TypeError),
         ({'refill_frequency': None}, TypeError),
         ({'refill_frequency': -1}, ValueError),
+        ({'refill_frequency': float('nan')}, ValueError),
+        ({'refill_frequency': float('inf')}, ValueError),
         ({'refill_amount': 1}, None),
-        ({'refill_amount': -1}, OverflowError),
+        ({'refill_amount': 0}, ValueError),
+        ({'refill_amount': -1}, ValueError),
+        ({'refill_amount': 0.5}, None),
+        ({'refill_amount': float('nan')}, ValueError),
         ({'refill_amount': 'test'}, TypeError),
         ({'refill_amount': None}, TypeError),
         ({'redis_url': 'redis://a.b'}, None),
@@ -98,6 +125,15 @@ def test_repr():
         ({'max_sleep': 0}, None),
         ({'max_sleep': 'test'}, TypeError),
         ({'max_sleep': None}, None),
+        ({'initial_tokens': 0}, None),
+        ({'initial_tokens': 1}, None),
+        ({'initial_tokens': 2}, ValueError),
+        ({'initial_tokens': -1}, OverflowError),
+        ({'expiry': None}, None),
+        ({'expiry': 0}, ValueError),
+        ({'local_lease': 1}, None),
+        ({'local_lease': 0}, ValueError),
+        ({'local_lease': 2}, ValueError),
     ],
 )
 def test_init_types(config, e):
@@ -108,10 +144,719 @@ def test_init_types(config, e):
         tokenbucket_factory(**config)()
 
 
+async def test_prefix():
+    """
+    Token buckets with the same name but different prefixes shouldn't share state.
+    """
+    name = limiter_name('prefix-test')
+    first = tokenbucket_factory(name=name, capacity=1, max_sleep=0.2, prefix='app-a:')()
+    second = tokenbucket_factory(name=name, capacity=1, max_sleep=0.2, prefix='app-b:')()
+    assert first.name == f'app-a:{name}'
+    assert second.name == f'app-b:{name}'
+
+    # Both can be entered at once, though each has a capacity of 1
+    async with first, second:
+        pass
+
+
+def test_prefix_validation():
+    with pytest.raises(ValueError, match='Prefix must not be empty'):
+        tokenbucket_factory(prefix='')()
+
+
+async def test_aenter_returns_whether_we_slept():
+    tb = tokenbucket_factory(refill_frequency=0.1)()
+    async with tb as slept:
+        assert slept is False
+    async with tb as slept:
+        assert slept is True
+
+
 async def test_max_sleep():
-    name = uuid4().hex[:6]
+    name = limiter_name()
     e = 'Received wake up time in [0-9] seconds, which is greater or equal to the specified max sleep of 1 seconds'
-    with pytest.raises(MaxSleepExceededError, match=e):
+    with pytest.raises(MaxSleepExceededError, match=e) as info:
         await asyncio.gather(
             *[asyncio.create_task(run(tokenbucket_factory(name=name, max_sleep=1), 0)) for _ in range(10)]
         )
+
+    # The error breaks down where the time was spent
+    assert re.search(
+        r"This is [0-9.]+s until the assigned slot, after 0.000s waiting for the tenant's share, "
+        r'and [0-9.]+s waiting for redis',
+        str(info.value),
+    )
+
+
+async def test_quiet(caplog):
+    with caplog.at_level(logging.DEBUG):
+        await run(tokenbucket_factory(quiet=True), 0)
+    assert not [r for r in caplog.records if 'Retrieved slot' in r.getMessage()]
+
+
+@pytest.mark.parametrize(
+    'initial_tokens, expected',
+    [
+        (None, [False, False, False, True]),  # Warm start; the bucket starts full
+        (1, [False, True, True, True]),
+        (0, [True, True, True, True]),  # Cold start; the first caller waits for a refill
+    ],
+)
+async def test_initial_tokens(initial_tokens, expected):
+    tb = tokenbucket_factory(capacity=3, refill_frequency=0.1, initial_tokens=initial_tokens)()
+    slept = []
+    for _ in range(4):
+        async with tb as s:
+            slept.append(s)
+    assert slept == expected
+
+
+async def test_first_acquire_is_immediate_when_pacing_a_single_client():
+    """
+    A bucket pacing to 1 request per minute should let the first request through right away.
+    """
+    tb = tokenbucket_factory(capacity=1, refill_frequency=60, refill_amount=1, max_sleep=1)()
+    before = datetime.now()
+    async with tb as slept:
+        assert slept is False
+    assert delta_to_seconds(datetime.now() - before) < 1
+
+    # Only subsequent requests are paced
+    with pytest.raises(MaxSleepExceededError):
+        await run(lambda: tb, 0)
+
+
+async def test_with_key():
+    tb = tokenbucket_factory(capacity=1, refill_frequency=10)()
+    tenant_a = tb.with_key('a')
+    tenant_b = tb.with_key('b')
+    assert tenant_a.name == f'{tb.name}:a'
+
+    # Each key has its own bucket, so both get a token straight away
+    async with tenant_a as slept:
+        assert slept is False
+    async with tenant_b as slept:
+        assert slept is False
+
+    with pytest.raises(ValueError, match='Key suffix must not be empty'):
+        tb.with_key('')
+
+
+async def test_with_redis_url():
+    tb = tokenbucket_factory(capacity=1, refill_frequency=10)()
+    routed = tb.with_redis_url('redis://127.0.0.1:6389/1')
+    assert routed.name == tb.name
+
+    # Each server has its own bucket, so both get a token straight away
+    async with tb as slept:
+        assert slept is False
+    async with routed as slept:
+        assert slept is False
+    assert await Redis.from_url('redis://127.0.0.1:6389/1').exists(tb.name)
+
+    with pytest.raises(self_limiters.RedisError, match='Failed to parse redis url'):
+        tb.with_redis_url('not a url')
+
+
+async def test_snapshot_and_restore():
+    tb = tokenbucket_factory(capacity=2)()
+    snapshot = await tb.snapshot()
+    assert snapshot['slot'] is None
+    assert snapshot['tokens'] is None
+
+    async with tb:
+        pass
+    snapshot = await tb.snapshot()
+    assert snapshot['capacity'] == 2
+    assert snapshot['tokens'] == 1
+
+    # Restoring into another bucket carries over the tokens left
+    other = tokenbucket_factory(capacity=2)()
+    await other.restore(snapshot)
+    assert await other.snapshot() == {**snapshot, 'name': other.name}
+
+
+async def test_count_rejections():
+    name = limiter_name()
+    pt = tokenbucket_factory(name=name, refill_frequency=10, max_sleep=1, count_rejections=True)
+    await run(pt, 0)
+
+    with pytest.raises(MaxSleepExceededError):
+        await run(pt, 0)
+    assert await pt().rejection_rate() == 1
+
+
+async def test_resize_shrink():
+    tb = tokenbucket_factory(capacity=3, refill_frequency=10)()
+    async with tb:
+        pass
+    assert (await tb.snapshot())['tokens'] == 2
+
+    # The tokens left are capped at the new capacity
+    await tb.resize(1)
+    assert tb.capacity == 1
+    assert (await tb.snapshot())['tokens'] == 1
+
+    async with tb as slept:
+        assert slept is False
+    assert (await tb.snapshot())['tokens'] == 0
+
+
+async def test_resize_grow():
+    tb = tokenbucket_factory(capacity=1, refill_frequency=0.1)()
+    async with tb:
+        pass
+
+    # Without the resize, refills would stop at a single token
+    await tb.resize(3)
+    await asyncio.sleep(0.35)
+    for _ in range(3):
+        async with tb as slept:
+            assert slept is False
+
+
+async def test_resize_validation():
+    tb = tokenbucket_factory()()
+    with pytest.raises(ValueError, match='Capacity must be greater than 0'):
+        await tb.resize(0)
+    with pytest.raises(ValueError, match='Capacity must be at most'):
+        await tb.resize(1_000_001)
+
+    # Resizing a bucket without state just changes the capacity used to create it
+    await tb.resize(2)
+    assert (await tb.snapshot())['tokens'] is None
+
+
+async def test_resize_failure():
+    """
+    The instance should keep its capacity when the bucket couldn't be resized in redis.
+    """
+    tb = tokenbucket_factory(capacity=2, redis_url='redis://127.0.0.1:1', connect_timeout=0.1)()
+    with pytest.raises(self_limiters.RedisError):
+        await tb.resize(1)
+    assert tb.capacity == 2
+
+
+async def test_wait_stats():
+    tb = tokenbucket_factory(refill_frequency=0.2)()
+    assert tb.wait_stats() == {'count': 0, 'p50': None, 'p90': None, 'p99': None}
+
+    for _ in range(2):
+        async with tb:
+            pass
+
+    stats = tb.wait_stats()
+    assert stats['count'] == 2
+    assert stats['p50'] == 0
+    assert 100 <= stats['p99'] <= 200
+
+
+async def test_with_share():
+    # 10 tokens per second, split 70/30 between two tenants
+    tb = tokenbucket_factory(capacity=10, refill_amount=10, refill_frequency=1, max_sleep=0.1)()
+    tenant_a = tb.with_share('a', 0.7)
+    tenant_b = tb.with_share('b', 0.3)
+    assert (tenant_a.name, tenant_a.tenant, tenant_a.share) == (tb.name, 'a', pytest.approx(0.7))
+    assert tb.tenant is None
+
+    # Tenant b is refused its 4th token in the window, even though the bucket has tokens left
+    for _ in range(3):
+        async with tenant_b as slept:
+            assert slept is False
+    with pytest.raises(MaxSleepExceededError):
+        async with tenant_b:
+            pass
+
+    # Meanwhile, tenant a can use the rest of its share straight away
+    for _ in range(7):
+        async with tenant_a as slept:
+            assert slept is False
+
+
+async def test_with_share_waits_for_next_window():
+    tb = tokenbucket_factory(capacity=2, refill_amount=2, refill_frequency=0.2)()
+    tenant = tb.with_share('a', 0.5)
+    start = 1_000_000.0  # The start of a 200ms window
+
+    # The bucket has a token left, but the second has to wait for the tenant's next window
+    slots = [await tenant.schedule_at(start) for _ in range(2)]
+    assert slots == [start, pytest.approx(start + 0.2)]
+
+
+def test_with_share_validation():
+    tb = tokenbucket_factory()()
+    with pytest.raises(ValueError, match='Tenant must not be empty'):
+        tb.with_share('', 0.5)
+    for share in [0, 1.5, float('nan')]:
+        with pytest.raises(ValueError, match='Share must be greater than 0, and at most 1'):
+            tb.with_share('a', share)
+
+
+async def test_with_cost():
+    tb = tokenbucket_factory(capacity=2, refill_frequency=10, max_sleep=1)()
+    cheap = tb.with_cost(0.1)
+    assert (cheap.name, cheap.cost, tb.cost) == (tb.name, 0.1, 1.0)
+
+    # Ten cheap requests use up exactly one token
+    for _ in range(10):
+        async with cheap as slept:
+            assert slept is False
+    assert (await tb.snapshot())['tokens'] == 1
+
+    async with tb.with_cost(0.5) as slept:
+        assert slept is False
+    assert (await tb.snapshot())['tokens'] == 0.5
+
+    # The half token left isn't enough for a full one, which has to wait for the next refill
+    with pytest.raises(MaxSleepExceededError):
+        async with tb:
+            pass
+
+
+def test_with_cost_validation():
+    tb = tokenbucket_factory(capacity=2)()
+    for cost in [0, -1, float('nan'), 1e7]:
+        with pytest.raises(ValueError, match='Cost must be greater than 0, and at most 1000000'):
+            tb.with_cost(cost)
+
+
+async def test_cost_above_capacity():
+    """
+    Costs above the capacity should wait for all the refills they need, and leave the bucket empty.
+    """
+    tb = tokenbucket_factory(capacity=1, refill_amount=1, refill_frequency=0.2, initial_tokens=0, max_sleep=5)()
+
+    start = time.monotonic()
+    async with tb.with_cost(3) as slept:
+        assert slept is True
+    assert 0.55 <= time.monotonic() - start <= 0.8
+    assert (await tb.snapshot())['tokens'] == 0
+
+
+@pytest.mark.skipif(not hasattr(self_limiters, '_set_redis_time'), reason='Only available in debug builds')
+async def test_exact_slots():
+    tb = tokenbucket_factory(capacity=2, refill_frequency=0.1)()
+
+    async def acquire_at(now):
+        self_limiters._set_redis_time(now)
+        async with tb:
+            pass
+        snapshot = await tb.snapshot()
+        return snapshot['slot'], snapshot['tokens']
+
+    try:
+        # A full bucket hands out its tokens right away, then schedules one per refill
+        assert await acquire_at(1_000_000) == (1_000_000, 1)
+        assert await acquire_at(1_000_000) == (1_000_000, 0)
+        assert await acquire_at(1_000_000) == (1_000_100, 0)
+        assert await acquire_at(1_000_000) == (1_000_200, 0)
+
+        # Refills skipped while idle are added back, up to the capacity
+        assert await acquire_at(1_000_450) == (1_000_400, 1)
+    finally:
+        self_limiters._set_redis_time(None)
+
+
+async def test_wait_for_burst():
+    tb = tokenbucket_factory(capacity=3, refill_frequency=0.1)()
+
+    # A full bucket can burst straight away
+    assert await tb.wait_for_burst(3) is False
+    assert (await tb.snapshot())['tokens'] == 0
+
+    # An empty one waits for all three tokens to be refilled, then hands them out together
+    before = datetime.now()
+    assert await tb.wait_for_burst(3) is True
+    assert 0.25 <= delta_to_seconds(datetime.now() - before) <= 0.4
+    assert (await tb.snapshot())['tokens'] == 0
+
+
+def test_wait_for_burst_validation():
+    tb = tokenbucket_factory(capacity=2)()
+    for n in [0, 3]:
+        with pytest.raises(ValueError, match='Burst size must be greater than 0, and at most the capacity'):
+            tb.wait_for_burst(n)
+
+
+async def test_max_backlog():
+    name = limiter_name()
+    pt = tokenbucket_factory(name=name, capacity=1, refill_frequency=1, max_backlog=2.5)
+
+    # The bucket starts full, and the next two slots are within the backlog
+    tasks = [asyncio.create_task(run(pt, 0)) for _ in range(3)]
+    await asyncio.sleep(0.1)
+
+    # The next slot is 3 seconds away, so we're refused right away, and leave the bucket as it was
+    snapshot = await pt().snapshot()
+    before = datetime.now()
+    with pytest.raises(BacklogExceededError) as e:
+        await run(pt, 0)
+    assert delta_to_seconds(datetime.now() - before) < 0.5
+    assert e.value.limiter_name == pt().name
+    assert await pt().snapshot() == snapshot
+
+    for task in tasks:
+        task.cancel()
+
+
+async def test_max_backlog_bounds_the_bucket_state():
+    """
+    A huge burst should never push the stored slot further ahead than the max backlog.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=0.1, max_backlog=2)()
+    start = 1_700_000_000.0
+
+    scheduled, refused = 0, 0
+    for _ in range(200):
+        try:
+            await bucket.schedule_at(start)
+            scheduled += 1
+        except BacklogExceededError:
+            refused += 1
+
+    # One slot now, and one per refill within the backlog
+    assert scheduled == 21
+    assert refused == 179
+    assert (await bucket.snapshot())['slot'] / 1000 <= start + 2
+
+
+def test_max_backlog_validation():
+    with pytest.raises(ValueError, match='Max backlog must not be negative'):
+        tokenbucket_factory(max_backlog=-1)()
+
+
+async def test_with_idempotency_key():
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=0.5)()
+    request = bucket.with_idempotency_key('request-1', window=0.3)
+    assert request.idempotency_key == 'request-1'
+    assert bucket.idempotency_key is None
+
+    # The first acquire consumes the only token, and retries get the same slot, without sleeping
+    assert await request.__aenter__() is False
+    state = await bucket.snapshot()
+    assert await request.__aenter__() is False
+    assert await bucket.snapshot() == state
+
+    # Other keys are unaffected, and wait for the next slot
+    before = datetime.now()
+    assert await bucket.with_idempotency_key('request-2').__aenter__() is True
+    assert delta_to_seconds(datetime.now() - before) >= 0.4
+
+    # After the window, the key is forgotten and tokens are consumed again
+    await asyncio.sleep(0.3)
+    state = await bucket.snapshot()
+    await request.__aenter__()
+    assert await bucket.snapshot() != state
+
+
+def test_with_idempotency_key_validation():
+    bucket = tokenbucket_factory()()
+    with pytest.raises(ValueError, match='Idempotency key must not be empty'):
+        bucket.with_idempotency_key('')
+    for window in [0, float('nan')]:
+        with pytest.raises(ValueError, match='Idempotency window must be greater than 0'):
+            bucket.with_idempotency_key('key', window=window)
+
+
+async def test_fractional_refill_amount():
+    """
+    Fractional refill amounts should pace acquires at the average rate, on whole refills.
+    """
+    bucket = tokenbucket_factory(capacity=2, refill_frequency=1, refill_amount=0.4, initial_tokens=0)()
+    assert bucket.refill_amount == pytest.approx(0.4)
+    start = 1_000_000.0
+
+    # Each acquire needs 2.5 refills, so slots alternate between 3 and 2 refills apart
+    slots = [await bucket.schedule_at(start) - start for _ in range(6)]
+    assert slots == [3, 5, 8, 10, 13, 15]
+    assert (await bucket.snapshot())['tokens'] == 0
+
+
+async def test_schedule_at():
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=60)()
+    start = 1_000_000.0
+
+    # Slots are paced from the given time, without sleeping
+    before = datetime.now()
+    slots = [await bucket.schedule_at(start) for _ in range(3)]
+    assert delta_to_seconds(datetime.now() - before) < 1
+    assert slots == [start, start + 60, start + 120]
+
+    with pytest.raises(ValueError, match='Timestamp must be greater than 0'):
+        await bucket.schedule_at(0)
+
+
+@pytest.mark.parametrize('expiry', [None, 5])
+async def test_expiry(expiry):
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    tb = tokenbucket_factory(expiry=expiry)()
+    assert tb.expiry == expiry
+    assert (await tb.snapshot())['expiry'] == expiry
+
+    async with tb:
+        pass
+
+    ttl = await r.ttl(tb.state_key)
+    if expiry is None:
+        assert ttl == -1
+    else:
+        assert 0 < ttl <= expiry
+
+
+async def test_local_lease():
+    """
+    Acquires should be served from the local lease until it's used up.
+    """
+    tb = tokenbucket_factory(capacity=10, refill_frequency=10, local_lease=5)()
+    assert tb.local_lease == 5
+
+    # The first acquire leases 5 tokens, and the next 4 are served locally
+    for _ in range(5):
+        async with tb as slept:
+            assert slept is False
+        assert (await tb.snapshot())['tokens'] == 5
+
+    async with tb as slept:
+        assert slept is False
+    assert (await tb.snapshot())['tokens'] == 0
+
+
+async def test_local_lease_shared_between_handles():
+    """
+    Handles on the same bucket should share its lease, rather than each leasing tokens.
+    """
+    tb = tokenbucket_factory(capacity=10, refill_frequency=10, local_lease=5)()
+    first, second = tb.with_key('tenant'), tb.with_key('tenant')
+
+    # The first acquire leases 5 tokens, and the second is served from that lease
+    for handle in [first, second, tb.with_key('tenant').with_cost(1)]:
+        async with handle as slept:
+            assert slept is False
+    assert (await first.snapshot())['tokens'] == 5
+
+    # Other keys, and the bucket itself, have leases of their own
+    async with tb.with_key('other'):
+        pass
+    assert (await tb.with_key('other').snapshot())['tokens'] == 5
+    assert (await first.snapshot())['tokens'] == 5
+
+
+async def test_local_lease_respects_the_rate():
+    tb = tokenbucket_factory(capacity=2, refill_frequency=0.2, refill_amount=2, local_lease=2)()
+    before = datetime.now()
+    for _ in range(6):
+        async with tb:
+            pass
+    # Two acquires per refill, and the bucket starts full, so the last two go after two refills
+    assert 0.4 <= delta_to_seconds(datetime.now() - before) < 0.6
+
+
+async def test_heartbeat():
+    """
+    The heartbeat should be called every interval during a long sleep, and not for short ones.
+    """
+    beats = []
+
+    def heartbeat(slept, remaining):
+        beats.append((slept, remaining))
+        raise ValueError('Exceptions should be swallowed')
+
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=1, heartbeat=heartbeat, heartbeat_interval=0.3)()
+    assert bucket.heartbeat_interval == 0.3
+
+    # The bucket starts full, so the first acquire doesn't sleep
+    await run(lambda: bucket, 0)
+    assert beats == []
+
+    # The second sleeps for about a second, beating at 0.3, 0.6 and 0.9 seconds
+    await run(lambda: bucket, 0)
+    assert 2 <= len(beats) <= 3
+    assert [round(slept, 1) for slept, _ in beats[:2]] == [0.3, 0.6]
+    assert all(remaining > 0 for _, remaining in beats)
+
+
+@pytest.mark.parametrize('heartbeat_interval', [0, float('nan'), float('inf'), 1e30])
+def test_heartbeat_interval_validation(heartbeat_interval):
+    with pytest.raises(ValueError, match='Heartbeat interval must be greater than 0, and at most 31536000 seconds'):
+        tokenbucket_factory(heartbeat_interval=heartbeat_interval)()
+
+
+async def test_state_from_earlier_versions():
+    """
+    Earlier versions stored whole tokens, as '{slot} {tokens}'. Acquires should pick
+    that state up as is, rather than reset the bucket, so upgrading doesn't let a burst through.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=10, max_sleep=1)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    seconds, microseconds = await r.time()
+    slot = seconds * 1000 + microseconds // 1000 + 5000
+    await r.set(bucket.name, f'{slot} 0', ex=30)
+
+    # A reset bucket would start full, and let us through right away
+    with pytest.raises(MaxSleepExceededError):
+        await run(lambda: bucket, 0)
+
+
+async def test_latency_callback():
+    """
+    The latency callback should be called once per acquire, without counting the sleep.
+    """
+    latencies = []
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=1, latency_callback=latencies.append)()
+
+    await run(lambda: bucket, 0)
+    await run(lambda: bucket, 0)
+
+    assert len(latencies) == 2
+    assert all(0 < latency < 0.5 for latency in latencies)
+
+
+async def test_report():
+    """
+    Overloads should halve the rate once per cooldown, and ok outcomes raise it back after the cooldown.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=0.1, adaptive=True)()
+    assert bucket.adaptive is True
+
+    assert await bucket.report('overloaded') == 0.5
+    # Still cooling down, so neither outcome changes the rate
+    assert await bucket.report('overloaded') == 0.5
+    assert await bucket.report('ok') == 0.5
+
+    await asyncio.sleep(0.15)
+    assert await bucket.report('overloaded') == 0.25
+    await asyncio.sleep(0.15)
+    assert await bucket.report('ok') == pytest.approx(0.35)
+
+    # Back at the full rate, the state is deleted
+    for _ in range(7):
+        factor = await bucket.report('ok')
+    assert factor == 1.0
+    r = Redis.from_url('redis://127.0.0.1:6389')
+    assert await r.exists(f'{bucket.name}-rate') == 0
+
+
+async def test_report_retry_after():
+    """
+    The cooldown should last `retry_after` seconds, when that's longer than the refill window.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=0.1, adaptive=True)()
+    assert await bucket.report('overloaded', retry_after=0.5) == 0.5
+
+    await asyncio.sleep(0.2)
+    assert await bucket.report('ok') == 0.5
+    await asyncio.sleep(0.4)
+    assert await bucket.report('ok') == pytest.approx(0.6)
+
+
+async def test_report_slows_the_bucket():
+    """
+    Slots should be spaced further apart while the rate is scaled down.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=10, adaptive=True)()
+    now = datetime.now().timestamp()
+    assert await bucket.schedule_at(now) == pytest.approx(now, abs=0.001)
+    assert await bucket.schedule_at(now) == pytest.approx(now + 10, abs=0.001)
+
+    await bucket.report('overloaded')
+    assert await bucket.schedule_at(now) == pytest.approx(now + 30, abs=0.001)
+
+
+async def test_report_validation():
+    with pytest.raises(ValueError, match='adaptive=True'):
+        await tokenbucket_factory()().report('overloaded')
+
+    bucket = tokenbucket_factory(adaptive=True)()
+    with pytest.raises(ValueError, match="Outcome must be 'ok' or 'overloaded'"):
+        await bucket.report('throttled')
+    with pytest.raises(ValueError, match='Retry after must be between 0 and 31536000 seconds'):
+        await bucket.report('overloaded', retry_after=-1)
+
+
+async def test_dry_run(caplog):
+    """
+    Dry run acquires should log whether they would have slept, without sleeping, consuming
+    tokens from a shadow bucket rather than the bucket itself.
+    """
+    name = limiter_name('dry-run')
+    bucket = tokenbucket_factory(name=name, capacity=1, refill_frequency=10)()
+    dry_run = tokenbucket_factory(name=name, capacity=1, refill_frequency=10, dry_run=True)()
+    assert dry_run.dry_run is True
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    # A full bucket lets the acquire through, and the bucket itself isn't created by the dry run
+    async with dry_run as throttled:
+        assert throttled is False
+    assert (await bucket.snapshot())['slot'] is None
+    assert await r.exists(f'{bucket.name}-dry-run') == 1
+
+    # The token taken above is gone from the shadow bucket, so the next dry run would have slept
+    with caplog.at_level(logging.INFO):
+        assert await asyncio.wait_for(dry_run.__aenter__(), 1) is True
+    assert [r for r in caplog.records if 'would have slept' in r.getMessage()]
+    assert (await bucket.snapshot())['slot'] is None
+
+    # Enforced acquires don't see the dry run traffic
+    await asyncio.wait_for(run(lambda: bucket, 0), 1)
+
+
+async def test_dry_run_rejection(caplog):
+    dry_run = tokenbucket_factory(capacity=1, refill_frequency=10, max_sleep=1, dry_run=True)()
+    async with dry_run as throttled:
+        assert throttled is False
+    with caplog.at_level(logging.INFO):
+        async with dry_run as throttled:
+            assert throttled is True
+    assert [r for r in caplog.records if 'would have rejected' in r.getMessage()]
+
+
+def test_sync_context_manager():
+    """
+    Entering the bucket synchronously in a loop should be paced like async acquires.
+    """
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=0.1)()
+    entered = []
+    for _ in range(5):
+        with bucket as slept:
+            entered.append(time.monotonic())
+    assert slept is True
+
+    # The bucket starts full, so the first acquire goes right away, and the rest one refill apart
+    gaps = [b - a for a, b in zip(entered, entered[1:])]
+    assert all(0.08 <= gap < 0.15 for gap in gaps)
+
+
+def test_sync_context_manager_max_sleep():
+    bucket = tokenbucket_factory(capacity=1, refill_frequency=10, max_sleep=1)()
+    with bucket:
+        pass
+    with pytest.raises(MaxSleepExceededError, match='greater or equal to the specified max sleep of 1 seconds') as e:
+        with bucket:
+            pass
+    assert e.value.limiter_name == bucket.name
+
+
+async def test_tokens_remaining():
+    """
+    Peeking at the tokens available should count refills, without consuming tokens or advancing the slot.
+    """
+    bucket = tokenbucket_factory(capacity=3, refill_frequency=0.2)()
+    r = Redis.from_url('redis://127.0.0.1:6389')
+
+    slot, tokens = await bucket.tokens_remaining()
+    assert tokens == 3
+    assert abs(slot - datetime.now().timestamp() * 1000) < 100
+    assert await r.exists(bucket.name) == 0
+
+    for _ in range(3):
+        await run(lambda: bucket, 0)
+    state = await r.get(bucket.name)
+    _, tokens = await bucket.tokens_remaining()
+    assert tokens == 0
+    assert await r.get(bucket.name) == state
+
+    # One refill later, a token is available again
+    await asyncio.sleep(0.25)
+    _, tokens = await bucket.tokens_remaining()
+    assert tokens == 1
+    assert await r.get(bucket.name) == state